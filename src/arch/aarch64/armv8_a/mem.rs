@@ -1,11 +1,19 @@
+use core::arch::asm;
+
 use crate::{
     baocore::{
         mmu::mem::{AddrSpace, AsArchTrait},
-        types::AsType::*,
+        types::{AsType::*, Vaddr},
     },
-    pt_cpu_rec_index, pt_vm_rec_index,
+    pt_cpu_rec_index, pt_vm_rec_index, read_reg,
+    util::{align_down, align_up, fill_memory},
 };
 
+use super::fences::fence_sync;
+
+const DCZID_BS_MSK: u64 = 0xf;
+const DCZID_DZP_BIT: u64 = 1 << 4;
+
 impl AsArchTrait for AddrSpace {
     fn arch_init(&mut self) {
         let index = match self.as_type {
@@ -15,3 +23,28 @@ impl AsArchTrait for AddrSpace {
         self.pt.pt_set_recursive(index);
     }
 }
+
+/// Zeroes `[va, va + size)` with `DC ZVA` where the range covers whole zero
+/// blocks. The memory must be mapped as normal memory.
+pub fn mem_zero(va: Vaddr, size: usize) {
+    let dczid = read_reg!(dczid_el0);
+    let beg = va as usize;
+    let end = beg + size;
+    if dczid & DCZID_DZP_BIT == 0 {
+        let block = 4 << (dczid & DCZID_BS_MSK);
+        let zva_beg = align_up(beg, block);
+        let zva_end = align_down(end, block);
+        if zva_beg < zva_end {
+            fill_memory(beg as _, zva_beg - beg, 0);
+            for addr in (zva_beg..zva_end).step_by(block) {
+                unsafe {
+                    asm!("dc zva, {}", in(reg) addr);
+                }
+            }
+            fill_memory(zva_end as _, end - zva_end, 0);
+            fence_sync();
+            return;
+        }
+    }
+    fill_memory(va, size, 0);
+}
@@ -3,11 +3,12 @@ pub mod mem;
 #[macro_use]
 pub mod pagetable;
 pub mod fences;
+pub mod tlb;
 pub mod vm;
 pub mod vmm;
 
 use super::{defs::*, sysregs::*};
-use crate::baocore::cpu::CPU_SIZE;
+use crate::baocore::cpu::{CPU_SIZE, CPU_STACK_GUARD_OFF};
 use core::arch::global_asm;
 use pagetable::*;
 
@@ -15,6 +16,8 @@ pub const PT_LVLS: usize = 4;
 
 global_asm!(include_str!("boot.S"),
     PTE_HYP_FLAGS = const PTE_HYP_FLAGS,
+    PTE_HYP_RO_FLAGS = const PTE_HYP_RO_FLAGS,
+    PTE_HYP_TEXT_FLAGS = const PTE_HYP_TEXT_FLAGS,
     PTE_SUPERPAGE = const PTE_SUPERPAGE,
     BAO_VAS_BASE = const BAO_VAS_BASE,
     BAO_CPU_BASE = const BAO_CPU_BASE,
@@ -24,6 +27,8 @@ global_asm!(include_str!("boot.S"),
     PT_SIZE = const PAGE_SIZE,
     PT_LVLS = const PT_LVLS,
     CPU_SIZE = const CPU_SIZE,
+    CPU_STACK_GUARD_OFF = const CPU_STACK_GUARD_OFF,
+    PTE_RSW_RSRV = const PTE_RSW_RSRV,
     TCR_EL2_DFLT = const TCR_EL2_DFLT,
     MAIR_EL2_DFLT = const MAIR_EL2_DFLT,
     SCTLR_DFLT = const SCTLR_DFLT);
@@ -7,7 +7,7 @@ use crate::{
     arch::aarch64::defs::PAGE_SIZE,
     baocore::{
         pagetable::Pagetable,
-        types::{Paddr, Vaddr},
+        types::{MemAccess, MemFlags, MemType, Paddr, Vaddr},
     },
 };
 
@@ -29,6 +29,7 @@ pub const PTE_ATTR_OFF: u64 = 2;
 pub const PTE_ATTR_MSK: u64 = 0x7 << PTE_ATTR_OFF;
 pub const PTE_AP_OFF: u64 = 6;
 pub const PTE_AP_RW: u64 = 0x1 << PTE_AP_OFF;
+pub const PTE_AP_RO: u64 = 0x3 << PTE_AP_OFF;
 pub const PTE_SH_OFF: u64 = 8;
 pub const PTE_SH_NS: u64 = 0x0 << PTE_SH_OFF;
 pub const PTE_SH_OS: u64 = 0x2 << PTE_SH_OFF;
@@ -54,22 +55,67 @@ const PTE_MEMATTR_NRML_OWBC: u64 = (0x03 << 2) << PTE_MEMATTR_OFF;
 const PTE_MEMATTR_NRML_INC: u64 = (0x01 << 0) << PTE_MEMATTR_OFF;
 const PTE_MEMATTR_NRML_IWTC: u64 = (0x02 << 0) << PTE_MEMATTR_OFF;
 const PTE_MEMATTR_NRML_IWBC: u64 = (0x03 << 0) << PTE_MEMATTR_OFF;
+const PTE_MEMATTR_NRML_WB: u64 = PTE_MEMATTR_NRML_OWBC | PTE_MEMATTR_NRML_IWBC;
+const PTE_MEMATTR_NRML_NC: u64 = PTE_MEMATTR_NRML_ONC | PTE_MEMATTR_NRML_INC;
+const PTE_MEMATTR_MSK: u64 = 0xf << PTE_MEMATTR_OFF;
 
 const PTE_S2AP_RO: u64 = (0x1 << PTE_AP_OFF);
 const PTE_S2AP_WO: u64 = (0x2 << PTE_AP_OFF);
 const PTE_S2AP_RW: u64 = (0x3 << PTE_AP_OFF);
 
-pub const PTE_HYP_FLAGS: u64 = pte_attr(1) | PTE_AP_RW | PTE_SH_IS | PTE_AF;
+/* Hypervisor mappings are either writable or executable, never both */
+const PTE_HYP_NRML: u64 = pte_attr(1) | PTE_SH_IS | PTE_AF;
+pub const PTE_HYP_FLAGS: u64 = PTE_HYP_NRML | PTE_AP_RW | PTE_XN;
+pub const PTE_HYP_RO_FLAGS: u64 = PTE_HYP_NRML | PTE_AP_RO | PTE_XN;
+pub const PTE_HYP_TEXT_FLAGS: u64 = PTE_HYP_NRML | PTE_AP_RO;
 pub const PTE_VM_FLAGS: u64 =
     PTE_MEMATTR_NRML_OWBC | PTE_MEMATTR_NRML_IWBC | PTE_SH_NS | PTE_S2AP_RW | PTE_AF;
 pub const PTE_HYP_DEV_FLAGS: u64 = pte_attr(2) | PTE_AP_RW | PTE_SH_IS | PTE_AF | PTE_XN;
 pub const PTE_VM_DEV_FLAGS: u64 = PTE_MEMATTR_DEV_GRE | PTE_SH_NS | PTE_S2AP_RW | PTE_AF;
 
+pub const fn pte_vm_flags(mem_type: MemType, access: MemAccess, xn: bool) -> MemFlags {
+    let attr = match mem_type {
+        MemType::Normal => PTE_MEMATTR_NRML_WB,
+        MemType::NormalNonCacheable => PTE_MEMATTR_NRML_NC,
+        MemType::DevicenGnRnE => PTE_MEMATTR_DEV_nGnRnE,
+        MemType::DevicenGnRE => PTE_MEMATTR_DEV_nGnRE,
+        MemType::DeviceGRE => PTE_MEMATTR_DEV_GRE,
+    };
+    let ap = match access {
+        MemAccess::ReadOnly => PTE_S2AP_RO,
+        MemAccess::WriteOnly => PTE_S2AP_WO,
+        MemAccess::ReadWrite => PTE_S2AP_RW,
+    };
+    attr | ap | PTE_SH_NS | PTE_AF | if xn { PTE_XN } else { 0 }
+}
+
+pub const fn pte_vm_mem_type(flags: MemFlags) -> Option<MemType> {
+    match flags & PTE_MEMATTR_MSK {
+        PTE_MEMATTR_DEV_nGnRnE => Some(MemType::DevicenGnRnE),
+        PTE_MEMATTR_DEV_nGnRE => Some(MemType::DevicenGnRE),
+        PTE_MEMATTR_DEV_GRE => Some(MemType::DeviceGRE),
+        PTE_MEMATTR_NRML_NC => Some(MemType::NormalNonCacheable),
+        PTE_MEMATTR_NRML_WB => Some(MemType::Normal),
+        _ => None,
+    }
+}
+
+pub const fn pte_vm_access(flags: MemFlags) -> Option<MemAccess> {
+    match flags & PTE_S2AP_RW {
+        PTE_S2AP_RO => Some(MemAccess::ReadOnly),
+        PTE_S2AP_WO => Some(MemAccess::WriteOnly),
+        PTE_S2AP_RW => Some(MemAccess::ReadWrite),
+        _ => None,
+    }
+}
+
 pub const PTE_RSW_OFF: u64 = 55;
 pub const PTE_RSW_WDT: u64 = 4;
 pub const PTE_RSW_MSK: u64 =
     ((1u64 << (PTE_RSW_OFF + PTE_RSW_WDT)) - 1) - ((1u64 << (PTE_RSW_OFF)) - 1);
 pub const PTE_RSW_RSRV: u64 = 0x3 << PTE_RSW_OFF;
+/// Writable stage 2 mapping temporarily made read-only by dirty logging
+pub const PTE_RSW_WRPROT: u64 = 0x1 << PTE_RSW_OFF;
 
 #[repr(C)]
 pub struct PageTableDescriptor {
@@ -178,6 +224,14 @@ impl PTE {
             && paddr as usize % lvlsize == 0
     }
 
+    pub fn set_s2_writable(&mut self, writable: bool) {
+        if writable {
+            self.0 |= PTE_S2AP_WO;
+        } else {
+            self.0 &= !PTE_S2AP_WO;
+        }
+    }
+
     pub fn set_rsw(&mut self, flag: u64) {
         self.0 &= !PTE_RSW_MSK;
         self.0 |= flag & PTE_RSW_MSK;
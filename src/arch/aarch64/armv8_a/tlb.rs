@@ -0,0 +1,36 @@
+use core::arch::asm;
+
+use crate::baocore::types::Vaddr;
+
+use super::fences::{fence_sync, isb};
+
+pub fn tlb_hyp_inv_va(va: Vaddr) {
+    unsafe {
+        asm!("tlbi vae2is, {}", in(reg) va >> 12);
+    }
+    fence_sync();
+    isb();
+}
+
+/// Invalidates `ipa` for the vm currently installed in VTTBR_EL2. Any
+/// combined stage 1+2 entries may hold it as well, so those go too.
+pub fn tlb_vm_inv_ipa(ipa: Vaddr) {
+    unsafe {
+        asm!("tlbi ipas2e1is, {}", in(reg) ipa >> 12);
+    }
+    fence_sync();
+    unsafe {
+        asm!("tlbi vmalle1is");
+    }
+    fence_sync();
+    isb();
+}
+
+/// Invalidates every entry of the vm currently installed in VTTBR_EL2.
+pub fn tlb_vm_inv_all() {
+    unsafe {
+        asm!("tlbi vmalls12e1is");
+    }
+    fence_sync();
+    isb();
+}
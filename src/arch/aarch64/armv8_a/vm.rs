@@ -7,19 +7,22 @@ use crate::{
     arch::aarch64::{
         armv8_a::fences::isb,
         sysregs::{VTTBR_VMID_MSK, VTTBR_VMID_OFF},
-        vm::VCpuArchProfileTrait, gic::vgic::vgic_inject_hw,
+        vm::VCpuArchProfileTrait, gic::vgic::{vgic_inject, vgic_inject_hw, vgic_inject_vm},
     },
     baocore::{
         cpu::mycpu,
         types::{Paddr, IrqID},
         vm::{VCpu, VM},
     },
+    util::BaoResult,
 };
 
 pub struct VGicDscr {
     pub gicd_addr: Paddr,
     pub gicc_addr: Paddr,
     pub gicr_addr: Paddr,
+    /// Virtual interrupt translation service, 0 for none
+    pub its_addr: Paddr,
     pub interrupt_num: usize,
 }
 
@@ -43,6 +46,14 @@ impl VCpuArchProfileTrait for VCpu {
     }
 }
 
-pub fn vcpu_arch_inject_hw_irq(vcpu: &'static mut VCpu, id: IrqID) {
-    vgic_inject_hw(vcpu, id);
+pub fn vcpu_arch_inject_hw_irq(vcpu: &'static mut VCpu, id: IrqID) -> bool {
+    vgic_inject_hw(vcpu, id)
+}
+
+pub fn vcpu_arch_inject_irq(vcpu: &'static mut VCpu, id: IrqID) -> BaoResult<()> {
+    vgic_inject(vcpu, id)
+}
+
+pub fn vm_arch_inject_irq(vm: &'static mut VM, id: IrqID) -> BaoResult<()> {
+    vgic_inject_vm(vm, id)
 }
\ No newline at end of file
@@ -5,3 +5,4 @@ pub const BAO_VAS_TOP: usize = 0xff0000000000;
 
 pub const PAGE_SIZE: usize = 0x1000;
 pub const CPU_STACK_SIZE: usize = 3 * PAGE_SIZE;
+pub const CPU_EMERGENCY_STACK_SIZE: usize = PAGE_SIZE;
@@ -3,12 +3,14 @@ use tock_registers::interfaces::Readable;
 
 use crate::{
     arch::aarch64::{
-        gic::{gicc_dir, gicc_eoir, gicc_iar},
+        gic::{gic_iar_id, gic_is_lpi, gicc_dir, gicc_eoir, gicc_iar},
         intr::interrupts_handle,
         psci::{is_psci_smc_call, psci_smc_handler},
     },
     baocore::{
+        cpu::{cpu_stack_guard_contains, mycpu},
         emul::EmulAccess,
+        hypercall::hypercall,
         intr::IntrHandleResult,
         vm::{myvcpu, myvm},
     },
@@ -19,10 +21,32 @@ use crate::{
 use super::sysregs::*;
 
 fn aborts_data_lower(iss: u64, far: u64, il: u64) {
+    let dsfc = bit64_extract(iss, ESR_ISS_DA_DSFC_OFF, ESR_ISS_DA_DSFC_LEN) & (0xf << 2); // Data Fault Status Code
+
+    // Writes to pages write-protected for dirty logging are simply retried,
+    // whatever the instruction, so this goes before the syndrome check.
+    if dsfc == ESR_ISS_DA_DSFC_PERMIS
+        && iss & ESR_ISS_DA_WnR_BIT != 0
+        && myvm().addr_space.mem_dirty_log_fault(far)
+    {
+        return;
+    }
+
+    // Splitting a block briefly invalidates its entry, a vcpu faulting
+    // meanwhile finds the address mapped again once the splitter releases
+    // the lock, and just retries.
+    let handler = myvm().emul_get_mem(far);
+    if handler.is_none() && dsfc == ESR_ISS_DA_DSFC_TRNSLT {
+        let addr_space = &myvm().addr_space;
+        let _lock = addr_space.lock.lock();
+        if addr_space.mem_is_mapped(far) {
+            return;
+        }
+    }
+
     if iss & ESR_ISS_DA_ISV_BIT == 0 || iss & ESR_ISS_DA_FnV_BIT != 0 {
         panic!("no information to handle data abort ({:#x?})", far);
     }
-    let dsfc = bit64_extract(iss, ESR_ISS_DA_DSFC_OFF, ESR_ISS_DA_DSFC_LEN) & (0xf << 2); // Data Fault Status Code
 
     if dsfc != ESR_ISS_DA_DSFC_TRNSLT && dsfc != ESR_ISS_DA_DSFC_PERMIS {
         panic!("data abort is not translation fault - cant deal with it");
@@ -41,7 +65,7 @@ fn aborts_data_lower(iss: u64, far: u64, il: u64) {
     //     myvcpu().read_reg(access.reg) & bit64_mask(0, access.width * 8)
     // );
 
-    let handler = myvm().emul_get_mem(addr).unwrap();
+    let handler = handler.unwrap();
     if handler(&access) {
         let pc_step = 2 + 2 * il;
         myvcpu().write_pc(myvcpu().read_pc() + pc_step);
@@ -71,6 +95,13 @@ pub fn smc_handler() {
     // todo!("fid = {:#x?}", fid);
 }
 
+pub fn hvc_handler() {
+    let id = myvcpu().read_reg(0);
+    let ret = hypercall(id);
+    myvcpu().write_reg(0, ret as u64);
+    // the preferred return address of an hvc is already the next instruction
+}
+
 #[no_mangle]
 fn sync_exceptions_handler() {
     let esr = ESR_EL2.extract();
@@ -101,6 +132,9 @@ fn sync_exceptions_handler() {
             );
             aborts_data_lower(iss, ipa_fault_addr, il);
         }
+        Some(ESR_EL2::EC::Value::HVC64) => {
+            hvc_handler();
+        }
         Some(ESR_EL2::EC::Value::SMC64) => {
             println!("SMC64: instruction_addr = {:#x?}", ELR_EL2.get());
             smc_handler();
@@ -121,10 +155,10 @@ fn sync_exceptions_handler() {
 #[no_mangle]
 fn gic_handler() {
     let ack = gicc_iar();
-    let id = ack & ((1 << 24) - 1);
+    let id = gic_iar_id(ack);
     // info!("gic_handler: id = {}", id);
 
-    if id < 1020 {
+    if id < 1020 || gic_is_lpi(id as _) {
         let res = interrupts_handle(id as _);
         gicc_eoir(ack as _);
         if let IntrHandleResult::HandledByHyp = res {
@@ -133,8 +167,9 @@ fn gic_handler() {
     }
 }
 
+/// Runs on the emergency stack, `regs` holds x0-x30 and the faulting sp.
 #[no_mangle]
-fn internal_sync_exceptions_handler() {
+fn internal_sync_exceptions_handler(regs: &[u64; 32]) {
     println!("internal_sync_exceptions_handler");
     let esr = ESR_EL2.extract();
 
@@ -145,6 +180,15 @@ fn internal_sync_exceptions_handler() {
     let ipa_fault_addr = (far & 0xfff) | (hpfar << 8);
 
     match esr.read_as_enum(ESR_EL2::EC) {
+        Some(ESR_EL2::EC::Value::DataAbortCurrentEL) if cpu_stack_guard_contains(far) => {
+            panic!(
+                "cpu {}: hypervisor stack overflow at pc {:#x} (sp {:#x}, fault_addr {:#x})",
+                mycpu().id,
+                ELR_EL2.get(),
+                regs[31],
+                far,
+            );
+        }
         Some(ESR_EL2::EC::Value::Unknown) => {
             panic!("Unknown exception!");
         }
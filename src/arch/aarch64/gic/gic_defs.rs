@@ -51,25 +51,96 @@ pub const GIC_NUM_SGI_REGS: usize =
     (GIC_MAX_SGIS * GIC_SGI_BITS) / (core::mem::size_of::<u32>() * 8);
 pub const GIC_NUM_PRIVINT_REGS: usize = GIC_CPU_PRIV / (core::mem::size_of::<u32>() * 8);
 pub const GIC_LOWEST_PRIO: usize = 0xff;
+pub const GIC_HYP_PRIO: u8 = 0x7f;
 
 // ****************  GICD  ******************
 pub const MPIDR_AFF_MSK: u64 = 0xffff;
 pub const GICD_IROUTER_INV: u64 = !MPIDR_AFF_MSK;
+pub const GICD_IROUTER_IRM_BIT: u64 = 1 << 31;
+pub const GICD_IROUTER_AFF_MSK: u64 = 0xff_00ff_ffff;
 
 pub const GICD_CTLR_ARE_NS_BIT: u32 = 0x10;
 pub const GICD_CTLR_EN_BIT: u32 = 0x1;
 pub const GICD_CTLR_ENA_BIT: u32 = 0x2;
 
+pub const GIC_MAX_TARGETS: usize = 8;
+pub const GICD_SGIR_ID_MSK: u32 = 0xf;
+pub const GICD_SGIR_TGT_LIST_OFF: u32 = 16;
+pub const GICD_SGIR_TGT_LIST_MSK: u32 = 0xff << GICD_SGIR_TGT_LIST_OFF;
+pub const GICD_SGIR_TGT_FILTER_OFF: u32 = 24;
+pub const GICD_SGIR_TGT_FILTER_MSK: u32 = 0x3 << GICD_SGIR_TGT_FILTER_OFF;
+pub const GICD_SGIR_FILTER_LIST: u32 = 0;
+pub const GICD_SGIR_FILTER_OTHERS: u32 = 1;
+pub const GICD_SGIR_FILTER_SELF: u32 = 2;
+
+// ****************  GICC  ******************
+pub const GICC_CTLR_EN_BIT: u32 = 0x1;
+pub const GICC_CTLR_EOImodeNS_BIT: u32 = 1 << 9;
+pub const GICC_IAR_ID_MSK: u64 = 0x3ff;
+pub const GICC_IAR_CPU_OFF: u64 = 10;
+
+pub const GICD_TYPER_LPIS_BIT: u32 = 1 << 17;
+pub const GICD_TYPER_IDBITS_OFF: u32 = 19;
+pub const GICD_TYPER_IDBITS_MSK: u32 = 0x1f << GICD_TYPER_IDBITS_OFF;
+
 // ****************  GICR  ******************
 pub const GICR_WAKER_ProcessorSleep_BIT: u32 = 0x2;
 pub const GICR_WAKER_ChildrenASleep_BIT: u32 = 0x4;
+pub const GICR_CTLR_ENABLE_LPIS_BIT: u32 = 1 << 0;
+pub const GICR_TYPER_PLPIS_BIT: u64 = 1 << 0;
+pub const GICR_TYPER_PROC_NUM_OFF: u64 = 8;
+pub const GICR_TYPER_PROC_NUM_LEN: u64 = 16;
+pub const GICR_PROPBASER_IDBITS_MSK: u64 = 0x1f;
+pub const GICR_PROPBASER_PA_MSK: u64 = ((1 << 52) - 1) & !0xfff;
+pub const GICR_PENDBASER_PA_MSK: u64 = ((1 << 52) - 1) & !0xffff;
+pub const GICR_PENDBASER_PTZ_BIT: u64 = 1 << 62;
+/// Inner shareable, inner write-back tables
+pub const GICR_BASER_DFLT: u64 = (1 << 10) | (7 << 7);
+pub const GICR_PENDBASER_ALIGN: usize = 0x10000;
+
+// ****************  LPIs  ******************
+pub const GIC_FIRST_LPI: usize = 8192;
+/// Interrupt id bits with LPIs, for the hypervisor and its guests alike
+pub const GIC_LPI_ID_BITS: usize = 14;
+pub const GIC_MAX_LPIS: usize = (1 << GIC_LPI_ID_BITS) - GIC_FIRST_LPI;
+pub const GIC_LPI_CFG_EN_BIT: u8 = 1 << 0;
+pub const GIC_LPI_CFG_RES1_BIT: u8 = 1 << 1;
+pub const GIC_LPI_CFG_PRIO_MSK: u8 = 0xfc;
 
 // ****************  GICH  ******************
 pub const GICH_VTR_OFF: u32 = 0;
 pub const GICH_VTR_LEN: u32 = 6;
 pub const GICH_VTR_MSK: u32 = ((1 << GICH_VTR_LEN) - 1) << GICH_VTR_OFF;
+pub const GICH_VTR_PREBITS_OFF: u32 = 26;
+pub const GICH_VTR_PREBITS_LEN: u32 = 3;
+pub const GICH_VTR_PREBITS_MSK: u32 =
+    ((1 << GICH_VTR_PREBITS_LEN) - 1) << GICH_VTR_PREBITS_OFF;
+/// Active priority registers per group, with 7 preemption bits
+pub const GICH_MAX_APRS: usize = 4;
+pub const GICH_HCR_EN_BIT: u32 = 1 << 0;
+pub const GICH_HCR_UIE_BIT: u32 = 1 << 1;
 pub const GICH_HCR_LRENPIE_BIT: u32 = 1 << 2;
-
+pub const GICH_HCR_EOICOUNT_OFF: u32 = 27;
+pub const GICH_HCR_EOICOUNT_LEN: u32 = 5;
+pub const GICH_HCR_EOICOUNT_MSK: u32 = ((1 << GICH_HCR_EOICOUNT_LEN) - 1) << GICH_HCR_EOICOUNT_OFF;
+pub const GICH_MISR_EOI_BIT: u32 = 1 << 0;
+pub const GICH_MISR_U_BIT: u32 = 1 << 1;
+pub const GICH_MISR_LRENP_BIT: u32 = 1 << 2;
+
+pub const GICH_LR_VID_MSK: u64 = 0xffff_ffff;
 pub const GICH_LR_GRP_BIT: u64 = 1 << 60;
 pub const GICH_LR_HW_BIT: u64 = 1 << 61;
 pub const GICH_LR_EOI_BIT: u64 = 1 << 41;
+pub const GICH_LR_STATE_MSK: u64 = 3 << 62;
+pub const GICH_LR_STATE_PEND: u64 = 1 << 62;
+pub const GICH_LR_STATE_ACT: u64 = 2 << 62;
+pub const GICH_LR_PRIO_OFF: u64 = 48;
+pub const GICH_LR_PRIO_LEN: u64 = 8;
+pub const GICH_VMCR_VFIQEN_BIT: u32 = 1 << 3;
+pub const GICH_VMCR_VPMR_OFF: u32 = 24;
+pub const GICV2_VMCR_VMPRIMASK_OFF: u32 = 27;
+
+pub const GICV2_MAX_LRS: usize = 64;
+/// List registers of either version, GICv3 has up to 16
+pub const GIC_MAX_LRS: usize = GICV2_MAX_LRS;
+
@@ -3,12 +3,14 @@
 use crate::{baocore::types::IrqID, util::bit32_mask};
 
 use super::{
+    gic,
     gic_defs::{
         gic_config_regs, gic_int_mask, gic_int_regs, gic_prio_off, gic_prio_regs, gic_sec_regs,
-        gic_target_regs, GICD_CTLR_ARE_NS_BIT, GICD_CTLR_ENA_BIT, GICD_IROUTER_INV, GIC_CPU_PRIV,
-        GIC_MAX_INTERUPTS, GIC_NUM_PRIVINT_REGS, GIC_NUM_SGI_REGS, GIC_PRIO_BITS, gic_config_off, GIC_CONFIG_BITS,
+        gic_target_regs, GICD_CTLR_ARE_NS_BIT, GICD_CTLR_ENA_BIT, GICD_CTLR_EN_BIT,
+        GICD_IROUTER_INV, GIC_CPU_PRIV, GIC_MAX_INTERUPTS, GIC_NUM_PRIVINT_REGS, GIC_NUM_SGI_REGS,
+        GIC_PRIO_BITS, gic_config_off, GIC_CONFIG_BITS, GIC_TARGET_BITS,
     },
-    GicVersion, GIC_VERSION,
+    gic_version, GicVersion,
 };
 
 #[repr(C)]
@@ -45,7 +47,9 @@ pub struct GicdHw {
     pub pad6: [u8; 0x0F10 - 0x0F04],
     pub CPENDSGIR: [u32; GIC_NUM_SGI_REGS],
     pub SPENDSGIR: [u32; GIC_NUM_SGI_REGS],
-    pub pad7: [u8; 0x6000 - 0x0F30],
+    pub pad7: [u8; 0x0FD0 - 0x0F30],
+    pub ID_V2: [u32; (0x1000 - 0x0FD0) / core::mem::size_of::<u32>()], // 0xfd0, GICv2 only
+    pub pad10: [u8; 0x6000 - 0x1000],
     pub IROUTER: [u64; GIC_MAX_INTERUPTS], // 0x6000
     pub pad8: [u8; 0xFFD0 - 0x8000],
     pub ID: [u32; (0x10000 - 0xFFD0) / core::mem::size_of::<u32>()],
@@ -68,16 +72,15 @@ impl GicdHw {
             self.IPRIORITYR[i] = u32::MAX;
         }
 
-        match GIC_VERSION {
+        match gic_version() {
             GicVersion::GicVersion2 => {
-                todo!("gicv2: GICD init")
-                // // No CPU targets for any interrupt by default
-                // for i in GIC_NUM_TARGET_REGS(GIC_CPU_PRIV)..GIC_NUM_TARGET_REGS(int_num) {
-                //     self.itargetsr[i].write(0);
-                // }
-
-                // // Enable distributor
-                // gicd.ctlr.modify(|val| val | GICD_CTLR_EN_BIT);
+                // No CPU targets for any interrupt by default
+                for i in gic_target_regs(GIC_CPU_PRIV)..gic_target_regs(int_num) {
+                    self.ITARGETSR[i] = 0;
+                }
+
+                // Enable distributor
+                self.CTLR |= GICD_CTLR_EN_BIT;
             }
             GicVersion::GicVersion3 => {
                 for i in GIC_CPU_PRIV..GIC_MAX_INTERUPTS {
@@ -136,6 +139,15 @@ impl GicdHw {
         self.IROUTER[id as usize] = route;
     }
 
+    pub fn set_targets(&mut self, id: IrqID, targets: u8) {
+        let reg_ind = gic_target_regs(id as _);
+        let off = (id as usize * GIC_TARGET_BITS) % 32;
+        let mask = bit32_mask(off as _, GIC_TARGET_BITS as _);
+
+        self.ITARGETSR[reg_ind] =
+            (self.ITARGETSR[reg_ind] & !mask) | (((targets as u32) << off) & mask);
+    }
+
     pub fn set_cfg(&mut self, id: IrqID, cfg: u8) {
         let reg_ind = gic_config_regs(id as _);
         let off = gic_config_off(id as _);
@@ -143,3 +155,58 @@ impl GicdHw {
         self.ICFGR[reg_ind] = (self.ICFGR[reg_ind] & !mask) | (((cfg as u32) << off) & mask);
     }
 }
+
+pub fn gicd_set_enable(id: IrqID, enabled: bool) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().set_enable(id, enabled);
+}
+
+pub fn gicd_set_act(id: IrqID, act: bool) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().set_act(id, act);
+}
+
+pub fn gicd_set_pend(id: IrqID, pend: bool) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().set_pend(id, pend);
+}
+
+pub fn gicd_set_prio(id: IrqID, prio: u8) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().set_prio(id, prio);
+}
+
+/// Routes a shared interrupt, `route` being what `gic_cpu_route` builds.
+pub fn gicd_set_route(id: IrqID, route: u64) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    match gic_version() {
+        GicVersion::GicVersion2 => gic.gicd().set_targets(id, route as _),
+        GicVersion::GicVersion3 => gic.gicd().set_route(id, route),
+    }
+}
+
+pub fn gicd_set_cfg(id: IrqID, cfg: u8) {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().set_cfg(id, cfg as _);
+}
+
+pub fn gicd_get_pidr(addr: u64) -> u32 {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    match gic_version() {
+        GicVersion::GicVersion2 => gic.gicd().ID_V2[((addr as usize & 0xfff) - 0xfd0) / 4],
+        GicVersion::GicVersion3 => gic.gicd().ID[((addr as usize & 0xffff) - 0xffd0) / 4],
+    }
+}
+
+pub fn gicd_get_iidr() -> u32 {
+    let gic = gic();
+    let _lock = gic.gicd_lock.lock();
+    gic.gicd().IIDR
+}
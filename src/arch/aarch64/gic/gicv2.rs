@@ -0,0 +1,224 @@
+#![allow(non_snake_case)]
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use spin::RwLock;
+
+use super::{gic_defs::*, gicd::GicdHw, GichState};
+use crate::{
+    baocore::types::{CpuID, IrqID},
+    util::bit64_extract,
+};
+
+#[repr(C)]
+#[repr(align(0x1000))]
+pub struct GiccHw {
+    CTLR: u32,                         // 0x0
+    PMR: u32,                          // 0x4
+    BPR: u32,                          // 0x8
+    IAR: u32,                          // 0xc
+    EOIR: u32,                         // 0x10
+    RPR: u32,                          // 0x14
+    HPPIR: u32,                        // 0x18
+    ABPR: u32,                         // 0x1c
+    AIAR: u32,                         // 0x20
+    AEOIR: u32,                        // 0x24
+    AHPPIR: u32,                       // 0x28
+    pad0: [u8; 0x00D0 - 0x002C],
+    APR: [u32; 4],                     // 0xd0
+    NSAPR: [u32; 4],                   // 0xe0
+    pad1: [u8; 0x00FC - 0x00F0],
+    IIDR: u32,                         // 0xfc
+    pad2: [u8; 0x1000 - 0x0100],
+    DIR: u32,                          // 0x1000
+}
+
+#[repr(C)]
+#[repr(align(0x1000))]
+pub struct GichHw {
+    HCR: u32,                          // 0x0
+    VTR: u32,                          // 0x4
+    VMCR: u32,                         // 0x8
+    pad0: [u8; 0x0010 - 0x000C],
+    MISR: u32,                         // 0x10
+    pad1: [u8; 0x0020 - 0x0014],
+    EISR: [u32; GICV2_MAX_LRS / 32],   // 0x20
+    pad2: [u8; 0x0030 - 0x0028],
+    ELRSR: [u32; GICV2_MAX_LRS / 32],  // 0x30
+    pad3: [u8; 0x00F0 - 0x0038],
+    APR: u32,                          // 0xf0
+    pad4: [u8; 0x0100 - 0x00F4],
+    LR: [u32; GICV2_MAX_LRS],          // 0x100
+}
+
+/// Without affinity routing cpus are told apart by the bit ITARGETSR gives
+/// each of them, which only the cpu itself can read.
+static GIC_CPU_MASKS: RwLock<[u8; GIC_MAX_TARGETS]> = RwLock::new([0; GIC_MAX_TARGETS]);
+
+pub fn gic_cpu_mask(cpu_id: CpuID) -> u8 {
+    GIC_CPU_MASKS.read()[cpu_id as usize]
+}
+
+/// Resets the calling cpu's banked private interrupts.
+pub fn gicd_cpu_init(gicd: &mut GicdHw, cpu_id: CpuID) {
+    assert!((cpu_id as usize) < GIC_MAX_TARGETS, "gicv2: too many cpus");
+
+    gicd.ICENABLER[0] = u32::MAX;
+    gicd.ICPENDR[0] = u32::MAX;
+    gicd.ICACTIVER[0] = u32::MAX;
+
+    for i in 0..gic_prio_regs(GIC_CPU_PRIV) {
+        gicd.IPRIORITYR[i] = u32::MAX;
+    }
+
+    GIC_CPU_MASKS.write()[cpu_id as usize] = gicd.ITARGETSR[0] as u8;
+}
+
+pub fn gicc_init(gicc: *mut GiccHw, gich: *mut GichHw) {
+    for i in 0..gich_num_lrs(gich) {
+        gich_write_lr(gich, i, 0);
+    }
+    unsafe {
+        write_volatile(addr_of_mut!((*gicc).PMR), GIC_LOWEST_PRIO as _);
+        write_volatile(addr_of_mut!((*gicc).BPR), 0);
+        write_volatile(
+            addr_of_mut!((*gicc).CTLR),
+            GICC_CTLR_EN_BIT | GICC_CTLR_EOImodeNS_BIT,
+        );
+    }
+    gich_set_hcr(gich, gich_get_hcr(gich) | GICH_HCR_LRENPIE_BIT);
+}
+
+pub fn gicc_iar(gicc: *mut GiccHw) -> u64 {
+    unsafe { read_volatile(addr_of!((*gicc).IAR)) as _ }
+}
+
+pub fn gicc_eoir(gicc: *mut GiccHw, eoir: u32) {
+    unsafe { write_volatile(addr_of_mut!((*gicc).EOIR), eoir) }
+}
+
+pub fn gicc_dir(gicc: *mut GiccHw, dir: u32) {
+    unsafe { write_volatile(addr_of_mut!((*gicc).DIR), dir) }
+}
+
+pub fn gicd_send_sgi(gicd: &mut GicdHw, cpu_target: CpuID, sgi_num: IrqID) {
+    let sgir = ((gic_cpu_mask(cpu_target) as u32) << GICD_SGIR_TGT_LIST_OFF)
+        | (GICD_SGIR_FILTER_LIST << GICD_SGIR_TGT_FILTER_OFF)
+        | (sgi_num & GICD_SGIR_ID_MSK);
+    unsafe { write_volatile(addr_of_mut!(gicd.SGIR), sgir) }
+}
+
+pub fn gich_num_lrs(gich: *mut GichHw) -> u32 {
+    let vtr = unsafe { read_volatile(addr_of!((*gich).VTR)) };
+    ((vtr & GICH_VTR_MSK) >> GICH_VTR_OFF) + 1
+}
+
+pub fn gich_get_hcr(gich: *mut GichHw) -> u32 {
+    unsafe { read_volatile(addr_of!((*gich).HCR)) }
+}
+
+pub fn gich_set_hcr(gich: *mut GichHw, hcr: u32) {
+    unsafe { write_volatile(addr_of_mut!((*gich).HCR), hcr) }
+}
+
+pub fn gich_get_misr(gich: *mut GichHw) -> u32 {
+    unsafe { read_volatile(addr_of!((*gich).MISR)) }
+}
+
+pub fn gich_get_eisr(gich: *mut GichHw) -> u64 {
+    unsafe {
+        read_volatile(addr_of!((*gich).EISR[0])) as u64
+            | (read_volatile(addr_of!((*gich).EISR[1])) as u64) << 32
+    }
+}
+
+pub fn gich_get_elrsr(gich: *mut GichHw) -> u64 {
+    unsafe {
+        read_volatile(addr_of!((*gich).ELRSR[0])) as u64
+            | (read_volatile(addr_of!((*gich).ELRSR[1])) as u64) << 32
+    }
+}
+
+/// The guest's priority mask, only its top 5 bits are kept.
+pub fn gich_get_vpmr(gich: *mut GichHw) -> u8 {
+    let vmcr = unsafe { read_volatile(addr_of!((*gich).VMCR)) };
+    ((vmcr >> GICV2_VMCR_VMPRIMASK_OFF) << 3) as u8
+}
+
+/// GICv2 has a single active priorities register, kept as the group 1 one.
+pub fn gich_save(gich: *mut GichHw, state: &mut GichState) {
+    state.hcr = gich_get_hcr(gich);
+    unsafe {
+        state.vmcr = read_volatile(addr_of!((*gich).VMCR));
+        state.ap1r[0] = read_volatile(addr_of!((*gich).APR));
+    }
+    for i in 0..gich_num_lrs(gich) {
+        state.lrs[i as usize] = gich_read_lr(gich, i);
+    }
+}
+
+pub fn gich_restore(gich: *mut GichHw, state: &GichState) {
+    for i in 0..gich_num_lrs(gich) {
+        gich_write_lr(gich, i, state.lrs[i as usize]);
+    }
+    unsafe {
+        write_volatile(addr_of_mut!((*gich).APR), state.ap1r[0]);
+        write_volatile(addr_of_mut!((*gich).VMCR), state.vmcr);
+    }
+    gich_set_hcr(gich, state.hcr);
+}
+
+pub fn gich_write_lr(gich: *mut GichHw, i: u32, val: u64) {
+    assert!((i as usize) < GICV2_MAX_LRS, "gich_write_lr: index out of range");
+    unsafe { write_volatile(addr_of_mut!((*gich).LR[i as usize]), gich_lr_to_v2(val)) }
+}
+
+pub fn gich_read_lr(gich: *mut GichHw, i: u32) -> u64 {
+    assert!((i as usize) < GICV2_MAX_LRS, "gich_read_lr: index out of range");
+    gich_lr_from_v2(unsafe { read_volatile(addr_of!((*gich).LR[i as usize])) })
+}
+
+// The vgic builds list registers in the GICv3 layout. Without the HW bit the
+// low pINTID bits carry the source cpu of an SGI.
+
+const GICV2_LR_PID_OFF: u64 = 10;
+const GICV2_LR_EOI_BIT: u32 = 1 << 19;
+const GICV2_LR_PRIO_OFF: u64 = 23;
+const GICV2_LR_STATE_OFF: u64 = 28;
+const GICV2_LR_GRP1_BIT: u32 = 1 << 30;
+const GICV2_LR_HW_BIT: u32 = 1 << 31;
+
+fn gich_lr_to_v2(lr: u64) -> u32 {
+    let mut lr2 = (bit64_extract(lr, 0, 10)
+        | (bit64_extract(lr, 48 + 3, 5) << GICV2_LR_PRIO_OFF)
+        | (bit64_extract(lr, 62, 2) << GICV2_LR_STATE_OFF)) as u32;
+    if lr & GICH_LR_GRP_BIT != 0 {
+        lr2 |= GICV2_LR_GRP1_BIT;
+    }
+    if lr & GICH_LR_HW_BIT != 0 {
+        lr2 |= GICV2_LR_HW_BIT | (bit64_extract(lr, 32, 10) << GICV2_LR_PID_OFF) as u32;
+    } else {
+        lr2 |= (bit64_extract(lr, 32, 3) << GICV2_LR_PID_OFF) as u32;
+        if lr & GICH_LR_EOI_BIT != 0 {
+            lr2 |= GICV2_LR_EOI_BIT;
+        }
+    }
+    lr2
+}
+
+fn gich_lr_from_v2(lr2: u32) -> u64 {
+    let lr2 = lr2 as u64;
+    let mut lr = bit64_extract(lr2, 0, 10)
+        | (bit64_extract(lr2, GICV2_LR_PRIO_OFF, 5) << (48 + 3))
+        | (bit64_extract(lr2, GICV2_LR_STATE_OFF, 2) << 62);
+    if lr2 & GICV2_LR_GRP1_BIT as u64 != 0 {
+        lr |= GICH_LR_GRP_BIT;
+    }
+    if lr2 & GICV2_LR_HW_BIT as u64 != 0 {
+        lr |= GICH_LR_HW_BIT | (bit64_extract(lr2, GICV2_LR_PID_OFF, 10) << 32);
+    } else {
+        lr |= bit64_extract(lr2, GICV2_LR_PID_OFF, 3) << 32;
+        if lr2 & GICV2_LR_EOI_BIT as u64 != 0 {
+            lr |= GICH_LR_EOI_BIT;
+        }
+    }
+    lr
+}
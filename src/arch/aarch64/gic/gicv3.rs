@@ -1,20 +1,15 @@
 #![allow(non_snake_case)]
-use spin::Mutex;
-
-use super::GIC;
-use super::{gic_defs::*, gicd::GicdHw};
+use super::{gic, GichState};
+use super::gic_defs::*;
 use crate::arch::aarch64::sysregs::*;
-use crate::baocore::types::IrqID;
+use crate::arch::aarch64::armv8_a::fences::fence_sync;
+use crate::baocore::types::{IrqID, Paddr};
 use crate::util::bit32_mask;
 use crate::{
-    baocore::{
-        cpu::mycpu,
-        mmu::sections::SEC_HYP_GLOBAL,
-        types::{CpuID, Vaddr},
-    },
-    platform::PLATFORM,
+    baocore::types::CpuID,
+    platform::{ArchPlatformTrait, PLATFORM},
     read_reg,
-    util::num_pages,
+    util::bit64_extract,
     write_reg,
 };
 
@@ -132,172 +127,88 @@ impl GicrHw {
             _ => panic!("set_cfg: invalid intr id")
         }
     }
-}
-
-#[repr(C)]
-#[repr(align(0x10000))]
-struct SgiBase();
-
-pub fn gic_map_mmio() -> (Vaddr, Vaddr) {
-    let addr_space = &mut mycpu().addr_space;
-    let gicd = addr_space
-        .mem_alloc_map_dev(
-            SEC_HYP_GLOBAL,
-            PLATFORM.arch.gic.gicd_addr,
-            None,
-            num_pages(core::mem::size_of::<GicdHw>()),
-        )
-        .unwrap();
-    let gicr = addr_space
-        .mem_alloc_map_dev(
-            SEC_HYP_GLOBAL,
-            PLATFORM.arch.gic.gicr_addr,
-            None,
-            PLATFORM.cpu_num * num_pages(core::mem::size_of::<GicrHw>()),
-        )
-        .unwrap();
-    (gicd, gicr)
-}
-
-pub struct GicV3 {
-    gicd_base: Vaddr,
-    gicr_base: Vaddr,
-    pub max_irqs: usize,
-    pub gicd_lock: Mutex<()>,
-    pub gicr_lock: Mutex<()>,
-}
-
-impl GicV3 {
-    pub fn new(gicd_base: Vaddr, gicr_base: Vaddr) -> Self {
-        let mut gic = Self {
-            gicd_base,
-            gicr_base,
-            max_irqs: 0,
-            gicd_lock: Mutex::new(()),
-            gicr_lock: Mutex::new(()),
-        };
-        gic.max_irqs = ((gic.gicd().TYPER as usize & 0b11111) + 1) * 32;
-        gic
-    }
-
-    pub fn gicd_init(&mut self) {
-        self.gicd().init(self.max_irqs);
-    }
-
-    pub fn each_cpu_init(&mut self, cpu_id: CpuID) {
-        self.gicr(cpu_id as _).init();
-        self.gicc_init();
-    }
 
-    fn gicd(&self) -> &mut GicdHw {
-        unsafe { &mut *(self.gicd_base as *mut _) }
+    pub fn typer(&self) -> u64 {
+        self.TYPER
     }
 
-    fn gicr(&self, index: usize) -> &mut GicrHw {
-        assert!(index < PLATFORM.cpu_num);
-        unsafe { &mut *((self.gicr_base as *mut GicrHw).add(index)) }
-    }
-
-    fn gicc_init(&self) {
-        let num_lrs = gich_num_lrs();
-        for i in 0..num_lrs {
-            gich_write_lr(i, 0);
+    /// Enables LPIs with the shared configuration table at `prop_pa` and this
+    /// redistributor's pending table at `pend_pa`, which must be zeroed.
+    /// Returns false if the redistributor has no LPIs.
+    pub fn lpi_init(&mut self, prop_pa: Paddr, pend_pa: Paddr) -> bool {
+        if self.TYPER & GICR_TYPER_PLPIS_BIT == 0 {
+            return false;
         }
-        write_reg!(icc_pmr_el1, GIC_LOWEST_PRIO);
-        write_reg!(icc_bpr1_el1, 0u64);
-        write_reg!(icc_ctlr_el1, ICC_CTLR_EOIMode_BIT as u64);
-        let hcr = read_reg!(ich_hcr_el2) as u32 | GICH_HCR_LRENPIE_BIT;
-        write_reg!(ich_hcr_el2, hcr as u64);
-        write_reg!(icc_igrpen1_el1, ICC_IGRPEN_EL1_ENB_BIT);
+        self.PROPBASER = GICR_BASER_DFLT
+            | (prop_pa & GICR_PROPBASER_PA_MSK)
+            | ((GIC_LPI_ID_BITS as u64 - 1) & GICR_PROPBASER_IDBITS_MSK);
+        self.PENDBASER = GICR_BASER_DFLT | GICR_PENDBASER_PTZ_BIT | (pend_pa & GICR_PENDBASER_PA_MSK);
+        fence_sync();
+        self.CTLR |= GICR_CTLR_ENABLE_LPIS_BIT;
+        true
     }
 }
 
-pub fn gicd_set_enable(id: IrqID, enabled: bool) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_enable(id, enabled);
-}
-
-pub fn gicd_set_act(id: IrqID, act: bool) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_act(id, act);
-}
-
-pub fn gicd_set_pend(id: IrqID, pend: bool) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_pend(id, pend);
-}
-
-pub fn gicd_set_prio(id: IrqID, prio: u8) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_prio(id, prio);
-}
-
-pub fn gicd_set_route(id: IrqID, route: u64) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_route(id, route);
-}
-
-pub fn gicd_set_cfg(id: IrqID, cfg: u8) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().set_cfg(id, cfg as _);
-}
+#[repr(C)]
+#[repr(align(0x10000))]
+struct SgiBase();
 
-pub fn gicd_get_pidr(addr: u64) -> u32 {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().ID[((addr as usize & 0xffff) - 0xffd0) / 4]
+pub fn gicc_init() {
+    let num_lrs = gich_num_lrs();
+    for i in 0..num_lrs {
+        gich_write_lr(i, 0);
+    }
+    write_reg!(icc_pmr_el1, GIC_LOWEST_PRIO);
+    write_reg!(icc_bpr1_el1, 0u64);
+    write_reg!(icc_ctlr_el1, ICC_CTLR_EOIMode_BIT as u64);
+    let hcr = read_reg!(ich_hcr_el2) as u32 | GICH_HCR_LRENPIE_BIT;
+    write_reg!(ich_hcr_el2, hcr as u64);
+    write_reg!(icc_igrpen1_el1, ICC_IGRPEN_EL1_ENB_BIT);
 }
 
-pub fn gicd_get_iidr() -> u32 {
-    let gic = unsafe { GIC.get_mut().unwrap() };
-    let _lock = gic.gicd_lock.lock();
-    gic.gicd().IIDR
+/// The IROUTER value targeting `cpu_id`, its MPIDR without the non-affinity
+/// bits, one of which would read as IRM.
+pub fn gic_cpu_route(cpu_id: CpuID) -> u64 {
+    PLATFORM.cpu_id_to_mpidr(cpu_id) & GICD_IROUTER_AFF_MSK
 }
-// ---------------------------------------------------------------
 
 pub fn gicr_set_enable(id: IrqID, enabled: bool, gicr_id: CpuID) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(gicr_id as _).set_enable(id, enabled);
 }
 
 pub fn gicr_set_act(id: IrqID, act: bool, gicr_id: CpuID) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(gicr_id as _).set_act(id, act);
 }
 
 pub fn gicr_set_pend(id: IrqID, pend: bool, gicr_id: CpuID) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(gicr_id as _).set_pend(id, pend);
 }
 
 pub fn gicr_set_prio(id: IrqID, prio: u8, gicr_id: CpuID) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(gicr_id as _).set_prio(id, prio);
 }
 
 pub fn gicr_set_cfg(id: IrqID, cfg: u8, gicr_id: CpuID) {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(gicr_id as _).set_cfg(id, cfg);
 }
 
 pub fn gicr_get_pidr(addr: u64) -> u32 {
-    let gic = unsafe { GIC.get_mut().unwrap() };
+    let gic = gic();
     let _lock = gic.gicr_lock.lock();
     gic.gicr(0).ID[((addr as usize & 0xffff) - 0xffd0) / 4]
 }
 
-fn gich_num_lrs() -> u32 {
+pub fn gich_num_lrs() -> u32 {
     ((read_reg!(ich_vtr_el2) as u32 & GICH_VTR_MSK) >> GICH_VTR_OFF) + 1
 }
 
@@ -331,6 +242,104 @@ pub fn gich_write_lr(i: u32, val: u64) {
     }
 }
 
+pub fn gich_read_lr(i: u32) -> u64 {
+    match i {
+        0 => read_reg!(ich_lr0_el2),
+        1 => read_reg!(ich_lr1_el2),
+        2 => read_reg!(ich_lr2_el2),
+        3 => read_reg!(ich_lr3_el2),
+        4 => read_reg!(ich_lr4_el2),
+        5 => read_reg!(ich_lr5_el2),
+        6 => read_reg!(ich_lr6_el2),
+        7 => read_reg!(ich_lr7_el2),
+        8 => read_reg!(ich_lr8_el2),
+        9 => read_reg!(ich_lr9_el2),
+        10 => read_reg!(ich_lr10_el2),
+        11 => read_reg!(ich_lr11_el2),
+        12 => read_reg!(ich_lr12_el2),
+        13 => read_reg!(ich_lr13_el2),
+        14 => read_reg!(ich_lr14_el2),
+        15 => read_reg!(ich_lr15_el2),
+        _ => panic!("gich_read_lr: index out of range"),
+    }
+}
+
+/// Active priority registers implemented per group, one for each 32
+/// preemption levels.
+fn gich_num_aprs() -> usize {
+    let prebits = (read_reg!(ich_vtr_el2) as u32 & GICH_VTR_PREBITS_MSK) >> GICH_VTR_PREBITS_OFF;
+    1 << (prebits + 1 - 5)
+}
+
+fn gich_read_apr(grp: usize, i: usize) -> u32 {
+    (match (grp, i) {
+        (0, 0) => read_reg!(ich_ap0r0_el2),
+        (0, 1) => read_reg!(ich_ap0r1_el2),
+        (0, 2) => read_reg!(ich_ap0r2_el2),
+        (0, 3) => read_reg!(ich_ap0r3_el2),
+        (1, 0) => read_reg!(ich_ap1r0_el2),
+        (1, 1) => read_reg!(ich_ap1r1_el2),
+        (1, 2) => read_reg!(ich_ap1r2_el2),
+        (1, 3) => read_reg!(ich_ap1r3_el2),
+        _ => panic!("gich_read_apr: index out of range"),
+    }) as u32
+}
+
+fn gich_write_apr(grp: usize, i: usize, val: u32) {
+    let val = val as u64;
+    match (grp, i) {
+        (0, 0) => write_reg!(ich_ap0r0_el2, val),
+        (0, 1) => write_reg!(ich_ap0r1_el2, val),
+        (0, 2) => write_reg!(ich_ap0r2_el2, val),
+        (0, 3) => write_reg!(ich_ap0r3_el2, val),
+        (1, 0) => write_reg!(ich_ap1r0_el2, val),
+        (1, 1) => write_reg!(ich_ap1r1_el2, val),
+        (1, 2) => write_reg!(ich_ap1r2_el2, val),
+        (1, 3) => write_reg!(ich_ap1r3_el2, val),
+        _ => panic!("gich_write_apr: index out of range"),
+    }
+}
+
+pub fn gich_get_vpmr() -> u8 {
+    (read_reg!(ich_vmcr_el2) >> GICH_VMCR_VPMR_OFF) as u8
+}
+
+pub fn gich_save(state: &mut GichState) {
+    state.hcr = gich_get_hcr();
+    state.vmcr = read_reg!(ich_vmcr_el2) as u32;
+    for i in 0..gich_num_aprs() {
+        state.ap0r[i] = gich_read_apr(0, i);
+        state.ap1r[i] = gich_read_apr(1, i);
+    }
+    for i in 0..gich_num_lrs() {
+        state.lrs[i as usize] = gich_read_lr(i);
+    }
+}
+
+pub fn gich_restore(state: &GichState) {
+    for i in 0..gich_num_lrs() {
+        gich_write_lr(i, state.lrs[i as usize]);
+    }
+    for i in 0..gich_num_aprs() {
+        gich_write_apr(0, i, state.ap0r[i]);
+        gich_write_apr(1, i, state.ap1r[i]);
+    }
+    write_reg!(ich_vmcr_el2, state.vmcr as u64);
+    gich_set_hcr(state.hcr);
+}
+
+pub fn gich_get_misr() -> u32 {
+    read_reg!(ich_misr_el2) as u32
+}
+
+pub fn gich_get_eisr() -> u64 {
+    read_reg!(ich_eisr_el2)
+}
+
+pub fn gich_get_elrsr() -> u64 {
+    read_reg!(ich_elrsr_el2)
+}
+
 pub fn gicc_iar() -> u64 {
     read_reg!(icc_iar1_el1)
 }
@@ -342,3 +351,13 @@ pub fn gicc_eoir(eoir: u32) {
 pub fn gicc_dir(dir: u32) {
     write_reg!(icc_dir_el1, dir as u64)
 }
+
+pub fn gicc_send_sgi(cpu_target: CpuID, sgi_num: IrqID) {
+    let mpidr = PLATFORM.cpu_id_to_mpidr(cpu_target);
+    let sgi = (1 << bit64_extract(mpidr, 0, 4))             // TargetList
+        | (bit64_extract(mpidr, 8, 8) << 16)                // Aff1
+        | ((sgi_num as u64 & 0xf) << 24)                    // INTID
+        | (bit64_extract(mpidr, 16, 8) << 32)               // Aff2
+        | (bit64_extract(mpidr, 32, 8) << 48); // Aff3
+    write_reg!(icc_sgi1r_el1, sgi);
+}
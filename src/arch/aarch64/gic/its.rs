@@ -0,0 +1,472 @@
+#![allow(non_snake_case)]
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ptr::{read_volatile, write_volatile};
+use spin::{Mutex, Once};
+
+use crate::{
+    arch::aarch64::{armv8_a::fences::fence_sync, defs::PAGE_SIZE},
+    baocore::{
+        cpu::mycpu,
+        mem::mem_alloc_page,
+        mmu::sections::SEC_HYP_GLOBAL,
+        types::{CpuID, IrqID, MemOwner, Paddr, Vaddr},
+    },
+    platform::PLATFORM,
+    println,
+    util::{bit64_extract, num_pages, BaoError, BaoResult},
+};
+
+use super::{
+    gic,
+    gic_defs::{
+        GIC_FIRST_LPI, GIC_LPI_CFG_EN_BIT, GIC_LPI_CFG_PRIO_MSK, GIC_LPI_CFG_RES1_BIT,
+        GIC_MAX_LPIS, GICR_PENDBASER_ALIGN, GICR_TYPER_PROC_NUM_LEN, GICR_TYPER_PROC_NUM_OFF,
+    },
+    gicv3::GicrHw,
+};
+
+pub const GITS_CTLR_EN_BIT: u32 = 1 << 0;
+pub const GITS_CTLR_QUIESCENT_BIT: u32 = 1 << 31;
+pub const GITS_TYPER_PHYS_BIT: u64 = 1 << 0;
+pub const GITS_TYPER_ITT_ENTRY_SIZE_OFF: u64 = 4;
+pub const GITS_TYPER_ITT_ENTRY_SIZE_LEN: u64 = 4;
+pub const GITS_TYPER_IDBITS_OFF: u64 = 8;
+pub const GITS_TYPER_IDBITS_LEN: u64 = 5;
+pub const GITS_TYPER_DEVBITS_OFF: u64 = 13;
+pub const GITS_TYPER_DEVBITS_LEN: u64 = 5;
+pub const GITS_TYPER_PTA_BIT: u64 = 1 << 19;
+pub const GITS_TYPER_HCC_OFF: u64 = 24;
+pub const GITS_TYPER_HCC_LEN: u64 = 8;
+
+pub const GITS_BASER_VALID_BIT: u64 = 1 << 63;
+pub const GITS_BASER_TYPE_OFF: u64 = 56;
+pub const GITS_BASER_TYPE_LEN: u64 = 3;
+pub const GITS_BASER_TYPE_DEVICES: u64 = 1;
+pub const GITS_BASER_TYPE_COLLECTIONS: u64 = 4;
+pub const GITS_BASER_ENTRY_SIZE_OFF: u64 = 48;
+pub const GITS_BASER_ENTRY_SIZE_LEN: u64 = 5;
+pub const GITS_BASER_PA_MSK: u64 = ((1 << 48) - 1) & !0xfff;
+pub const GITS_BASER_SHAREABILITY_MSK: u64 = 3 << 10;
+pub const GITS_BASER_CACHE_MSK: u64 = (7 << 59) | (7 << 53);
+pub const GITS_BASER_SIZE_MSK: u64 = 0xff;
+/// Inner shareable, inner write-back tables and queue
+pub const GITS_BASER_DFLT: u64 = (7 << 59) | (1 << 10);
+pub const GITS_CBASER_PA_MSK: u64 = ((1 << 52) - 1) & !0xfff;
+pub const GITS_CQ_OFF_MSK: u64 = ((1 << 20) - 1) & !0x1f;
+pub const GITS_CREADR_STALLED_BIT: u64 = 1 << 0;
+/// The translation register frame, where devices write their MSIs
+pub const GITS_TRANSLATION_FRAME_OFF: u64 = 0x10000;
+
+pub const ITS_CMD_MOVI: u64 = 0x01;
+pub const ITS_CMD_INT: u64 = 0x03;
+pub const ITS_CMD_CLEAR: u64 = 0x04;
+pub const ITS_CMD_SYNC: u64 = 0x05;
+pub const ITS_CMD_MAPD: u64 = 0x08;
+pub const ITS_CMD_MAPC: u64 = 0x09;
+pub const ITS_CMD_MAPTI: u64 = 0x0a;
+pub const ITS_CMD_MAPI: u64 = 0x0b;
+pub const ITS_CMD_INV: u64 = 0x0c;
+pub const ITS_CMD_INVALL: u64 = 0x0d;
+pub const ITS_CMD_MOVALL: u64 = 0x0e;
+pub const ITS_CMD_DISCARD: u64 = 0x0f;
+pub const ITS_CMD_DEVID_OFF: u64 = 32;
+pub const ITS_CMD_PINTID_OFF: u64 = 32;
+pub const ITS_CMD_ITT_MSK: u64 = ((1 << 52) - 1) & !0xff;
+pub const ITS_CMD_RDBASE_OFF: u64 = 16;
+pub const ITS_CMD_V_BIT: u64 = 1 << 63;
+pub const ITS_CMD_SIZE: usize = 32;
+
+/// Largest event and device id widths handled, bounding the interrupt
+/// translation and device tables
+pub const ITS_MAX_EVENT_BITS: usize = 10;
+pub const ITS_MAX_DEVICE_BITS: usize = 12;
+const ITS_CMDQ_PAGES: usize = 1;
+
+#[repr(C)]
+pub struct GitsHw {
+    pub CTLR: u32,                 // 0x0
+    pub IIDR: u32,                 // 0x4
+    pub TYPER: u64,                // 0x8
+    pub pad0: [u8; 0x80 - 0x10],
+    pub CBASER: u64,               // 0x80
+    pub CWRITER: u64,              // 0x88
+    pub CREADR: u64,               // 0x90
+    pub pad1: [u8; 0x100 - 0x98],
+    pub BASER: [u64; 8],           // 0x100
+    pub pad2: [u8; 0xffd0 - 0x140],
+    pub ID: [u32; (0x10000 - 0xffd0) / core::mem::size_of::<u32>()],
+    pub pad3: [u8; 0x40],          // 0x10000
+    pub TRANSLATER: u32,           // 0x10040
+    pub pad4: [u8; 0x20000 - 0x10044],
+}
+
+fn rd<T: Copy>(reg: &T) -> T {
+    unsafe { read_volatile(reg) }
+}
+
+fn wr<T>(reg: &mut T, val: T) {
+    unsafe { write_volatile(reg, val) }
+}
+
+/// Allocates physically contiguous, naturally aligned and zeroed memory for
+/// the its and the redistributors, returning its hypervisor va and pa.
+fn its_alloc(size: usize) -> (Vaddr, Paddr) {
+    let n = its_alloc_pages(size);
+    let va = mem_alloc_page(n, SEC_HYP_GLOBAL, true, MemOwner::HypData).unwrap();
+    unsafe { core::ptr::write_bytes(va as *mut u8, 0, n * PAGE_SIZE) };
+    let pa = mycpu().addr_space.mem_translate(va).unwrap();
+    (va, pa)
+}
+
+fn its_alloc_pages(size: usize) -> usize {
+    num_pages(size).next_power_of_two()
+}
+
+struct ItsDevice {
+    /// Hypervisor va of the interrupt translation table
+    itt: Vaddr,
+    itt_pages: usize,
+    event_bits: usize,
+}
+
+pub struct Its {
+    hw: Vaddr,
+    cmdq: Vaddr,
+    typer: u64,
+    /// Per cpu target of the MAPC and SYNC commands
+    rdbase: Vec<u64>,
+    /// LPI configuration table shared by every redistributor
+    prop: Vaddr,
+    prop_pa: Paddr,
+    devices: BTreeMap<u32, ItsDevice>,
+    free_lpis: Vec<IrqID>,
+    next_lpi: IrqID,
+}
+
+pub static ITS: Once<Mutex<Its>> = Once::new();
+
+impl Its {
+    fn hw(&self) -> &'static mut GitsHw {
+        unsafe { &mut *(self.hw as *mut GitsHw) }
+    }
+
+    fn new(hw_va: Vaddr) -> Option<Self> {
+        let hw = unsafe { &mut *(hw_va as *mut GitsHw) };
+        let typer = rd(&hw.TYPER);
+        if typer & GITS_TYPER_PHYS_BIT == 0 {
+            println!("its: physical lpis not supported");
+            return None;
+        }
+
+        let (cmdq, cmdq_pa) = its_alloc(ITS_CMDQ_PAGES * PAGE_SIZE);
+        wr(
+            &mut hw.CBASER,
+            GITS_BASER_VALID_BIT
+                | GITS_BASER_DFLT
+                | (cmdq_pa & GITS_CBASER_PA_MSK)
+                | (ITS_CMDQ_PAGES as u64 - 1),
+        );
+        if rd(&hw.CBASER) & GITS_BASER_SHAREABILITY_MSK == 0 {
+            println!("its: non-coherent its are not supported");
+            return None;
+        }
+        wr(&mut hw.CWRITER, 0);
+
+        let dev_bits = (bit64_extract(typer, GITS_TYPER_DEVBITS_OFF, GITS_TYPER_DEVBITS_LEN)
+            as usize
+            + 1)
+        .min(ITS_MAX_DEVICE_BITS);
+        let hcc = bit64_extract(typer, GITS_TYPER_HCC_OFF, GITS_TYPER_HCC_LEN) as usize;
+        for i in 0..hw.BASER.len() {
+            let baser = rd(&hw.BASER[i]);
+            let entries = match bit64_extract(baser, GITS_BASER_TYPE_OFF, GITS_BASER_TYPE_LEN) {
+                GITS_BASER_TYPE_DEVICES => 1 << dev_bits,
+                GITS_BASER_TYPE_COLLECTIONS if hcc < PLATFORM.cpu_num => PLATFORM.cpu_num,
+                _ => continue,
+            };
+            let entry_size =
+                bit64_extract(baser, GITS_BASER_ENTRY_SIZE_OFF, GITS_BASER_ENTRY_SIZE_LEN)
+                    as usize
+                    + 1;
+            let size = entries * entry_size;
+            let (_, pa) = its_alloc(size);
+            let kept = baser & ((7 << GITS_BASER_TYPE_OFF) | (0x1f << GITS_BASER_ENTRY_SIZE_OFF));
+            wr(
+                &mut hw.BASER[i],
+                kept | GITS_BASER_VALID_BIT
+                    | GITS_BASER_DFLT
+                    | (pa & GITS_BASER_PA_MSK)
+                    | (num_pages(size).next_power_of_two() as u64 - 1),
+            );
+        }
+
+        let rdbase = (0..PLATFORM.cpu_num)
+            .map(|cpu| {
+                if typer & GITS_TYPER_PTA_BIT != 0 {
+                    PLATFORM.arch.gic.gicr_addr + (cpu * core::mem::size_of::<GicrHw>()) as u64
+                } else {
+                    bit64_extract(
+                        gic().gicr(cpu).typer(),
+                        GICR_TYPER_PROC_NUM_OFF,
+                        GICR_TYPER_PROC_NUM_LEN,
+                    ) << ITS_CMD_RDBASE_OFF
+                }
+            })
+            .collect();
+
+        let (prop, prop_pa) = its_alloc(GIC_MAX_LPIS);
+
+        Some(Self {
+            hw: hw_va,
+            cmdq,
+            typer,
+            rdbase,
+            prop,
+            prop_pa,
+            devices: BTreeMap::new(),
+            free_lpis: Vec::new(),
+            next_lpi: GIC_FIRST_LPI as _,
+        })
+    }
+
+    fn enable(&mut self) {
+        let hw = self.hw();
+        let ctlr = rd(&hw.CTLR);
+        wr(&mut hw.CTLR, ctlr | GITS_CTLR_EN_BIT);
+    }
+
+    /// A collection per cpu, named after it, mapped once the cpu's
+    /// redistributor takes LPIs.
+    fn map_collection(&mut self, cpu: CpuID) {
+        self.cmd([
+            ITS_CMD_MAPC,
+            0,
+            ITS_CMD_V_BIT | self.rdbase[cpu as usize] | cpu,
+            0,
+        ]);
+        self.cmd_sync(cpu);
+    }
+
+    fn cmd(&mut self, cmd: [u64; 4]) {
+        let hw = self.hw();
+        let qsize = (ITS_CMDQ_PAGES * PAGE_SIZE) as u64;
+        let cwriter = rd(&hw.CWRITER) & GITS_CQ_OFF_MSK;
+        let next = (cwriter + ITS_CMD_SIZE as u64) % qsize;
+        while rd(&hw.CREADR) & GITS_CQ_OFF_MSK == next {}
+        let entry = (self.cmdq + cwriter) as *mut u64;
+        for (i, word) in cmd.iter().enumerate() {
+            unsafe { write_volatile(entry.add(i), *word) };
+        }
+        fence_sync();
+        wr(&mut hw.CWRITER, next);
+    }
+
+    /// Waits for every command issued so far to complete, with their effects
+    /// visible at the redistributor of `cpu`.
+    fn cmd_sync(&mut self, cpu: CpuID) {
+        self.cmd([ITS_CMD_SYNC, 0, self.rdbase[cpu as usize], 0]);
+        let hw = self.hw();
+        let cwriter = rd(&hw.CWRITER) & GITS_CQ_OFF_MSK;
+        loop {
+            let creadr = rd(&hw.CREADR);
+            if creadr & GITS_CREADR_STALLED_BIT != 0 {
+                panic!("its: command queue stalled at {:#x}", creadr & GITS_CQ_OFF_MSK);
+            }
+            if creadr & GITS_CQ_OFF_MSK == cwriter {
+                break;
+            }
+        }
+    }
+
+    fn event_bits(&self) -> usize {
+        (bit64_extract(self.typer, GITS_TYPER_IDBITS_OFF, GITS_TYPER_IDBITS_LEN) as usize + 1)
+            .min(ITS_MAX_EVENT_BITS)
+    }
+
+    fn device_bits(&self) -> usize {
+        (bit64_extract(self.typer, GITS_TYPER_DEVBITS_OFF, GITS_TYPER_DEVBITS_LEN) as usize + 1)
+            .min(ITS_MAX_DEVICE_BITS)
+    }
+
+    fn map_device(&mut self, dev: u32, event_bits: usize) -> BaoResult<()> {
+        if dev as usize >= 1 << self.device_bits() || event_bits > self.event_bits() {
+            return Err(BaoError::InvalidParam);
+        }
+        if self.devices.contains_key(&dev) {
+            return Err(BaoError::AlreadyExists);
+        }
+        let entry_size = bit64_extract(
+            self.typer,
+            GITS_TYPER_ITT_ENTRY_SIZE_OFF,
+            GITS_TYPER_ITT_ENTRY_SIZE_LEN,
+        ) as usize
+            + 1;
+        let itt_size = (1 << event_bits) * entry_size;
+        let (itt, itt_pa) = its_alloc(itt_size);
+        self.cmd([
+            ITS_CMD_MAPD | ((dev as u64) << ITS_CMD_DEVID_OFF),
+            event_bits.max(1) as u64 - 1,
+            ITS_CMD_V_BIT | (itt_pa & ITS_CMD_ITT_MSK),
+            0,
+        ]);
+        self.cmd_sync(mycpu().id);
+        self.devices.insert(
+            dev,
+            ItsDevice {
+                itt,
+                itt_pages: its_alloc_pages(itt_size),
+                event_bits,
+            },
+        );
+        Ok(())
+    }
+
+    fn unmap_device(&mut self, dev: u32) {
+        if let Some(device) = self.devices.remove(&dev) {
+            self.cmd([ITS_CMD_MAPD | ((dev as u64) << ITS_CMD_DEVID_OFF), 0, 0, 0]);
+            self.cmd_sync(mycpu().id);
+            mycpu().addr_space.mem_unmap(device.itt, device.itt_pages, true);
+        }
+    }
+
+    fn alloc_lpi(&mut self) -> Option<IrqID> {
+        if let Some(lpi) = self.free_lpis.pop() {
+            Some(lpi)
+        } else if (self.next_lpi as usize) < GIC_FIRST_LPI + GIC_MAX_LPIS {
+            self.next_lpi += 1;
+            Some(self.next_lpi - 1)
+        } else {
+            None
+        }
+    }
+
+    fn map_event(&mut self, dev: u32, event: u32, cpu: CpuID) -> BaoResult<IrqID> {
+        match self.devices.get(&dev) {
+            Some(device) if (event as usize) < 1 << device.event_bits => {}
+            _ => return Err(BaoError::InvalidParam),
+        }
+        let lpi = self.alloc_lpi().ok_or(BaoError::OutOfMemory)?;
+        self.set_lpi_cfg(lpi, 0);
+        self.cmd([
+            ITS_CMD_MAPTI | ((dev as u64) << ITS_CMD_DEVID_OFF),
+            event as u64 | ((lpi as u64) << ITS_CMD_PINTID_OFF),
+            cpu,
+            0,
+        ]);
+        self.cmd_sync(cpu);
+        Ok(lpi)
+    }
+
+    fn event_cmd(&mut self, cmd: u64, dev: u32, event: u32, icid: u64) {
+        self.cmd([
+            cmd | ((dev as u64) << ITS_CMD_DEVID_OFF),
+            event as u64,
+            icid,
+            0,
+        ]);
+    }
+
+    fn set_lpi_cfg(&mut self, lpi: IrqID, cfg: u8) {
+        let entry = (self.prop + (lpi as usize - GIC_FIRST_LPI) as u64) as *mut u8;
+        unsafe { write_volatile(entry, cfg | GIC_LPI_CFG_RES1_BIT) };
+        fence_sync();
+    }
+}
+
+pub fn its_init() {
+    let hw = mycpu()
+        .addr_space
+        .mem_alloc_map_dev(
+            SEC_HYP_GLOBAL,
+            PLATFORM.arch.gic.its_addr,
+            None,
+            num_pages(core::mem::size_of::<GitsHw>()),
+        )
+        .unwrap();
+    let Some(mut its) = Its::new(hw) else {
+        return;
+    };
+    its.enable();
+    ITS.call_once(|| Mutex::new(its));
+}
+
+/// Points the calling cpu's redistributor at the LPI tables, then maps its
+/// collection.
+pub fn its_cpu_init(gicr: &mut GicrHw) {
+    if let Some(its) = ITS.get() {
+        let (_, pend_pa) = its_alloc(GICR_PENDBASER_ALIGN);
+        let mut its = its.lock();
+        if gicr.lpi_init(its.prop_pa, pend_pa) {
+            its.map_collection(mycpu().id);
+        }
+    }
+}
+
+pub fn its_present() -> bool {
+    ITS.get().is_some()
+}
+
+/// The physical its identification registers, shown to guests as is.
+pub fn its_get_pidr(addr: u64) -> u32 {
+    let its = ITS.get().unwrap().lock();
+    rd(&its.hw().ID[((addr as usize & 0xffff) - 0xffd0) / 4])
+}
+
+pub fn its_get_iidr() -> u32 {
+    let its = ITS.get().unwrap().lock();
+    rd(&its.hw().IIDR)
+}
+
+pub fn its_event_bits() -> usize {
+    ITS.get().unwrap().lock().event_bits()
+}
+
+pub fn its_device_bits() -> usize {
+    ITS.get().unwrap().lock().device_bits()
+}
+
+pub fn its_map_device(dev: u32, event_bits: usize) -> BaoResult<()> {
+    ITS.get().ok_or(BaoError::Unsupported)?.lock().map_device(dev, event_bits)
+}
+
+pub fn its_unmap_device(dev: u32) {
+    if let Some(its) = ITS.get() {
+        its.lock().unmap_device(dev);
+    }
+}
+
+/// Has `event` of `dev` raise a newly allocated LPI, disabled, on `cpu`.
+pub fn its_map_event(dev: u32, event: u32, cpu: CpuID) -> BaoResult<IrqID> {
+    ITS.get().ok_or(BaoError::Unsupported)?.lock().map_event(dev, event, cpu)
+}
+
+/// Undoes `its_map_event`, freeing `lpi`.
+pub fn its_unmap_event(dev: u32, event: u32, lpi: IrqID, cpu: CpuID) {
+    let mut its = ITS.get().unwrap().lock();
+    its.set_lpi_cfg(lpi, 0);
+    its.event_cmd(ITS_CMD_DISCARD, dev, event, 0);
+    its.cmd_sync(cpu);
+    its.free_lpis.push(lpi);
+}
+
+pub fn its_move_event(dev: u32, event: u32, cpu: CpuID) {
+    let mut its = ITS.get().unwrap().lock();
+    its.event_cmd(ITS_CMD_MOVI, dev, event, cpu);
+    its.cmd_sync(cpu);
+}
+
+pub fn its_clear_event(dev: u32, event: u32, cpu: CpuID) {
+    let mut its = ITS.get().unwrap().lock();
+    its.event_cmd(ITS_CMD_CLEAR, dev, event, 0);
+    its.cmd_sync(cpu);
+}
+
+/// Sets the priority and enable of the LPI `event` of `dev` raises on `cpu`.
+pub fn its_set_lpi(dev: u32, event: u32, lpi: IrqID, cpu: CpuID, prio: u8, enabled: bool) {
+    let mut its = ITS.get().unwrap().lock();
+    let en = if enabled { GIC_LPI_CFG_EN_BIT } else { 0 };
+    its.set_lpi_cfg(lpi, (prio & GIC_LPI_CFG_PRIO_MSK) | en);
+    its.event_cmd(ITS_CMD_INV, dev, event, 0);
+    its.cmd_sync(cpu);
+}
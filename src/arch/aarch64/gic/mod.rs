@@ -1,38 +1,152 @@
 pub mod gic_defs;
 mod gicd;
+mod gicv2;
 mod gicv3;
+mod its;
+mod vgicv2;
 mod vgicv3;
 pub mod vgic;
+mod vits;
 
 use crate::{
+    arch::aarch64::armv8_a::vm::VGicDscr,
     baocore::{
         cpu::{mycpu, CPU_SYNC_TOKEN},
-        intr::interrupts_reserve, types::IrqID,
+        intr::{interrupts_cpu_enable, interrupts_reserve},
+        mmu::sections::SEC_HYP_GLOBAL,
+        types::{CpuID, IrqID, Vaddr},
+        vm::VM,
     },
     platform::PLATFORM,
+    util::num_pages,
     write_reg,
 };
 
-use self::{vgic::gic_maintenance_handler, gic_defs::{GIC_CPU_PRIV, GIC_MAX_SGIS}};
+use self::{
+    gic_defs::{
+        GICC_IAR_ID_MSK, GICD_CTLR_EN_BIT, GICD_CTLR_ENA_BIT, GICH_HCR_LRENPIE_BIT, GICH_VMCR_VFIQEN_BIT, GICH_MAX_APRS, GIC_CPU_PRIV,
+        GIC_FIRST_LPI, GIC_MAX_LRS, GIC_MAX_SGIS,
+    },
+    gicd::GicdHw,
+    gicv2::{GiccHw, GichHw},
+    gicv3::GicrHw,
+    vgic::{gic_maintenance_handler, vgic_ipi_handler, VGIC_IPI_ID},
+};
 
 use super::{
     armv8_a::fences::isb,
     sysregs::{ICC_SRE_ENB_BIT, ICC_SRE_SRE_BIT},
 };
-use spin::Once;
+use spin::{Mutex, Once};
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum GicVersion {
     GicVersion2,
     GicVersion3,
 }
 
-pub const GIC_VERSION: GicVersion = GicVersion::GicVersion3;
-pub use vgicv3::{vgic_init, gicd_reg_mask, VGIC_ENABLE_MASK};
-pub use gicv3::*;
-type Gic = GicV3;
+pub fn gic_version() -> GicVersion {
+    PLATFORM.arch.gic.version
+}
+
+pub use gicd::{
+    gicd_get_iidr, gicd_get_pidr, gicd_set_act, gicd_set_cfg, gicd_set_enable, gicd_set_pend,
+    gicd_set_prio, gicd_set_route,
+};
+pub use vgicv3::gicd_reg_mask;
+pub use vits::{vits_init, VIts};
 
 pub static mut GIC: Once<Gic> = Once::new();
 
+pub struct Gic {
+    gicd_base: Vaddr,
+    gicr_base: Vaddr,
+    gicc_base: Vaddr,
+    gich_base: Vaddr,
+    pub max_irqs: usize,
+    pub gicd_lock: Mutex<()>,
+    pub gicr_lock: Mutex<()>,
+}
+
+impl Gic {
+    fn new() -> Self {
+        let addr_space = &mut mycpu().addr_space;
+        let mut map = |pa, size| {
+            addr_space
+                .mem_alloc_map_dev(SEC_HYP_GLOBAL, pa, None, num_pages(size))
+                .unwrap()
+        };
+        let desc = &PLATFORM.arch.gic;
+        let gicd_base = map(desc.gicd_addr, core::mem::size_of::<GicdHw>());
+        let (gicr_base, gicc_base, gich_base) = match desc.version {
+            GicVersion::GicVersion2 => (
+                0,
+                map(desc.gicc_addr, core::mem::size_of::<GiccHw>()),
+                map(desc.gich_addr, core::mem::size_of::<GichHw>()),
+            ),
+            GicVersion::GicVersion3 => (
+                map(
+                    desc.gicr_addr,
+                    PLATFORM.cpu_num * core::mem::size_of::<GicrHw>(),
+                ),
+                0,
+                0,
+            ),
+        };
+
+        let mut gic = Self {
+            gicd_base,
+            gicr_base,
+            gicc_base,
+            gich_base,
+            max_irqs: 0,
+            gicd_lock: Mutex::new(()),
+            gicr_lock: Mutex::new(()),
+        };
+        gic.max_irqs = ((gic.gicd().TYPER as usize & 0b11111) + 1) * 32;
+        gic
+    }
+
+    fn gicd_init(&mut self) {
+        self.gicd().init(self.max_irqs);
+    }
+
+    fn each_cpu_init(&mut self, cpu_id: CpuID) {
+        match gic_version() {
+            GicVersion::GicVersion2 => {
+                gicv2::gicd_cpu_init(self.gicd(), cpu_id);
+                gicv2::gicc_init(self.gicc(), self.gich());
+            }
+            GicVersion::GicVersion3 => {
+                self.gicr(cpu_id as _).init();
+                its::its_cpu_init(self.gicr(cpu_id as _));
+                gicv3::gicc_init();
+            }
+        }
+    }
+
+    fn gicd(&self) -> &mut GicdHw {
+        unsafe { &mut *(self.gicd_base as *mut _) }
+    }
+
+    fn gicr(&self, index: usize) -> &mut GicrHw {
+        assert!(index < PLATFORM.cpu_num);
+        unsafe { &mut *((self.gicr_base as *mut GicrHw).add(index)) }
+    }
+
+    fn gicc(&self) -> *mut GiccHw {
+        self.gicc_base as _
+    }
+
+    fn gich(&self) -> *mut GichHw {
+        self.gich_base as _
+    }
+}
+
+fn gic() -> &'static mut Gic {
+    unsafe { GIC.get_mut().unwrap() }
+}
+
 pub const fn gic_is_priv(int_id: IrqID) -> bool {
     int_id < GIC_CPU_PRIV as _
 }
@@ -41,28 +155,259 @@ pub const fn gic_is_sgi(int_id: IrqID) -> bool {
     int_id < GIC_MAX_SGIS as _
 }
 
+pub const fn gic_is_lpi(int_id: IrqID) -> bool {
+    int_id as usize >= GIC_FIRST_LPI
+}
+
 pub fn init() {
-    if let GicVersion::GicVersion3 = GIC_VERSION {
+    if let GicVersion::GicVersion3 = gic_version() {
         write_reg!(ICC_SRE_EL2, ICC_SRE_SRE_BIT | ICC_SRE_ENB_BIT);
         isb();
     }
 
     if mycpu().is_master() {
-        let (gicd, gicr) = gic_map_mmio();
-        let mut gic = Gic::new(gicd, gicr);
-        interrupts_reserve(
-            PLATFORM.arch.gic.maintenance_id,
-            gic_maintenance_handler as _,
-        );
+        let mut gic = Gic::new();
         gic.gicd_init();
         unsafe {
             GIC.call_once(|| gic);
         }
+        if gic_version() == GicVersion::GicVersion3 && PLATFORM.arch.gic.its_addr != 0 {
+            its::its_init();
+        }
     }
 
     CPU_SYNC_TOKEN.sync_and_clear_msg();
 
-    unsafe {
-        GIC.get_mut().unwrap().each_cpu_init(mycpu().id);
+    gic().each_cpu_init(mycpu().id);
+
+    interrupts_reserve(PLATFORM.arch.gic.maintenance_id, gic_maintenance_handler);
+    interrupts_cpu_enable(PLATFORM.arch.gic.maintenance_id, true);
+    interrupts_reserve(VGIC_IPI_ID, vgic_ipi_handler);
+    interrupts_cpu_enable(VGIC_IPI_ID, true);
+}
+
+pub fn vgic_init(vm: &mut VM, vgic_dscrp: &VGicDscr) {
+    match gic_version() {
+        GicVersion::GicVersion2 => vgicv2::vgic_init(vm, vgic_dscrp),
+        GicVersion::GicVersion3 => vgicv3::vgic_init(vm, vgic_dscrp),
+    }
+}
+
+/// The GICD_CTLR bits a guest may set, its group 0 and group 1 enables.
+pub fn vgic_enable_mask() -> u32 {
+    match gic_version() {
+        GicVersion::GicVersion2 => GICD_CTLR_EN_BIT | GICD_CTLR_ENA_BIT,
+        GicVersion::GicVersion3 => vgicv3::VGIC_ENABLE_MASK,
+    }
+}
+
+/// The interrupt id in an acknowledge value, GICv2 also reports the source
+/// cpu of SGIs there.
+pub fn gic_iar_id(ack: u64) -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => ack & GICC_IAR_ID_MSK,
+        GicVersion::GicVersion3 => ack & ((1 << 24) - 1),
+    }
+}
+
+/// The route of a shared interrupt to `cpu_id` alone, an IROUTER value with
+/// GICv3 and an ITARGETSR cpu mask with GICv2.
+pub fn gic_cpu_route(cpu_id: CpuID) -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gic_cpu_mask(cpu_id) as _,
+        GicVersion::GicVersion3 => gicv3::gic_cpu_route(cpu_id),
+    }
+}
+
+// Private interrupts live in each cpu's redistributor with GICv3. GICv2 banks
+// them in the distributor instead, where only the cpu itself reaches them.
+
+fn gic_priv_banked(gicr_id: CpuID) -> bool {
+    match gic_version() {
+        GicVersion::GicVersion2 => {
+            assert_eq!(
+                gicr_id,
+                mycpu().id,
+                "gicv2: private interrupts of other cpus are unreachable"
+            );
+            true
+        }
+        GicVersion::GicVersion3 => false,
+    }
+}
+
+pub fn gicr_set_enable(id: IrqID, enabled: bool, gicr_id: CpuID) {
+    if gic_priv_banked(gicr_id) {
+        gicd_set_enable(id, enabled);
+    } else {
+        gicv3::gicr_set_enable(id, enabled, gicr_id);
+    }
+}
+
+pub fn gicr_set_act(id: IrqID, act: bool, gicr_id: CpuID) {
+    if gic_priv_banked(gicr_id) {
+        gicd_set_act(id, act);
+    } else {
+        gicv3::gicr_set_act(id, act, gicr_id);
+    }
+}
+
+pub fn gicr_set_pend(id: IrqID, pend: bool, gicr_id: CpuID) {
+    if gic_priv_banked(gicr_id) {
+        gicd_set_pend(id, pend);
+    } else {
+        gicv3::gicr_set_pend(id, pend, gicr_id);
+    }
+}
+
+pub fn gicr_set_prio(id: IrqID, prio: u8, gicr_id: CpuID) {
+    if gic_priv_banked(gicr_id) {
+        gicd_set_prio(id, prio);
+    } else {
+        gicv3::gicr_set_prio(id, prio, gicr_id);
+    }
+}
+
+pub fn gicr_set_cfg(id: IrqID, cfg: u8, gicr_id: CpuID) {
+    if gic_priv_banked(gicr_id) {
+        gicd_set_cfg(id, cfg);
+    } else {
+        gicv3::gicr_set_cfg(id, cfg, gicr_id);
+    }
+}
+
+// The cpu interface is system registers with GICv3 and memory mapped with
+// GICv2. List registers are passed around in the GICv3 layout either way.
+
+pub fn gicc_iar() -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gicc_iar(gic().gicc()),
+        GicVersion::GicVersion3 => gicv3::gicc_iar(),
+    }
+}
+
+pub fn gicc_eoir(eoir: u32) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gicc_eoir(gic().gicc(), eoir),
+        GicVersion::GicVersion3 => gicv3::gicc_eoir(eoir),
+    }
+}
+
+pub fn gicc_dir(dir: u32) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gicc_dir(gic().gicc(), dir),
+        GicVersion::GicVersion3 => gicv3::gicc_dir(dir),
+    }
+}
+
+pub fn gicc_send_sgi(cpu_target: CpuID, sgi_num: IrqID) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gicd_send_sgi(gic().gicd(), cpu_target, sgi_num),
+        GicVersion::GicVersion3 => gicv3::gicc_send_sgi(cpu_target, sgi_num),
+    }
+}
+
+pub fn gich_num_lrs() -> u32 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_num_lrs(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_num_lrs(),
+    }
+}
+
+pub fn gich_get_hcr() -> u32 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_get_hcr(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_get_hcr(),
+    }
+}
+
+pub fn gich_set_hcr(hcr: u32) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_set_hcr(gic().gich(), hcr),
+        GicVersion::GicVersion3 => gicv3::gich_set_hcr(hcr),
+    }
+}
+
+pub fn gich_write_lr(i: u32, val: u64) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_write_lr(gic().gich(), i, val),
+        GicVersion::GicVersion3 => gicv3::gich_write_lr(i, val),
+    }
+}
+
+pub fn gich_read_lr(i: u32) -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_read_lr(gic().gich(), i),
+        GicVersion::GicVersion3 => gicv3::gich_read_lr(i),
+    }
+}
+
+/// The priority mask of the vcpu loaded here, as set by its guest.
+pub fn gich_get_vpmr() -> u8 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_get_vpmr(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_get_vpmr(),
+    }
+}
+
+pub fn gich_get_misr() -> u32 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_get_misr(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_get_misr(),
+    }
+}
+
+pub fn gich_get_eisr() -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_get_eisr(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_get_eisr(),
+    }
+}
+
+pub fn gich_get_elrsr() -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_get_elrsr(gic().gich()),
+        GicVersion::GicVersion3 => gicv3::gich_get_elrsr(),
+    }
+}
+
+/// The virtual cpu interface registers of a vcpu that is not loaded. List
+/// registers are in the GICv3 layout either way.
+pub struct GichState {
+    pub hcr: u32,
+    pub vmcr: u32,
+    pub ap0r: [u32; GICH_MAX_APRS],
+    pub ap1r: [u32; GICH_MAX_APRS],
+    pub lrs: [u64; GIC_MAX_LRS],
+}
+
+impl GichState {
+    /// The state `gicc_init` leaves the interface in. GICv3 guests get group 0
+    /// as virtual FIQs, GICv2 ones choose with GICC_CTLR.FIQEn.
+    pub fn new() -> Self {
+        Self {
+            hcr: GICH_HCR_LRENPIE_BIT,
+            vmcr: match gic_version() {
+                GicVersion::GicVersion2 => 0,
+                GicVersion::GicVersion3 => GICH_VMCR_VFIQEN_BIT,
+            },
+            ap0r: [0; GICH_MAX_APRS],
+            ap1r: [0; GICH_MAX_APRS],
+            lrs: [0; GIC_MAX_LRS],
+        }
+    }
+}
+
+pub fn gich_save(state: &mut GichState) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_save(gic().gich(), state),
+        GicVersion::GicVersion3 => gicv3::gich_save(state),
+    }
+}
+
+pub fn gich_restore(state: &GichState) {
+    match gic_version() {
+        GicVersion::GicVersion2 => gicv2::gich_restore(gic().gich(), state),
+        GicVersion::GicVersion3 => gicv3::gich_restore(state),
     }
 }
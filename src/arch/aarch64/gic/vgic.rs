@@ -1,33 +1,36 @@
-use alloc::vec::Vec;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
 use spin::{Mutex, RwLock};
 
 use crate::{
-    arch::aarch64::gic::{
-        gic_is_priv, gicd_reg_mask, gich_get_hcr, gich_set_hcr, gicr_set_act, gicr_set_enable,
-        gicr_set_pend, gicr_set_prio,
-        gicv3::{gicd_set_act, gicd_set_pend, gicd_set_prio, gicd_set_route},
-        VGIC_ENABLE_MASK, gicd_set_cfg, gicr_set_cfg,
-    },
     baocore::{
+        cpu::mycpu,
         emul::EmulAccess,
         types::{IrqID, VCpuID, Vaddr, CpuID},
         vm::{myvcpu, myvm, VCpu, VM},
+        vmm::vmm_intr_owner,
     },
     debug,
-    platform::{ArchPlatformTrait, PLATFORM},
-    read_reg,
-    util::bit64_extract, info, println,
+    platform::PLATFORM,
+    util::{bit64_extract, bit64_mask, BaoError, BaoResult},
+    info, println,
 };
 
 use super::{
     gic_defs::{
-        GICD_CTLR_ARE_NS_BIT, GICD_IROUTER_INV, GICH_LR_GRP_BIT, GICH_LR_HW_BIT, GIC_CPU_PRIV,
+        GICD_CTLR_ARE_NS_BIT, GICD_IROUTER_AFF_MSK, GICD_IROUTER_INV, GICD_IROUTER_IRM_BIT,
+        GICH_HCR_EN_BIT, GICH_HCR_EOICOUNT_MSK, GICH_HCR_UIE_BIT, GICH_LR_EOI_BIT, GICH_LR_GRP_BIT, GICH_LR_HW_BIT,
+        GICH_LR_PRIO_LEN, GICH_LR_PRIO_OFF, GICH_LR_STATE_ACT, GICH_LR_STATE_MSK, GICH_LR_STATE_PEND,
+        GICH_LR_VID_MSK, GICH_MISR_EOI_BIT, GICH_MISR_LRENP_BIT, GICH_MISR_U_BIT, GIC_CPU_PRIV,
         GIC_MAX_SGIS,
     },
-    gic_is_sgi, gicd_get_pidr, gich_write_lr,
-    gicv3::gicd_set_enable,
+    gic_cpu_route, gic_is_lpi, gic_is_priv, gic_is_sgi, gic_version, gicc_send_sgi, gicd_get_pidr,
+    gicd_reg_mask, gicd_set_act, gicd_set_cfg, gicd_set_enable, gicd_set_pend, gicd_set_prio,
+    gicd_set_route, gich_get_eisr, gich_get_elrsr, gich_get_hcr, gich_get_misr, gich_num_lrs,
+    gich_get_vpmr, gich_read_lr, gich_set_hcr, gich_write_lr, gicr_set_act, gicr_set_cfg, gicr_set_enable,
+    gicr_set_pend, gicr_set_prio, gich_restore, gich_save, vgic_enable_mask,
+    vgicv2::{vgic_int_get_target, vgic_int_set_target, vgicd_emul_sgir_access},
     vgicv3::VGicR,
-    GicVersion, GIC_VERSION,
+    GicVersion, GichState,
 };
 
 pub struct VGicIntr {
@@ -42,14 +45,18 @@ impl VGicIntr {
     }
 
     pub fn set_field(&mut self, handlers: &VGicHandlerInfo, data: u64, vcpu: *mut VCpu) {
-        // todo: vgic_int_set_field
         let mut intr_inner = self.inner.write();
         if intr_inner.set_ownership(vcpu) {
-            // todo: vgic_remove_lr
+            // The guest changes the state the list register held
+            vgic_remove_lr(&mut intr_inner);
             let update_field = handlers.update_field.unwrap();
             if update_field(vcpu, &mut intr_inner, data) && intr_inner.is_hw() {
-                let update_hw = handlers.update_hw.unwrap();
-                update_hw(vcpu, &mut intr_inner);
+                if let Some(update_hw) = handlers.update_hw {
+                    update_hw(vcpu, &mut intr_inner);
+                }
+            }
+            if intr_inner.pend || intr_inner.active {
+                vgic_add_lr(unsafe { &mut *vcpu }, &mut intr_inner);
             }
         }
     }
@@ -61,6 +68,8 @@ pub struct VGicIntrInner {
     pub pend: bool,
     pub active: bool,
     pub id: IrqID,
+    /// The hardware interrupt behind a hw interrupt, `id` is what the vm sees
+    pub phys_id: IrqID,
     pub hw: bool,
     pub prio: u8,
     pub route: u64,
@@ -68,6 +77,11 @@ pub struct VGicIntrInner {
     pub redist: u64,
     pub in_lr: bool,
     pub cfg: u8,
+    /// Group 1 interrupts are signaled as virtual IRQs, group 0 ones as
+    /// virtual FIQs
+    pub group1: bool,
+    /// The vcpu that last sent this SGI, GICv2 tells it to the guest
+    pub sgi_src: VCpuID,
 }
 
 impl VGicIntrInner {
@@ -84,7 +98,11 @@ impl VGicIntrInner {
             phys_route: GICD_IROUTER_INV,
             redist,
             cfg: 0,
+            // GICv3 guests expect the non-secure group 1, GICv2 ones group 0
+            group1: gic_version() == GicVersion::GicVersion3,
             id,
+            phys_id: id,
+            sgi_src: 0,
         }
     }
 
@@ -98,13 +116,20 @@ impl VGicIntrInner {
         }
     }
 
-    pub fn is_hw(&mut self) -> bool {
+    pub fn is_hw(&self) -> bool {
         self.id >= GIC_MAX_SGIS as _ && self.hw
     }
 }
 
 pub struct VGicD {
     pub interrupts: Vec<VGicIntr>,
+    /// Virtual id of each hardware interrupt assigned to the vm
+    pub hw_virt_ids: BTreeMap<IrqID, IrqID>,
+    /// LPIs the guest mapped through its ITS, kept once created so they can
+    /// be handed out without the lock
+    pub lpis: RwLock<BTreeMap<IrqID, Box<VGicIntr>>>,
+    /// Virtual id of each physical LPI the vm's devices raise
+    pub lpi_virt_ids: RwLock<BTreeMap<IrqID, IrqID>>,
     pub int_num: usize,
     pub ctlr: u32,
     pub typer: u32,
@@ -116,6 +141,9 @@ impl VGicD {
     pub fn new() -> Self {
         Self {
             interrupts: Vec::new(),
+            hw_virt_ids: BTreeMap::new(),
+            lpis: RwLock::new(BTreeMap::new()),
+            lpi_virt_ids: RwLock::new(BTreeMap::new()),
             int_num: 0,
             ctlr: 0,
             typer: 0,
@@ -123,11 +151,24 @@ impl VGicD {
             lock: Mutex::new(()),
         }
     }
+
+    pub fn lpi(&self, id: IrqID) -> Option<&'static mut VGicIntr> {
+        self.lpis
+            .read()
+            .get(&id)
+            .map(|intr| unsafe { &mut *(intr.as_ref() as *const VGicIntr as *mut VGicIntr) })
+    }
 }
 
 pub struct VGicPriv {
     pub vgicr: VGicR,
     pub _curr_lrs: Vec<IrqID>,
+    /// Virtual interrupts injected from other cpus, loaded on the next
+    /// `VGIC_IPI_ID`
+    pub pend_queue: Mutex<Vec<IrqID>>,
+    /// Pending interrupts that found no list register, loaded highest
+    /// priority first as list registers free up
+    pub spilled: Vec<IrqID>,
     pub interrupts: Vec<VGicIntr>,
 }
 
@@ -139,8 +180,12 @@ impl VGicPriv {
                 typer: 0,
                 ctlr: 0,
                 iidr: 0,
+                propbaser: 0,
+                pendbaser: 0,
             },
             _curr_lrs: Vec::new(),
+            pend_queue: Mutex::new(Vec::new()),
+            spilled: Vec::new(),
             interrupts: {
                 let mut intrs = Vec::with_capacity(GIC_CPU_PRIV);
                 for i in 0..GIC_CPU_PRIV {
@@ -164,11 +209,11 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
             update_hw: None,
         },
         GICD_REG_GROUP_IGROUPR => VGicHandlerInfo {
-            reg_access: vgic_emul_razwi,
-            field_width: 0,
-            regroup_base: 0,
-            read_field: None,
-            update_field: None,
+            reg_access: vgic_emul_generic_access,
+            field_width: 1,
+            regroup_base: GICD_REG_IGROUPR_OFF,
+            read_field: Some(vgic_int_get_group),
+            update_field: Some(vgic_int_set_group),
             update_hw: None,
         },
         GICD_REG_GROUP_ISENABLER => VGicHandlerInfo {
@@ -187,6 +232,14 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
             update_field: Some(vgic_int_clear_enable),
             update_hw: Some(vgic_int_enable_hw),
         },
+        GICD_REG_GROUP_ISPENDR => VGicHandlerInfo {
+            reg_access: vgic_emul_generic_access,
+            field_width: 1,
+            regroup_base: GICD_REG_ISPENDR_OFF,
+            read_field: Some(vgic_int_get_pend),
+            update_field: Some(vgic_int_set_pend),
+            update_hw: Some(vgic_int_state_hw),
+        },
         GICD_REG_GROUP_ICPENDR => VGicHandlerInfo {
             reg_access: vgic_emul_generic_access,
             field_width: 1,
@@ -195,6 +248,14 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
             update_field: Some(vgic_int_clear_pend),
             update_hw: Some(vgic_int_state_hw),
         },
+        GICD_REG_GROUP_ISACTIVER => VGicHandlerInfo {
+            reg_access: vgic_emul_generic_access,
+            field_width: 1,
+            regroup_base: GICD_REG_ISACTIVER_OFF,
+            read_field: Some(vgic_int_get_act),
+            update_field: Some(vgic_int_set_act),
+            update_hw: Some(vgic_int_state_hw),
+        },
         GICD_REG_GROUP_ICACTIVER => VGicHandlerInfo {
             reg_access: vgic_emul_generic_access,
             field_width: 1,
@@ -222,18 +283,9 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
                     update_field: Some(vgic_int_set_prio),
                     update_hw: Some(vgic_int_set_prio_hw),
                 }
-            } else if gicd_reg >= GICD_REG_ITARGETSR_OFF
-                && gicd_reg < (GICD_REG_ITARGETSR_OFF + 0x400)
-            {
-                VGicHandlerInfo {
-                    reg_access: vgic_emul_razwi,
-                    field_width: 0,
-                    regroup_base: 0,
-                    read_field: None,
-                    update_field: None,
-                    update_hw: None,
-                }
-            } else if gicd_reg >= GICD_REG_IROUTER_OFF && gicd_reg < (GICD_REG_IROUTER_OFF + 0x2000)
+            } else if gic_version() == GicVersion::GicVersion3
+                && gicd_reg >= GICD_REG_IROUTER_OFF
+                && gicd_reg < (GICD_REG_IROUTER_OFF + 0x2000)
             {
                 VGicHandlerInfo {
                     reg_access: vgic_emul_generic_access,
@@ -243,7 +295,28 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
                     update_field: Some(vgic_int_set_route),
                     update_hw: Some(vgic_int_set_route_hw),
                 }
-            } else if gicd_reg >= GICD_REG_ID_OFF {
+            } else if gic_version() == GicVersion::GicVersion2
+                && gicd_reg >= GICD_REG_ITARGETSR_OFF
+                && gicd_reg < (GICD_REG_ITARGETSR_OFF + 0x400)
+            {
+                VGicHandlerInfo {
+                    reg_access: vgic_emul_generic_access,
+                    field_width: 8,
+                    regroup_base: GICD_REG_ITARGETSR_OFF,
+                    read_field: Some(vgic_int_get_target),
+                    update_field: Some(vgic_int_set_target),
+                    update_hw: Some(vgic_int_set_route_hw),
+                }
+            } else if gic_version() == GicVersion::GicVersion2 && gicd_reg == GICD_REG_SGIR_OFF {
+                VGicHandlerInfo {
+                    reg_access: vgicd_emul_sgir_access,
+                    field_width: 0,
+                    regroup_base: 0,
+                    read_field: None,
+                    update_field: None,
+                    update_hw: None,
+                }
+            } else if (vgicd_id_off()..vgicd_id_off() + GICD_REG_ID_SIZE).contains(&gicd_reg) {
                 VGicHandlerInfo {
                     reg_access: vgicd_emul_pidr_access,
                     field_width: 0,
@@ -253,16 +326,70 @@ pub fn vgicd_emul_handler(acc: &EmulAccess) -> bool {
                     update_hw: None,
                 }
             } else {
-                todo!("vgicd_emul_handler");
+                // ITARGETSR and SGIR are ignored with affinity routing, there
+                // is a single security state, so no IGRPMODR or NSACR, and no
+                // extended SPI range. SGIs are never pending in GICv2's
+                // CPENDSGIR/SPENDSGIR as they go straight to a list register.
+                debug!("gicd: razwi access {:#x?}", acc.addr);
+                VGIC_RAZWI_HANDLER_INFO
             }
         }
     };
 
+    if !vgic_access_valid(acc, handler_info.field_width) {
+        debug!("gicd: bad access {:#x?} (width {})", acc.addr, acc.width);
+        vgic_emul_razwi(acc, &handler_info, false, myvcpu().id);
+        return true;
+    }
+
     let _vgicd_mutex = myvm().arch.vgicd.lock.lock();
     (handler_info.reg_access)(acc, &handler_info, false, myvcpu().id);
     true
 }
 
+/// Reads or writes the part of the 64-bit register `reg` a word or
+/// doubleword access covers, writing only the bits in `wmask`.
+pub fn vgic_reg64_access(acc: &EmulAccess, reg: &mut u64, wmask: u64) {
+    let shift = (acc.addr % 8) * 8;
+    let part = bit64_mask(shift, acc.width * 8);
+    if acc.write {
+        let val = myvcpu().read_reg(acc.reg) << shift;
+        *reg = (*reg & !(part & wmask)) | (val & part & wmask);
+    } else {
+        myvcpu().write_reg(acc.reg, (*reg & part) >> shift);
+    }
+}
+
+/// Registers are accessed naturally aligned, as words, and also as bytes or
+/// doublewords when their fields are that wide.
+pub fn vgic_access_valid(acc: &EmulAccess, field_width: u64) -> bool {
+    let width_ok = match acc.width {
+        4 => true,
+        1 => field_width == 8,
+        8 => field_width == 64,
+        _ => false,
+    };
+    width_ok && acc.addr % acc.width == 0
+}
+
+/// Where the ID registers start, at the end of GICv2's 4K distributor frame.
+/// GICv2 has nothing after them, the rest of its window is RAZ/WI.
+fn vgicd_id_off() -> u64 {
+    match gic_version() {
+        GicVersion::GicVersion2 => GICD_REG_ID_V2_OFF,
+        GicVersion::GicVersion3 => GICD_REG_ID_OFF,
+    }
+}
+
+pub const VGIC_RAZWI_HANDLER_INFO: VGicHandlerInfo = VGicHandlerInfo {
+    reg_access: vgic_emul_razwi,
+    regroup_base: 0,
+    field_width: 0,
+    read_field: None,
+    update_field: None,
+    update_hw: None,
+};
+
 pub struct VGicHandlerInfo {
     pub reg_access:
         fn(acc: &EmulAccess, handlers: &VGicHandlerInfo, gicr_access: bool, vgicr_id: VCpuID),
@@ -274,11 +401,18 @@ pub struct VGicHandlerInfo {
 }
 
 fn vgic_get_int(int_id: IrqID, vgicr_id: VCpuID) -> Option<&'static mut VGicIntr> {
+    vgic_get_vm_int(myvm(), int_id, vgicr_id)
+}
+
+fn vgic_get_vm_int(vm: *mut VM, int_id: IrqID, vgicr_id: VCpuID) -> Option<&'static mut VGicIntr> {
+    let vm = unsafe { &mut *vm };
     if gic_is_priv(int_id) {
-        Some(&mut myvm().get_vcpu_mut(vgicr_id).arch.vgic_priv.interrupts[int_id as usize])
-    } else if int_id < myvm().arch.vgicd.int_num as _ {
-        // assert!(myvm().arch.vgicd.lock.is_locked());
-        Some(&mut myvm().arch.vgicd.interrupts[int_id as usize - GIC_CPU_PRIV])
+        Some(&mut vm.get_vcpu_mut(vgicr_id).arch.vgic_priv.interrupts[int_id as usize])
+    } else if int_id < vm.arch.vgicd.int_num as _ {
+        // assert!(vm.arch.vgicd.lock.is_locked());
+        Some(&mut vm.arch.vgicd.interrupts[int_id as usize - GIC_CPU_PRIV])
+    } else if gic_is_lpi(int_id) {
+        vm.arch.vgicd.lpi(int_id)
     } else {
         None
     }
@@ -308,7 +442,7 @@ pub fn vgicd_emul_misc_access(
         GICD_REG_INDEX_CTLR => {
             if acc.write {
                 let prev_ctrl = vgicd.ctlr;
-                vgicd.ctlr = myvcpu().read_reg(acc.reg) as u32 & VGIC_ENABLE_MASK;
+                vgicd.ctlr = myvcpu().read_reg(acc.reg) as u32 & vgic_enable_mask();
                 debug!("write gicd.ctlr: {:#x?}", vgicd.ctlr);
 
                 if prev_ctrl ^ vgicd.ctlr != 0 {
@@ -323,8 +457,12 @@ pub fn vgicd_emul_misc_access(
                     // vm_msg_broadcast(cpu().vcpu.vm, &msg);
                 }
             } else {
-                myvcpu().write_reg(acc.reg as u64, (vgicd.ctlr | GICD_CTLR_ARE_NS_BIT) as u64);
-                debug!("read gicd.ctlr: {:#x?}", vgicd.ctlr | GICD_CTLR_ARE_NS_BIT);
+                let ctlr = match gic_version() {
+                    GicVersion::GicVersion2 => vgicd.ctlr,
+                    GicVersion::GicVersion3 => vgicd.ctlr | GICD_CTLR_ARE_NS_BIT,
+                };
+                myvcpu().write_reg(acc.reg as u64, ctlr as u64);
+                debug!("read gicd.ctlr: {:#x?}", ctlr);
             }
         }
         GICD_REG_INDEX_TYPER => {
@@ -339,22 +477,55 @@ pub fn vgicd_emul_misc_access(
                 debug!("read gicd.iidr: {:#x?}", vgicd.iidr);
             }
         }
+        GICD_REG_INDEX_SETSPI_NSR | GICD_REG_INDEX_CLRSPI_NSR => {
+            if acc.write {
+                let id = bit64_extract(myvcpu().read_reg(acc.reg), 0, 10) as IrqID;
+                vgicd_emul_spi_pend(id, reg == GICD_REG_INDEX_SETSPI_NSR);
+            } else {
+                myvcpu().write_reg(acc.reg, 0);
+            }
+        }
         _ => {
+            // TYPER2, STATUSR and the secure SETSPI/CLRSPI
             debug!(
                 "unknown gicd access {:#x?} (vgicd_emul_misc_access)",
                 acc.addr
             );
+            if !acc.write {
+                myvcpu().write_reg(acc.reg, 0);
+            }
         }
     }
 }
 
+/// Message based SPI pending state changes, through SETSPI_NSR and CLRSPI_NSR.
+fn vgicd_emul_spi_pend(id: IrqID, pend: bool) {
+    if gic_is_priv(id) {
+        return;
+    }
+    if let Some(interrupt) = vgic_get_int(id, myvcpu().id) {
+        let handlers = VGicHandlerInfo {
+            reg_access: vgic_emul_razwi,
+            regroup_base: 0,
+            field_width: 1,
+            read_field: Some(vgic_int_get_pend),
+            update_field: Some(if pend {
+                vgic_int_set_pend
+            } else {
+                vgic_int_clear_pend
+            }),
+            update_hw: Some(vgic_int_state_hw),
+        };
+        interrupt.set_field(&handlers, 1, myvcpu());
+    }
+}
+
 pub fn vgic_emul_generic_access(
     acc: &EmulAccess,
     handlers: &VGicHandlerInfo,
     gicr_access: bool,
     vgicr_id: VCpuID,
 ) {
-    println!("field_with = {}", handlers.field_width);
     let field_width = handlers.field_width;
     let first_int = (gicd_reg_mask(acc.addr) - handlers.regroup_base) * 8 / field_width;
     let mut val = if acc.write {
@@ -367,31 +538,44 @@ pub fn vgic_emul_generic_access(
     } else {
         (1u64 << field_width) - 1
     };
-    let valid_access = if let GicVersion::GicVersion2 = GIC_VERSION {
+    let valid_access = if let GicVersion::GicVersion2 = gic_version() {
         true
     } else {
         gicr_access == gic_is_priv(first_int as _)
     };
 
+    // A word access to a 64-bit field only covers half of it
+    let (shift, part) = if field_width > acc.width * 8 {
+        let shift = (acc.addr % (field_width / 8)) * 8;
+        (shift, bit64_mask(shift, acc.width * 8))
+    } else {
+        (0, mask)
+    };
+
     if valid_access {
         for i in 0..(1.max((acc.width * 8) / field_width)) {
             let interrupt = vgic_get_int((first_int + i) as _, vgicr_id);
             if interrupt.is_none() {
                 break;
             }
+            let interrupt = interrupt.unwrap();
+            let read_field = handlers.read_field.unwrap();
             if acc.write {
-                let data = bit64_extract(val, i * field_width, field_width);
-                interrupt.unwrap().set_field(handlers, data, myvcpu());
+                let mut data = bit64_extract(val, i * field_width, field_width);
+                if part != mask {
+                    let old = read_field(myvcpu(), &mut interrupt.inner.write());
+                    data = (old & !part) | ((val << shift) & part);
+                }
+                interrupt.set_field(handlers, data, myvcpu());
             } else {
-                let read_field = handlers.read_field.unwrap();
-                let mut intr_inner = interrupt.unwrap().inner.write();
-                val |= (read_field(myvcpu(), &mut intr_inner) & mask) << (i * field_width);
+                let mut intr_inner = interrupt.inner.write();
+                let field = read_field(myvcpu(), &mut intr_inner) & mask;
+                val |= ((field & part) >> shift) << (i * field_width);
             }
         }
     }
 
     if !acc.write {
-        println!("val = {:#x?}", val);
         myvcpu().write_reg(acc.reg, val);
     }
 }
@@ -409,36 +593,164 @@ pub fn vgicd_emul_pidr_access(
 }
 
 fn vgic_update_enable() {
-    if myvm().arch.vgicd.ctlr & VGIC_ENABLE_MASK != 0 {
-        gich_set_hcr(gich_get_hcr() | 0x1);
+    if myvm().arch.vgicd.ctlr & vgic_enable_mask() != 0 {
+        gich_set_hcr(gich_get_hcr() | GICH_HCR_EN_BIT);
         debug!("GicH HCR enabled.");
     } else {
-        gich_set_hcr(gich_get_hcr() & !0x1);
+        gich_set_hcr(gich_get_hcr() & !GICH_HCR_EN_BIT);
         debug!("GicH HCR disabled.");
     }
 }
 
-pub fn gic_maintenance_handler() {
-    todo!("gic_maintenance_handler");
+pub fn gic_maintenance_handler(_id: IrqID) {
+    let misr = gich_get_misr();
+    if misr & GICH_MISR_EOI_BIT != 0 {
+        vgic_handle_eoi();
+    }
+    if misr & GICH_MISR_LRENP_BIT != 0 {
+        // Only hw interrupts are deactivated without a list register, their
+        // state lives in the distributor, so there is nothing to refill.
+        gich_set_hcr(gich_get_hcr() & !GICH_HCR_EOICOUNT_MSK);
+    }
+    if misr & (GICH_MISR_EOI_BIT | GICH_MISR_U_BIT) != 0 {
+        vgic_refill_lrs(myvcpu());
+    }
+}
+
+/// Frees the list registers of the virtual interrupts the guest deactivated,
+/// loading again the ones injected meanwhile.
+fn vgic_handle_eoi() {
+    let vcpu = myvcpu();
+    let eisr = gich_get_eisr();
+    for lr_ind in (0..gich_num_lrs()).filter(|i| eisr & (1 << i) != 0) {
+        let id = (gich_read_lr(lr_ind) & GICH_LR_VID_MSK) as IrqID;
+        gich_write_lr(lr_ind, 0);
+        if let Some(interrupt) = vgic_get_int(id, vcpu.id) {
+            let mut intr = interrupt.inner.write();
+            intr.in_lr = false;
+            intr.active = false;
+            if intr.pend {
+                vgic_add_lr(myvcpu(), &mut intr);
+            }
+        }
+    }
+}
+
+/// Stores the virtual cpu interface of `vcpu`, loaded on this cpu, so another
+/// vcpu can take the cpu.
+pub fn vgic_save_state(vcpu: &mut VCpu) {
+    gich_save(&mut vcpu.arch.vgic_state);
+}
+
+/// Loads the virtual cpu interface of `vcpu` on this cpu.
+pub fn vgic_restore_state(vcpu: &VCpu) {
+    gich_restore(&vcpu.arch.vgic_state);
+}
+
+/// Empties the list registers of `vcpu`, loaded on this cpu, dropping the
+/// interrupts in them, and clears its active priorities. The physical
+/// interrupts behind hw ones are deactivated, the guest no longer can.
+pub fn vgic_reset_state(vcpu: &mut VCpu) {
+    vgic_save_state(vcpu);
+    for lr in vcpu.arch.vgic_state.lrs {
+        if lr & GICH_LR_STATE_MSK == 0 {
+            continue;
+        }
+        let id = (lr & GICH_LR_VID_MSK) as IrqID;
+        if let Some(interrupt) = vgic_get_vm_int(vcpu.vm, id, vcpu.id) {
+            let mut intr = interrupt.inner.write();
+            intr.in_lr = false;
+            intr.pend = false;
+            intr.active = false;
+            if lr & GICH_LR_HW_BIT != 0 {
+                vgic_int_state_hw(vcpu, &mut intr);
+            }
+        }
+    }
+    for id in core::mem::take(&mut vcpu.arch.vgic_priv.spilled) {
+        if let Some(interrupt) = vgic_get_vm_int(vcpu.vm, id, vcpu.id) {
+            interrupt.inner.write().pend = false;
+        }
+    }
+    let mut state = GichState::new();
+    if unsafe { &*vcpu.vm }.arch.vgicd.ctlr & vgic_enable_mask() != 0 {
+        state.hcr |= GICH_HCR_EN_BIT;
+    }
+    vcpu.arch.vgic_state = state;
+    vgic_restore_state(vcpu);
 }
 
-pub fn vgic_set_hw(vm: &mut VM, id: IrqID) {
-    if gic_is_sgi(id) {
+/// Kicks a cpu to load the virtual interrupts other cpus queued for its vcpu.
+pub const VGIC_IPI_ID: IrqID = 0;
+
+pub fn vgic_ipi_handler(_id: IrqID) {
+    let vcpu = myvcpu();
+    let pending = core::mem::take(&mut *vcpu.arch.vgic_priv.pend_queue.lock());
+    for id in pending {
+        if let Some(interrupt) = vgic_get_int(id, vcpu.id) {
+            vgic_add_lr(myvcpu(), &mut interrupt.inner.write());
+        }
+    }
+}
+
+/// Passes the hardware interrupt `phys_id` through to the vm, which sees it
+/// as `id`.
+pub fn vgic_set_hw(vm: &mut VM, id: IrqID, phys_id: IrqID) {
+    if gic_is_sgi(id) || gic_is_sgi(phys_id) {
         return;
     }
+    if gic_is_priv(id) != gic_is_priv(phys_id) {
+        panic!(
+            "vm {}: interrupt {} cannot be passed through as {}",
+            vm.id, phys_id, id
+        );
+    }
+    if vm.arch.vgicd.hw_virt_ids.insert(phys_id, id).is_some() {
+        panic!("vm {}: interrupt {} assigned twice", vm.id, phys_id);
+    }
+
+    let vm_ptr = vm as *mut VM;
+    let set_hw = |interrupt: &mut VGicIntr| {
+        let mut intr = interrupt.inner.write();
+        if intr.hw {
+            panic!(
+                "vm {}: interrupts {} and {} both passed through as {}",
+                vm.id, intr.phys_id, phys_id, id
+            );
+        }
+        intr.hw = true;
+        intr.phys_id = phys_id;
+    };
     if gic_is_priv(id) {
         for vcpuid in 0..vm.cpu_num {
-            let interrupt = vgic_get_int(id, vcpuid as _).unwrap();
-            interrupt.inner.write().hw = true;
+            set_hw(vgic_get_vm_int(vm_ptr, id, vcpuid as _).unwrap());
         }
     } else {
-        let _lock = myvm().arch.vgicd.lock.lock();
-        let interrupt = vgic_get_int(id, myvcpu().id).unwrap();
-        interrupt.inner.write().hw = true;
+        let _lock = vm.arch.vgicd.lock.lock();
+        let Some(interrupt) = vgic_get_vm_int(vm_ptr, id, 0) else {
+            panic!("vm {}: no virtual interrupt {}", vm.id, id);
+        };
+        set_hw(interrupt);
+        // Until the guest routes it, keep it on one of the vm's own cpus
+        let route = gic_cpu_route(vm.master);
+        interrupt.inner.write().phys_route = route;
+        gicd_set_route(phys_id, route);
     }
 }
 
 // ----------------------------
+pub fn vgic_int_get_group(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) -> u64 {
+    intr.group1 as _
+}
+
+/// The group only decides how the interrupt is signaled to the guest, the
+/// physical one stays in the group the hypervisor handles.
+pub fn vgic_int_set_group(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
+    intr.group1 = data != 0;
+    debug!("intr {} set group {}.", intr.id, intr.group1 as u8);
+    true
+}
+
 pub fn vgic_int_get_enable(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) -> u64 {
     debug!("get intr {} enable: {}.", intr.id, intr.enabled);
     intr.enabled as _
@@ -464,9 +776,9 @@ pub fn vgic_int_clear_enable(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u
 
 pub fn vgic_int_enable_hw(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) {
     if gic_is_priv(intr.id) {
-        gicr_set_enable(intr.id, intr.enabled, intr.redist);
+        gicr_set_enable(intr.phys_id, intr.enabled, intr.redist);
     } else {
-        gicd_set_enable(intr.id, intr.enabled);
+        gicd_set_enable(intr.phys_id, intr.enabled);
     }
     info!(
         "**********************intr {} (hardware) {}.",
@@ -480,6 +792,15 @@ pub fn vgic_int_get_pend(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) -> u64 {
     intr.pend as _
 }
 
+pub fn vgic_int_set_pend(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
+    if data == 0 {
+        return false;
+    }
+    intr.pend = true;
+    debug!("intr {} set pend.", intr.id);
+    true
+}
+
 pub fn vgic_int_clear_pend(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
     if data == 0 {
         return false;
@@ -494,6 +815,15 @@ pub fn vgic_int_get_act(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) -> u64 {
     intr.active as _
 }
 
+pub fn vgic_int_set_act(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
+    if data == 0 {
+        return false;
+    }
+    intr.active = true;
+    debug!("intr {} set active.", intr.id);
+    true
+}
+
 pub fn vgic_int_clear_act(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
     if data == 0 {
         return false;
@@ -503,13 +833,16 @@ pub fn vgic_int_clear_act(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64)
     true
 }
 
+/// A pending or active hw interrupt goes in a list register, whose link to
+/// the physical interrupt needs that one active, and not pending again.
 pub fn vgic_int_state_hw(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) {
+    let active = intr.pend || intr.active;
     if gic_is_priv(intr.id) {
-        gicr_set_act(intr.id, intr.active, intr.redist);
-        gicr_set_pend(intr.id, intr.pend, intr.redist);
+        gicr_set_act(intr.phys_id, active, intr.redist);
+        gicr_set_pend(intr.phys_id, false, intr.redist);
     } else {
-        gicd_set_act(intr.id, intr.active);
-        gicd_set_pend(intr.id, intr.pend);
+        gicd_set_act(intr.phys_id, active);
+        gicd_set_pend(intr.phys_id, false);
     }
     info!(
         "intr {} (hardware) set state: active = {}, pend = {}.",
@@ -530,9 +863,9 @@ pub fn vgic_int_set_cfg(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -
 
 pub fn vgic_int_set_cfg_hw(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) {
     if gic_is_priv(intr.id) {
-        gicr_set_cfg(intr.id, intr.cfg, intr.redist);
+        gicr_set_cfg(intr.phys_id, intr.cfg, intr.redist);
     } else {
-        gicd_set_cfg(intr.id, intr.cfg);
+        gicd_set_cfg(intr.phys_id, intr.cfg);
     }
     info!("intr {} (hardware) set cfg", intr.id);
 }
@@ -550,9 +883,9 @@ pub fn vgic_int_set_prio(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64)
 
 pub fn vgic_int_set_prio_hw(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) {
     if gic_is_priv(intr.id) {
-        gicr_set_prio(intr.id, intr.prio, intr.redist);
+        gicr_set_prio(intr.phys_id, intr.prio, intr.redist);
     } else {
-        gicd_set_prio(intr.id, intr.prio);
+        gicd_set_prio(intr.phys_id, intr.prio);
     }
     info!("intr {} (hardware) set prio", intr.id);
 }
@@ -569,62 +902,309 @@ pub fn vgic_int_set_route(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64)
     if gic_is_priv(intr.id) {
         return false;
     }
-    intr.route = data;
-    intr.phys_route = PLATFORM.cpu_id_to_mpidr(myvm().get_vcpu(data & 0xff).phys_id);
+    intr.route = data & (GICD_IROUTER_AFF_MSK | GICD_IROUTER_IRM_BIT);
     debug!("intr {} set route -> {:#x?}.", intr.id, intr.route);
+
+    let vm = myvm();
+    let phys_route = if intr.route & GICD_IROUTER_IRM_BIT != 0 {
+        // Physical 1-of-N only stays inside the vm if it has every cpu,
+        // otherwise the cpu setting it up takes it.
+        if vm.cpu_num == PLATFORM.cpu_num {
+            GICD_IROUTER_IRM_BIT
+        } else {
+            gic_cpu_route(mycpu().id)
+        }
+    } else if let Some(vcpu) = vgic_route_vcpu(vm, intr.route) {
+        gic_cpu_route(vcpu.phys_id)
+    } else {
+        // No such vcpu, so it is never delivered
+        return false;
+    };
+    intr.phys_route = phys_route;
     true
 }
 
+/// The vcpu a shared interrupt is routed to, `None` for 1-of-N routing.
+/// GICv2 routes are vcpu masks, the first vcpu in one takes the interrupt.
+fn vgic_route_vcpu(vm: &VM, route: u64) -> Option<&mut VCpu> {
+    if let GicVersion::GicVersion2 = gic_version() {
+        let targets = route & bit64_mask(0, vm.cpu_num as _);
+        return (targets != 0).then(|| vm.get_vcpu_mut(targets.trailing_zeros() as _));
+    }
+    if route & GICD_IROUTER_IRM_BIT != 0 {
+        return None;
+    }
+    (0..vm.cpu_num as VCpuID)
+        .map(|i| vm.get_vcpu_mut(i))
+        .find(|vcpu| vcpu.arch.vmpidr & GICD_IROUTER_AFF_MSK == route & GICD_IROUTER_AFF_MSK)
+}
+
 pub fn vgic_int_set_route_hw(_vcpu: *mut VCpu, intr: &mut VGicIntrInner) {
     if gic_is_priv(intr.id) {
         panic!("gicr: cannot set route");
     } else {
-        gicd_set_route(intr.id, intr.redist);
+        gicd_set_route(intr.phys_id, intr.phys_route);
     }
     info!("intr {} (hardware) set route", intr.id);
 }
 
 // --------------------------------------------------
 
-pub fn vgic_write_lr(_vcpu: &'static mut VCpu, intr: &mut VGicIntrInner, lr_ind: u64) {
+pub fn vgic_write_lr(_vcpu: &VCpu, intr: &mut VGicIntrInner, lr_ind: u64) {
+    let lr = vgic_lr(intr);
+    // debug!("gich_write_lr({}) -> {:#x?}", lr_ind, lr);
+    gich_write_lr(lr_ind as _, lr);
+}
+
+/// The list register loading `intr`, in the GICv3 layout.
+fn vgic_lr(intr: &VGicIntrInner) -> u64 {
     let mut lr = intr.id as u64  // vINTid
         | ((intr.prio as u64) << 48)
-        | GICH_LR_GRP_BIT;
+        | (intr.pend as u64) << 62 // LR_STATE
+        | (intr.active as u64) << 63;
+    if intr.group1 {
+        lr |= GICH_LR_GRP_BIT;
+    }
     if intr.is_hw() {
         lr |= GICH_LR_HW_BIT;
-        lr |= (intr.id as u64) << 32; // pINTid
-        lr |= (intr.pend as u64) << 62; // LR_STATE
+        lr |= (intr.phys_id as u64) << 32; // pINTid
+    } else {
+        // Nothing deactivates a virtual interrupt in hardware, so have the
+        // guest's EOI raise a maintenance interrupt.
+        lr |= GICH_LR_EOI_BIT;
+        if gic_version() == GicVersion::GicVersion2 && gic_is_sgi(intr.id) {
+            lr |= intr.sgi_src << 32; // CPUID
+        }
     }
-    // debug!("gich_write_lr({}) -> {:#x?}", lr_ind, lr);
-    gich_write_lr(lr_ind as _, lr);
+    lr
 }
 
-pub fn vgic_add_lr(vcpu: &'static mut VCpu, intr: &mut VGicIntrInner) -> bool {
-    if !intr.enabled || intr.in_lr {
+/// Loads `intr` in a list register of `vcpu`, which runs here. With none
+/// free, it takes the one of the lowest priority interrupt that is only
+/// pending, if its own priority is higher and not masked by the guest.
+/// Interrupts left out wait in `spilled`. Returns whether `intr` was loaded.
+/// Active ones are loaded even if disabled, so the guest can still retire
+/// them.
+pub fn vgic_add_lr(vcpu: &mut VCpu, intr: &mut VGicIntrInner) -> bool {
+    if !(intr.enabled || intr.active) || intr.in_lr {
         return false;
     }
 
-    let elrsr = read_reg!(ich_elrsr_el2);
-    let lr_ind = (0..16u64).find(|i| bit64_extract(elrsr, *i, 1) != 0);
+    let elrsr = gich_get_elrsr();
+    let mut lr_ind = (0..gich_num_lrs() as u64).find(|i| bit64_extract(elrsr, *i, 1) != 0);
+
+    if lr_ind.is_none() && intr.prio < gich_get_vpmr() {
+        let lrs = (0..gich_num_lrs()).map(|i| (i, gich_read_lr(i)));
+        if let Some((i, lr)) = vgic_lr_victim(lrs, intr.prio) {
+            vgic_spill_lr(vcpu, i, lr);
+            lr_ind = Some(i as _);
+        }
+    }
 
     if let Some(lr_ind) = lr_ind {
         vgic_write_lr(vcpu, intr, lr_ind);
+        intr.in_lr = true;
+        if !intr.is_hw() {
+            // The list register holds the state from now on
+            intr.pend = false;
+            intr.active = false;
+        }
         true
     } else {
-        todo!("vgic_add_lr: no empty lr found");
+        vgic_spill(vcpu, intr.id);
+        false
     }
 }
 
-pub fn vgic_inject_hw(vcpu: &'static mut VCpu, id: IrqID) {
-    let interrupt = vgic_get_int(id, vcpu.id).unwrap();
+/// Picks among `lrs`, as (index, value), the list register to give up for an
+/// interrupt of priority `prio`: the lowest priority one that is only
+/// pending, if lower than `prio`.
+fn vgic_lr_victim(lrs: impl Iterator<Item = (u32, u64)>, prio: u8) -> Option<(u32, u64)> {
+    let lr_prio = |lr| bit64_extract(lr, GICH_LR_PRIO_OFF, GICH_LR_PRIO_LEN) as u8;
+    lrs.filter(|(_, lr)| lr & GICH_LR_STATE_MSK == GICH_LR_STATE_PEND)
+        .max_by_key(|(_, lr)| lr_prio(*lr))
+        .filter(|(_, lr)| lr_prio(*lr) > prio)
+}
+
+/// Takes `intr` out of the list register of the vcpu running here, which
+/// owns it, moving the state the register held back to `intr`. A hw
+/// interrupt missing from the list registers was already retired by the
+/// guest.
+fn vgic_remove_lr(intr: &mut VGicIntrInner) {
+    if !intr.in_lr {
+        return;
+    }
+    intr.in_lr = false;
+    let lr = (0..gich_num_lrs()).map(|i| (i, gich_read_lr(i))).find(|(_, lr)| {
+        lr & GICH_LR_STATE_MSK != 0 && (lr & GICH_LR_VID_MSK) as IrqID == intr.id
+    });
+    let state = match lr {
+        Some((lr_ind, lr)) => {
+            gich_write_lr(lr_ind, 0);
+            lr & GICH_LR_STATE_MSK
+        }
+        None => 0,
+    };
+    intr.pend = state & GICH_LR_STATE_PEND != 0;
+    intr.active = state & GICH_LR_STATE_ACT != 0;
+}
+
+/// Takes the pending interrupt in list register `lr_ind` out of it, back to
+/// the vcpu's spilled ones.
+fn vgic_spill_lr(vcpu: &mut VCpu, lr_ind: u32, lr: u64) {
+    gich_write_lr(lr_ind, 0);
+    let id = (lr & GICH_LR_VID_MSK) as IrqID;
+    if let Some(interrupt) = vgic_get_vm_int(vcpu.vm, id, vcpu.id) {
+        let mut intr = interrupt.inner.write();
+        intr.in_lr = false;
+        intr.pend = true;
+    }
+    vgic_spill(vcpu, id);
+}
+
+fn vgic_spill(vcpu: &mut VCpu, id: IrqID) {
+    let spilled = &mut vcpu.arch.vgic_priv.spilled;
+    if !spilled.contains(&id) {
+        spilled.push(id);
+    }
+    // Refill once the list registers drain
+    gich_set_hcr(gich_get_hcr() | GICH_HCR_UIE_BIT);
+}
+
+/// Loads spilled interrupts in the free list registers, highest priority
+/// first. Those no longer pending or enabled are dropped, enabling them
+/// loads them again.
+fn vgic_refill_lrs(vcpu: &mut VCpu) {
+    loop {
+        let (vm, vcpu_id) = (vcpu.vm, vcpu.id);
+        let spilled = &mut vcpu.arch.vgic_priv.spilled;
+        let next = vgic_highest_prio(spilled.iter().enumerate().filter_map(|(i, id)| {
+            let intr = vgic_get_vm_int(vm, *id, vcpu_id)?;
+            Some((i, intr.inner.read().prio))
+        }));
+        let Some(i) = next else {
+            spilled.clear();
+            break;
+        };
+        let id = spilled.swap_remove(i);
+        let interrupt = vgic_get_vm_int(vcpu.vm, id, vcpu.id).unwrap();
+        let mut intr = interrupt.inner.write();
+        if !intr.pend || !intr.enabled || intr.in_lr {
+            continue;
+        }
+        if !vgic_add_lr(vcpu, &mut intr) {
+            // Put back in `spilled`, the list registers are full
+            break;
+        }
+    }
+    if vcpu.arch.vgic_priv.spilled.is_empty() {
+        gich_set_hcr(gich_get_hcr() & !GICH_HCR_UIE_BIT);
+    }
+}
+
+/// The index of the highest priority, i.e. lowest value, among `prios`.
+fn vgic_highest_prio(prios: impl Iterator<Item = (usize, u8)>) -> Option<usize> {
+    prios.min_by_key(|(_, prio)| *prio).map(|(i, _)| i)
+}
+
+/// Makes the virtual interrupt `id` pending for `vcpu`. Nothing in hardware
+/// backs it, so it is retired through the maintenance interrupt.
+pub fn vgic_inject(vcpu: &'static mut VCpu, id: IrqID) -> BaoResult<()> {
+    let interrupt = vgic_get_vm_int(vcpu.vm, id, vcpu.id).ok_or(BaoError::InvalidParam)?;
 
     let mut intr = interrupt.inner.write();
+    if intr.is_hw() {
+        return Err(BaoError::InvalidParam);
+    }
     intr.owner = Some(vcpu);
     intr.pend = true;
+    if vcpu.phys_id == mycpu().id {
+        vgic_add_lr(vcpu, &mut intr);
+    } else {
+        drop(intr);
+        vcpu.arch.vgic_priv.pend_queue.lock().push(id);
+        gicc_send_sgi(vcpu.phys_id, VGIC_IPI_ID);
+    }
+    Ok(())
+}
+
+/// Makes SGI `id` from vcpu `src` pending for `vcpu`. Only the latest source
+/// is kept if several send it before the guest takes it.
+pub fn vgic_inject_sgi(vcpu: &'static mut VCpu, id: IrqID, src: VCpuID) -> BaoResult<()> {
+    if !gic_is_sgi(id) {
+        return Err(BaoError::InvalidParam);
+    }
+    let interrupt = vgic_get_vm_int(vcpu.vm, id, vcpu.id).ok_or(BaoError::InvalidParam)?;
+    interrupt.inner.write().sgi_src = src;
+    vgic_inject(vcpu, id)
+}
+
+/// Makes the shared virtual interrupt `id` pending for the vcpu the guest
+/// routed it to.
+pub fn vgic_inject_vm(vm: &'static mut VM, id: IrqID) -> BaoResult<()> {
+    if gic_is_priv(id) {
+        return Err(BaoError::InvalidParam);
+    }
+    let interrupt = vgic_get_vm_int(vm, id, 0).ok_or(BaoError::InvalidParam)?;
+    let route = interrupt.inner.read().route;
+    let vcpu_id = match vgic_route_vcpu(vm, route) {
+        Some(vcpu) => vcpu.id,
+        None => 0,
+    };
+    vgic_inject(vm.get_vcpu_mut(vcpu_id), id)
+}
+
+/// Injects the hardware interrupt `phys_id` under the id the vm knows it by.
+/// Shared interrupts go to the vcpu the guest routed them to, which is asked
+/// to take them if it runs elsewhere, also when they belong to a vm other
+/// than the one running here. Returns false if no vm owns the interrupt.
+pub fn vgic_inject_hw(vcpu: &'static mut VCpu, phys_id: IrqID) -> bool {
+    if gic_is_lpi(phys_id) {
+        return vgic_inject_lpi(vcpu, phys_id);
+    }
+    let (vm, default) = if myvm().arch.vgicd.hw_virt_ids.contains_key(&phys_id) {
+        (myvm(), vcpu)
+    } else if let Some(vm) = vmm_intr_owner(phys_id).filter(|_| !gic_is_priv(phys_id)) {
+        // Delivered here while the physical route was moving
+        let vcpu = vm.get_vcpu_mut(0) as *mut VCpu;
+        (vm, unsafe { &mut *vcpu })
+    } else {
+        println!("vgic: interrupt {} not assigned to any vm", phys_id);
+        return false;
+    };
+    let id = vm.arch.vgicd.hw_virt_ids[&phys_id];
+    let interrupt = vgic_get_vm_int(vm, id, default.id).unwrap();
+
+    let mut intr = interrupt.inner.write();
+    let target = if gic_is_priv(id) {
+        default
+    } else {
+        vgic_route_vcpu(vm, intr.route).unwrap_or(default)
+    };
+    intr.owner = Some(target);
+    intr.pend = true;
     intr.in_lr = false;
-    if !vgic_add_lr(vcpu, &mut intr) {
-        panic!("vgic: failed to inject hw")
+    if target.phys_id != mycpu().id {
+        drop(intr);
+        target.arch.vgic_priv.pend_queue.lock().push(id);
+        gicc_send_sgi(target.phys_id, VGIC_IPI_ID);
+    } else {
+        vgic_add_lr(target, &mut intr);
     }
+    true
+}
+
+/// LPIs have no active state to hand over, so the vm gets a purely virtual
+/// one, retired through the maintenance interrupt like any other.
+fn vgic_inject_lpi(vcpu: &'static mut VCpu, phys_id: IrqID) -> bool {
+    let vm = myvm();
+    let Some(id) = vm.arch.vgicd.lpi_virt_ids.read().get(&phys_id).copied() else {
+        println!("vgic: lpi {} not mapped by vm {}", phys_id, vm.id);
+        return false;
+    };
+    let route = vm.arch.vgicd.lpi(id).unwrap().inner.read().route;
+    let target = vgic_route_vcpu(vm, route).unwrap_or(vcpu);
+    vgic_inject(target, id).is_ok()
 }
 
 // --------------- GICD_REG_GROUPS ------------------
@@ -634,22 +1214,32 @@ pub const GICD_REG_GROUP_CTLR: u64 = 0;
 pub const GICD_REG_INDEX_CTLR: u64 = 0x0;
 pub const GICD_REG_INDEX_TYPER: u64 = 0x4;
 pub const GICD_REG_INDEX_IIDR: u64 = 0x8;
+pub const GICD_REG_INDEX_SETSPI_NSR: u64 = 0x40;
+pub const GICD_REG_INDEX_CLRSPI_NSR: u64 = 0x48;
 
+pub const GICD_REG_IGROUPR_OFF: u64 = 0x80;
 pub const GICD_REG_ISENABLER_OFF: u64 = 0x100;
 pub const GICD_REG_ICENABLER_OFF: u64 = 0x180;
+pub const GICD_REG_ISPENDR_OFF: u64 = 0x200;
 pub const GICD_REG_ICPENDR_OFF: u64 = 0x280;
+pub const GICD_REG_ISACTIVER_OFF: u64 = 0x300;
 pub const GICD_REG_ICACTIVER_OFF: u64 = 0x380;
 pub const GICD_REG_IPRIORITYR_OFF: u64 = 0x400;
 pub const GICD_REG_ITARGETSR_OFF: u64 = 0x800;
 pub const GICD_REG_ICFGR_OFF: u64 = 0xc00;
 pub const GICD_REG_IROUTER_OFF: u64 = 0x6000;
+pub const GICD_REG_SGIR_OFF: u64 = 0xf00;
+pub const GICD_REG_ID_V2_OFF: u64 = 0xfd0;
 pub const GICD_REG_ID_OFF: u64 = 0xffd0;
+pub const GICD_REG_ID_SIZE: u64 = 0x30;
 
 // OTHER GROUPS
 pub const GICD_REG_GROUP_IGROUPR: u64 = 0x80 >> 7;
 pub const GICD_REG_GROUP_ISENABLER: u64 = 0x100 >> 7;
 pub const GICD_REG_GROUP_ICENABLER: u64 = 0x180 >> 7;
+pub const GICD_REG_GROUP_ISPENDR: u64 = 0x200 >> 7;
 pub const GICD_REG_GROUP_ICPENDR: u64 = 0x280 >> 7;
+pub const GICD_REG_GROUP_ISACTIVER: u64 = 0x300 >> 7;
 pub const GICD_REG_GROUP_ICACTIVER: u64 = 0x380 >> 7;
 pub const GICD_REG_GROUP_IPRIORITYR: u64 = 0x400 >> 7;
 pub const GICD_REG_GROUP_ITARGETSR: u64 = 0x800 >> 7;
@@ -0,0 +1,111 @@
+use crate::{
+    arch::aarch64::{armv8_a::vm::VGicDscr, defs::PAGE_SIZE},
+    baocore::{
+        emul::{EmulAccess, EmulMem},
+        mmu::sections::SEC_VM_ANY,
+        types::VCpuID,
+        vm::{myvcpu, myvm, VCpu, VM},
+    },
+    debug,
+    platform::PLATFORM,
+    util::{align_up, bit64_mask, num_pages},
+};
+
+use super::{
+    gic_cpu_route,
+    gic_defs::{
+        GICD_SGIR_FILTER_LIST, GICD_SGIR_FILTER_OTHERS, GICD_SGIR_FILTER_SELF, GICD_SGIR_ID_MSK,
+        GICD_SGIR_TGT_FILTER_MSK, GICD_SGIR_TGT_FILTER_OFF, GICD_SGIR_TGT_LIST_MSK,
+        GICD_SGIR_TGT_LIST_OFF, GIC_CPU_PRIV,
+    },
+    gic_is_priv, gicd_get_iidr,
+    gicd::GicdHw,
+    gicv2::GiccHw,
+    vgic::{vgic_inject_sgi, vgicd_emul_handler, VGicHandlerInfo, VGicIntr, VGicIntrInner},
+    GIC,
+};
+
+pub fn vgic_init(vm: &mut VM, vgic_dscrp: &VGicDscr) {
+    vm.arch.vgicd.int_num = unsafe { GIC.get().unwrap().max_irqs };
+    vm.arch.vgicd.typer = ((vm.arch.vgicd.int_num as u32 / 32 - 1) & 0b11111) // ITLN
+        | ((vm.cpu_num as u32 - 1) << 5); // CPU_NUM
+    vm.arch.vgicd.iidr = gicd_get_iidr();
+
+    for i in 0..vm.arch.vgicd.int_num {
+        let intr = VGicIntr::new((i + GIC_CPU_PRIV) as _, 0);
+        vm.arch.vgicd.interrupts.push(intr);
+    }
+
+    let vgicd_emul = EmulMem {
+        va_base: vgic_dscrp.gicd_addr,
+        size: align_up(core::mem::size_of::<GicdHw>(), PAGE_SIZE),
+        handler: vgicd_emul_handler,
+    };
+    vm.emul_add_mem(vgicd_emul);
+
+    // The guest drives its cpu interface itself, through the virtual one
+    vm.addr_space
+        .mem_alloc_map_dev(
+            SEC_VM_ANY,
+            PLATFORM.arch.gic.gicv_addr,
+            Some(vgic_dscrp.gicc_addr),
+            num_pages(core::mem::size_of::<GiccHw>()),
+        )
+        .unwrap();
+}
+
+pub fn vgic_int_get_target(vcpu: *mut VCpu, intr: &mut VGicIntrInner) -> u64 {
+    if gic_is_priv(intr.id) {
+        // Read only, each vcpu sees itself
+        return 1 << unsafe { (*vcpu).id };
+    }
+    debug!("get intr {} targets: {:#x?}.", intr.id, intr.route);
+    intr.route
+}
+
+pub fn vgic_int_set_target(_vcpu: *mut VCpu, intr: &mut VGicIntrInner, data: u64) -> bool {
+    if gic_is_priv(intr.id) {
+        return false;
+    }
+    let vm = myvm();
+    intr.route = data & bit64_mask(0, vm.cpu_num as _);
+    debug!("intr {} set targets -> {:#x?}.", intr.id, intr.route);
+
+    intr.phys_route = (0..vm.cpu_num as VCpuID)
+        .filter(|i| intr.route & (1 << i) != 0)
+        .fold(0, |mask, i| mask | gic_cpu_route(vm.get_vcpu_mut(i).phys_id));
+    true
+}
+
+/// Software generated interrupts between the vm's vcpus.
+pub fn vgicd_emul_sgir_access(
+    acc: &EmulAccess,
+    _handlers: &VGicHandlerInfo,
+    _gicr_access: bool,
+    _vgicr_id: VCpuID,
+) {
+    if !acc.write {
+        myvcpu().write_reg(acc.reg, 0);
+        return;
+    }
+
+    let sgir = myvcpu().read_reg(acc.reg) as u32;
+    let id = sgir & GICD_SGIR_ID_MSK;
+    let vm = myvm();
+    let src = myvcpu().id;
+    let targets = match (sgir & GICD_SGIR_TGT_FILTER_MSK) >> GICD_SGIR_TGT_FILTER_OFF {
+        GICD_SGIR_FILTER_LIST => {
+            ((sgir & GICD_SGIR_TGT_LIST_MSK) >> GICD_SGIR_TGT_LIST_OFF) as u64
+        }
+        GICD_SGIR_FILTER_OTHERS => !(1 << src),
+        GICD_SGIR_FILTER_SELF => 1 << src,
+        _ => 0,
+    } & bit64_mask(0, vm.cpu_num as _);
+    debug!("write gicd.sgir: sgi {} to {:#x?}", id, targets);
+
+    for vcpu_id in (0..vm.cpu_num as VCpuID).filter(|i| targets & (1 << i) != 0) {
+        if vgic_inject_sgi(vm.get_vcpu_mut(vcpu_id), id, src).is_err() {
+            debug!("gicd.sgir: failed to send sgi {} to vcpu {}", id, vcpu_id);
+        }
+    }
+}
@@ -5,12 +5,13 @@ use crate::{
         armv8_a::vm::VGicDscr,
         defs::PAGE_SIZE,
         gic::vgic::{
-            vgic_emul_generic_access, vgic_emul_razwi, vgic_int_clear_act, vgic_int_clear_enable,
-            vgic_int_clear_pend, vgic_int_enable_hw, vgic_int_get_act, vgic_int_get_enable,
-            vgic_int_get_pend, vgic_int_get_prio, vgic_int_set_enable, vgic_int_set_prio,
-            vgic_int_set_prio_hw, vgic_int_state_hw, VGicHandlerInfo, GICD_REG_ICACTIVER_OFF,
-            GICD_REG_ICENABLER_OFF, GICD_REG_ICPENDR_OFF, GICD_REG_IPRIORITYR_OFF,
-            GICD_REG_ISENABLER_OFF,
+            vgic_access_valid, vgic_emul_generic_access, vgic_emul_razwi, vgic_int_clear_act,
+            vgic_int_clear_enable, vgic_int_clear_pend, vgic_int_enable_hw, vgic_int_get_act,
+            vgic_int_get_enable, vgic_int_get_group, vgic_int_get_pend, vgic_int_get_prio, vgic_int_set_act,
+            vgic_int_set_enable, vgic_int_set_group, vgic_int_set_pend, vgic_int_set_prio, vgic_int_set_prio_hw,
+            vgic_int_state_hw, VGicHandlerInfo, GICD_REG_ICACTIVER_OFF, GICD_REG_ICENABLER_OFF,
+            GICD_REG_ICPENDR_OFF, GICD_REG_IGROUPR_OFF, GICD_REG_IPRIORITYR_OFF, GICD_REG_ISACTIVER_OFF,
+            GICD_REG_ISENABLER_OFF, GICD_REG_ISPENDR_OFF, VGIC_RAZWI_HANDLER_INFO,
         },
     },
     baocore::{
@@ -23,11 +24,11 @@ use crate::{
 };
 
 use super::{
-    gic_defs::GIC_CPU_PRIV,
+    gic_defs::{GICR_CTLR_ENABLE_LPIS_BIT, GICR_TYPER_PLPIS_BIT, GIC_CPU_PRIV},
     gicd::GicdHw,
-    gicd_get_iidr, gicr_get_pidr,
-    gicv3::GicrHw,
-    vgic::{vgicd_emul_handler, VGicIntr, GICD_REG_ICFGR_OFF, vgic_int_get_cfg, vgic_int_set_cfg, vgic_int_set_cfg_hw},
+    gicd_get_iidr,
+    gicv3::{gicr_get_pidr, GicrHw},
+    vgic::{vgic_reg64_access, vgicd_emul_handler, VGicIntr, GICD_REG_ICFGR_OFF, vgic_int_get_cfg, vgic_int_set_cfg, vgic_int_set_cfg_hw},
     GIC,
 };
 
@@ -49,6 +50,7 @@ pub fn vgic_init(vm: &mut VM, vgic_dscrp: &VGicDscr) {
     vm.arch.vgicd.int_num = unsafe { GIC.get().unwrap().max_irqs };
     vm.arch.vgicd.typer = ((vm.arch.vgicd.int_num as u32 / 32 - 1) & 0b11111) // ITLN
         | ((vm.cpu_num as u32 - 1) << 5)        // CPU_NUM
+        | (1 << 16)                             // MBIS
         | ((10 - 1) << 19); // TYPER_IDBITS
     vm.arch.vgicd.iidr = gicd_get_iidr();
 
@@ -79,6 +81,7 @@ pub fn vgic_init(vm: &mut VM, vgic_dscrp: &VGicDscr) {
         typer |= (vcpu.arch.vmpidr & 0xffff) << 32;
         typer |= ((vcpu.id as usize == myvm().cpu_num - 1) as u64) << 4;
         vcpu.arch.vgic_priv.vgicr.typer = typer;
+        vcpu.arch.vgic_priv.vgicr.iidr = gicd_get_iidr();
     }
 
     let vgicr_emul = EmulMem {
@@ -92,22 +95,48 @@ pub fn vgic_init(vm: &mut VM, vgic_dscrp: &VGicDscr) {
 fn vgicr_emul_handler(acc: &EmulAccess) -> bool {
     let gicr_reg = gicr_reg_mask(acc.addr);
     let handler_info = match gicr_reg {
-        GICR_REG_CTRL_OFF | GICR_REG_WAKER_OFF | GICR_REG_IGROUPR0_OFF => VGicHandlerInfo {
-            reg_access: vgic_emul_razwi,
+        GICR_REG_CTRL_OFF => VGicHandlerInfo {
+            reg_access: vgicr_emul_ctlr_access,
             regroup_base: 0,
             field_width: 0,
             read_field: None,
             update_field: None,
             update_hw: None,
         },
-        GICR_REG_TYPER_OFF => VGicHandlerInfo {
-            reg_access: vgicr_emul_typer_access,
+        GICR_REG_PROPBASER_OFF..=GICR_REG_PENDBASER_TOP_OFF => VGicHandlerInfo {
+            reg_access: vgicr_emul_baser_access,
+            regroup_base: 0,
+            field_width: 64,
+            read_field: None,
+            update_field: None,
+            update_hw: None,
+        },
+        GICR_REG_STATUSR_OFF | GICR_REG_WAKER_OFF | GICR_REG_SYNCR_OFF
+        | GICR_REG_IGRPMODR0_OFF | GICR_REG_NSACR_OFF => VGIC_RAZWI_HANDLER_INFO,
+        GICR_REG_IGROUPR0_OFF => VGicHandlerInfo {
+            reg_access: vgic_emul_generic_access,
+            regroup_base: GICD_REG_IGROUPR_OFF,
+            field_width: 1,
+            read_field: Some(vgic_int_get_group),
+            update_field: Some(vgic_int_set_group),
+            update_hw: None,
+        },
+        GICR_REG_IIDR_OFF => VGicHandlerInfo {
+            reg_access: vgicr_emul_iidr_access,
             regroup_base: 0,
             field_width: 0,
             read_field: None,
             update_field: None,
             update_hw: None,
         },
+        GICR_REG_TYPER_OFF | GICR_REG_TYPER_TOP_OFF => VGicHandlerInfo {
+            reg_access: vgicr_emul_typer_access,
+            regroup_base: 0,
+            field_width: 64,
+            read_field: None,
+            update_field: None,
+            update_hw: None,
+        },
         GICR_REG_ISENABLER0_OFF => VGicHandlerInfo {
             reg_access: vgic_emul_generic_access,
             regroup_base: GICD_REG_ISENABLER_OFF,
@@ -124,6 +153,14 @@ fn vgicr_emul_handler(acc: &EmulAccess) -> bool {
             update_field: Some(vgic_int_clear_enable),
             update_hw: Some(vgic_int_enable_hw),
         },
+        GICR_REG_ISPENDR0_OFF => VGicHandlerInfo {
+            reg_access: vgic_emul_generic_access,
+            regroup_base: GICD_REG_ISPENDR_OFF,
+            field_width: 1,
+            read_field: Some(vgic_int_get_pend),
+            update_field: Some(vgic_int_set_pend),
+            update_hw: Some(vgic_int_state_hw),
+        },
         GICR_REG_ICPENDR0_OFF => VGicHandlerInfo {
             reg_access: vgic_emul_generic_access,
             regroup_base: GICD_REG_ICPENDR_OFF,
@@ -132,6 +169,14 @@ fn vgicr_emul_handler(acc: &EmulAccess) -> bool {
             update_field: Some(vgic_int_clear_pend),
             update_hw: Some(vgic_int_state_hw),
         },
+        GICR_REG_ISACTIVER0_OFF => VGicHandlerInfo {
+            reg_access: vgic_emul_generic_access,
+            regroup_base: GICD_REG_ISACTIVER_OFF,
+            field_width: 1,
+            read_field: Some(vgic_int_get_act),
+            update_field: Some(vgic_int_set_act),
+            update_hw: Some(vgic_int_state_hw),
+        },
         GICR_REG_ICACTIVER0_OFF => VGicHandlerInfo {
             reg_access: vgic_emul_generic_access,
             regroup_base: GICD_REG_ICACTIVER_OFF,
@@ -168,13 +213,20 @@ fn vgicr_emul_handler(acc: &EmulAccess) -> bool {
                     update_hw: None,
                 }
             } else {
-                todo!("vgicr_emul_handler");
+                // The direct LPI registers read as zero too, LPIs are only
+                // handled through the ITS.
+                debug!("gicr: razwi access {:#x?}", acc.addr);
+                VGIC_RAZWI_HANDLER_INFO
             }
         }
     };
 
-    // todo: check alignment?
     let vgcir_id = vgicr_get_id(acc);
+    if !vgic_access_valid(acc, handler_info.field_width) {
+        debug!("gicr: bad access {:#x?} (width {})", acc.addr, acc.width);
+        vgic_emul_razwi(acc, &handler_info, true, vgcir_id);
+        return true;
+    }
     let vcpu = myvm().get_vcpu_mut(vgcir_id);
 
     let _gicr_mutex = vcpu.arch.vgic_priv.vgicr.lock.lock();
@@ -194,6 +246,56 @@ fn vgicr_emul_pidr_access(
     }
 }
 
+fn vgicr_emul_iidr_access(
+    acc: &EmulAccess,
+    _handlers: &VGicHandlerInfo,
+    _gicr_access: bool,
+    vgicr_id: VCpuID,
+) {
+    if !acc.write {
+        let iidr = myvm().get_vcpu_mut(vgicr_id).arch.vgic_priv.vgicr.iidr;
+        myvcpu().write_reg(acc.reg, iidr as _);
+        debug!("read gicr({}).iidr {:#x?}", vgicr_id, iidr);
+    }
+}
+
+fn vgicr_emul_ctlr_access(
+    acc: &EmulAccess,
+    _handlers: &VGicHandlerInfo,
+    _gicr_access: bool,
+    vgicr_id: VCpuID,
+) {
+    let vgicr = &mut myvm().get_vcpu_mut(vgicr_id).arch.vgic_priv.vgicr;
+    if acc.write {
+        // Once enabled, LPIs cannot be disabled again
+        if vgicr.typer & GICR_TYPER_PLPIS_BIT != 0 {
+            vgicr.ctlr |= myvcpu().read_reg(acc.reg) as u32 & GICR_CTLR_ENABLE_LPIS_BIT;
+        }
+        debug!("write gicr({}).ctlr {:#x?}", vgicr_id, vgicr.ctlr);
+    } else {
+        myvcpu().write_reg(acc.reg, vgicr.ctlr as _);
+    }
+}
+
+/// The LPI configuration and pending tables. Pending state is kept by the
+/// hypervisor, so only the configuration table is ever read.
+fn vgicr_emul_baser_access(
+    acc: &EmulAccess,
+    _handlers: &VGicHandlerInfo,
+    _gicr_access: bool,
+    vgicr_id: VCpuID,
+) {
+    let vgicr = &mut myvm().get_vcpu_mut(vgicr_id).arch.vgic_priv.vgicr;
+    let enabled = vgicr.ctlr & GICR_CTLR_ENABLE_LPIS_BIT != 0;
+    // Both are read only while LPIs are enabled
+    let wmask = if enabled { 0 } else { u64::MAX };
+    if gicr_reg_mask(acc.addr) < GICR_REG_PENDBASER_OFF {
+        vgic_reg64_access(acc, &mut vgicr.propbaser, wmask);
+    } else {
+        vgic_reg64_access(acc, &mut vgicr.pendbaser, wmask);
+    }
+}
+
 fn vgicr_emul_typer_access(
     acc: &EmulAccess,
     _handlers: &VGicHandlerInfo,
@@ -222,21 +324,35 @@ pub struct VGicR {
     pub typer: u64,
     pub ctlr: u32,
     pub iidr: u32,
+    pub propbaser: u64,
+    pub pendbaser: u64,
 }
 
-pub const VGIC_ENABLE_MASK: u32 = 0x2;
+/// EnableGrp0 and EnableGrp1, the vm sees a single security state
+pub const VGIC_ENABLE_MASK: u32 = 0x3;
 
 // ------------ GICR REGS ------------------
 
 const GICR_REG_CTRL_OFF: u64 = 0x0;
+const GICR_REG_IIDR_OFF: u64 = 0x4;
 const GICR_REG_TYPER_OFF: u64 = 0x8;
+const GICR_REG_TYPER_TOP_OFF: u64 = 0xc;
+const GICR_REG_STATUSR_OFF: u64 = 0x10;
 const GICR_REG_WAKER_OFF: u64 = 0x14;
+const GICR_REG_PROPBASER_OFF: u64 = 0x70;
+const GICR_REG_PENDBASER_OFF: u64 = 0x78;
+const GICR_REG_PENDBASER_TOP_OFF: u64 = 0x7c;
+const GICR_REG_SYNCR_OFF: u64 = 0xc0;
 const GICR_REG_IGROUPR0_OFF: u64 = 0x10080;
 const GICR_REG_ISENABLER0_OFF: u64 = 0x10100;
 const GICR_REG_ICENABLER0_OFF: u64 = 0x10180;
+const GICR_REG_ISPENDR0_OFF: u64 = 0x10200;
 const GICR_REG_ICPENDR0_OFF: u64 = 0x10280;
+const GICR_REG_ISACTIVER0_OFF: u64 = 0x10300;
 const GICR_REG_ICACTIVER0_OFF: u64 = 0x10380;
 const GICR_REG_ICFGR0_OFF: u64 = 0x10c00;
 const GICR_REG_ICFGR1_OFF: u64 = 0x10c04;
+const GICR_REG_IGRPMODR0_OFF: u64 = 0x10d00;
+const GICR_REG_NSACR_OFF: u64 = 0x10e00;
 const GICR_REG_IPRIORITYR_OFF: u64 = 0x10400;
 const GICR_REG_ID_OFF: u64 = 0x0ffd0;
@@ -0,0 +1,519 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+
+use crate::{
+    arch::aarch64::{armv8_a::vm::VGicDscr, defs::PAGE_SIZE},
+    baocore::{
+        cpu::mycpu,
+        emul::{EmulAccess, EmulMem},
+        mmu::guest::{copy_from_guest_ipa, copy_to_guest_ipa},
+        types::{IrqID, VCpuID, Vaddr},
+        vm::{myvcpu, myvm, VCpu, VMDeviceRegion, VM},
+    },
+    debug, println,
+    util::{bit64_extract, BaoError, BaoResult},
+};
+
+use super::{
+    gic_defs::{
+        GICD_IROUTER_AFF_MSK, GICD_TYPER_IDBITS_MSK, GICD_TYPER_IDBITS_OFF, GICD_TYPER_LPIS_BIT,
+        GICR_CTLR_ENABLE_LPIS_BIT, GICR_PROPBASER_IDBITS_MSK, GICR_PROPBASER_PA_MSK,
+        GICR_TYPER_PLPIS_BIT, GIC_FIRST_LPI, GIC_LPI_CFG_EN_BIT, GIC_LPI_CFG_PRIO_MSK,
+        GIC_LPI_ID_BITS,
+    },
+    gic_is_lpi, gicc_send_sgi,
+    its::{
+        its_clear_event, its_device_bits, its_event_bits, its_get_iidr, its_get_pidr,
+        its_map_device, its_map_event, its_move_event, its_present, its_set_lpi,
+        its_unmap_device, its_unmap_event, GITS_BASER_CACHE_MSK, GITS_BASER_ENTRY_SIZE_OFF,
+        GITS_BASER_PA_MSK, GITS_BASER_SHAREABILITY_MSK, GITS_BASER_SIZE_MSK,
+        GITS_BASER_TYPE_COLLECTIONS, GITS_BASER_TYPE_DEVICES, GITS_BASER_TYPE_OFF,
+        GITS_BASER_VALID_BIT, GITS_CBASER_PA_MSK, GITS_CQ_OFF_MSK, GITS_CTLR_EN_BIT,
+        GITS_CTLR_QUIESCENT_BIT, GITS_TRANSLATION_FRAME_OFF, GITS_TYPER_DEVBITS_OFF,
+        GITS_TYPER_IDBITS_OFF, GITS_TYPER_ITT_ENTRY_SIZE_OFF, GITS_TYPER_PHYS_BIT,
+        ITS_CMD_CLEAR, ITS_CMD_DISCARD, ITS_CMD_INT, ITS_CMD_INV, ITS_CMD_INVALL, ITS_CMD_ITT_MSK,
+        ITS_CMD_MAPC, ITS_CMD_MAPD, ITS_CMD_MAPI, ITS_CMD_MAPTI, ITS_CMD_MOVALL, ITS_CMD_MOVI,
+        ITS_CMD_RDBASE_OFF, ITS_CMD_SIZE, ITS_CMD_SYNC, ITS_CMD_V_BIT,
+    },
+    vgic::{vgic_add_lr, vgic_inject, vgic_reg64_access, VGicIntr, VGIC_IPI_ID},
+};
+
+// The device, collection and interrupt translation tables are the guest's,
+// with entries in this layout:
+//   DTE: V[63], ITT address[51:8], event id bits - 1[4:0]
+//   CTE: V[63], vcpu id[15:0]
+//   ITE: V[63], collection[47:32], vLPI[31:0]
+const VITS_ENTRY_SIZE: u64 = 8;
+const VITS_ENTRY_V_BIT: u64 = 1 << 63;
+const VITS_DTE_SIZE_MSK: u64 = 0x1f;
+const VITS_ITE_ICID_OFF: u64 = 32;
+
+const GITS_REG_CTLR_OFF: u64 = 0x0;
+const GITS_REG_IIDR_OFF: u64 = 0x4;
+const GITS_REG_TYPER_OFF: u64 = 0x8;
+const GITS_REG_CBASER_OFF: u64 = 0x80;
+const GITS_REG_CWRITER_OFF: u64 = 0x88;
+const GITS_REG_CREADR_OFF: u64 = 0x90;
+const GITS_REG_BASER_OFF: u64 = 0x100;
+const GITS_REG_ID_OFF: u64 = 0xffd0;
+const GITS_REG_TRANSLATER_OFF: u64 = 0x40;
+const GITS_CTRL_FRAME_SIZE: usize = 0x10000;
+
+const VITS_BASER_TYPES: [u64; 2] = [GITS_BASER_TYPE_DEVICES, GITS_BASER_TYPE_COLLECTIONS];
+const VITS_BASER_WMASK: u64 = GITS_BASER_VALID_BIT
+    | GITS_BASER_CACHE_MSK
+    | GITS_BASER_PA_MSK
+    | GITS_BASER_SHAREABILITY_MSK
+    | GITS_BASER_SIZE_MSK;
+const VITS_CBASER_WMASK: u64 = GITS_BASER_VALID_BIT
+    | GITS_BASER_CACHE_MSK
+    | GITS_CBASER_PA_MSK
+    | GITS_BASER_SHAREABILITY_MSK
+    | GITS_BASER_SIZE_MSK;
+
+pub struct VIts {
+    pub lock: Mutex<()>,
+    pub addr: Vaddr,
+    ctlr: u32,
+    cbaser: u64,
+    cwriter: u64,
+    creadr: u64,
+    baser: [u64; 2],
+    /// Device ids the vm may map, those of its devices' streams
+    devices: Vec<u32>,
+    /// The physical LPI each mapped event of the vm's devices raises
+    events: BTreeMap<(u32, u32), IrqID>,
+}
+
+impl VIts {
+    pub fn new() -> Self {
+        Self {
+            lock: Mutex::new(()),
+            addr: 0,
+            ctlr: 0,
+            cbaser: 0,
+            cwriter: 0,
+            creadr: 0,
+            baser: [0; 2],
+            devices: Vec::new(),
+            events: BTreeMap::new(),
+        }
+    }
+}
+
+pub fn vits_init(vm: &mut VM, vgic_dscrp: &VGicDscr, devs: &[VMDeviceRegion]) {
+    if !its_present() {
+        println!("vm {}: no physical its, lpis disabled", vm.id);
+        return;
+    }
+
+    vm.arch.vits.addr = vgic_dscrp.its_addr;
+    vm.arch.vits.devices = devs
+        .iter()
+        .flat_map(|dev| dev.stream_ids.iter().copied())
+        .collect();
+
+    vm.arch.vgicd.typer = (vm.arch.vgicd.typer & !GICD_TYPER_IDBITS_MSK)
+        | GICD_TYPER_LPIS_BIT
+        | ((GIC_LPI_ID_BITS as u32 - 1) << GICD_TYPER_IDBITS_OFF);
+    for vcpu_id in 0..vm.cpu_num {
+        vm.get_vcpu_mut(vcpu_id as _).arch.vgic_priv.vgicr.typer |= GICR_TYPER_PLPIS_BIT;
+    }
+
+    vm.emul_add_mem(EmulMem {
+        va_base: vgic_dscrp.its_addr,
+        size: GITS_CTRL_FRAME_SIZE,
+        handler: vits_emul_handler,
+    });
+
+    // The physical translation register would let the guest raise the LPIs
+    // of whatever device id the ITS assigns cpu writes, so it is not mapped.
+    // Devices with no smmu in the way still write their MSIs to the physical
+    // one, so their vm must place its its at the physical address.
+    vm.emul_add_mem(EmulMem {
+        va_base: vgic_dscrp.its_addr + GITS_TRANSLATION_FRAME_OFF,
+        size: GITS_CTRL_FRAME_SIZE,
+        handler: vits_translater_handler,
+    });
+}
+
+/// Cpu writes carry no device id, the vm's first device takes them.
+fn vits_translater_handler(acc: &EmulAccess) -> bool {
+    let vits = &myvm().arch.vits;
+    let reg = acc.addr - (vits.addr + GITS_TRANSLATION_FRAME_OFF);
+    if !acc.write {
+        myvcpu().write_reg(acc.reg, 0);
+        return true;
+    }
+    if reg != GITS_REG_TRANSLATER_OFF || acc.width != 4 {
+        debug!("gits: razwi access {:#x?}", acc.addr);
+        return true;
+    }
+    let event = myvcpu().read_reg(acc.reg) as u32;
+    let _vits_mutex = vits.lock.lock();
+    let Some(&dev) = vits.devices.first() else {
+        return true;
+    };
+    let injected = vits_translate(dev, event).and_then(|(vlpi, vcpu)| vgic_inject(vcpu, vlpi));
+    if injected.is_err() {
+        debug!("gits: event {} of device {:#x} not mapped", event, dev);
+    }
+    true
+}
+
+fn vits_emul_handler(acc: &EmulAccess) -> bool {
+    let vits = &mut myvm().arch.vits;
+    let reg = acc.addr - vits.addr;
+    let reg64 = reg & !0x7;
+    if acc.addr % acc.width != 0 || (acc.width != 4 && (acc.width != 8 || reg < 0x8)) {
+        debug!("gits: bad access {:#x?} (width {})", acc.addr, acc.width);
+        if !acc.write {
+            myvcpu().write_reg(acc.reg, 0);
+        }
+        return true;
+    }
+
+    let _vits_mutex = vits.lock.lock();
+    match reg64 {
+        GITS_REG_CTLR_OFF if reg == GITS_REG_IIDR_OFF => {
+            if !acc.write {
+                myvcpu().write_reg(acc.reg, its_get_iidr() as _);
+            }
+        }
+        GITS_REG_CTLR_OFF => {
+            if acc.write {
+                vits.ctlr = myvcpu().read_reg(acc.reg) as u32 & GITS_CTLR_EN_BIT;
+                vits_process();
+            } else {
+                // Commands complete as they are issued, so it is always
+                // quiescent
+                myvcpu().write_reg(acc.reg, (vits.ctlr | GITS_CTLR_QUIESCENT_BIT) as _);
+            }
+        }
+        GITS_REG_TYPER_OFF => {
+            let mut typer = vits_typer();
+            vgic_reg64_access(acc, &mut typer, 0);
+        }
+        GITS_REG_CBASER_OFF => {
+            vgic_reg64_access(acc, &mut vits.cbaser, VITS_CBASER_WMASK);
+            if acc.write {
+                vits.creadr = 0;
+            }
+        }
+        GITS_REG_CWRITER_OFF => {
+            vgic_reg64_access(acc, &mut vits.cwriter, GITS_CQ_OFF_MSK);
+            if acc.write {
+                vits_process();
+            }
+        }
+        GITS_REG_CREADR_OFF => vgic_reg64_access(acc, &mut vits.creadr, 0),
+        _ if reg64 >= GITS_REG_BASER_OFF && reg64 < GITS_REG_BASER_OFF + 0x40 => {
+            let i = ((reg64 - GITS_REG_BASER_OFF) / 8) as usize;
+            if i < vits.baser.len() {
+                let ro = (VITS_BASER_TYPES[i] << GITS_BASER_TYPE_OFF)
+                    | ((VITS_ENTRY_SIZE - 1) << GITS_BASER_ENTRY_SIZE_OFF);
+                let mut baser = vits.baser[i] | ro;
+                vgic_reg64_access(acc, &mut baser, VITS_BASER_WMASK);
+                vits.baser[i] = baser & VITS_BASER_WMASK;
+            } else if !acc.write {
+                myvcpu().write_reg(acc.reg, 0);
+            }
+        }
+        _ if reg >= GITS_REG_ID_OFF => {
+            if !acc.write {
+                myvcpu().write_reg(acc.reg, its_get_pidr(reg) as _);
+            }
+        }
+        _ => {
+            debug!("gits: razwi access {:#x?}", acc.addr);
+            if !acc.write {
+                myvcpu().write_reg(acc.reg, 0);
+            }
+        }
+    }
+    true
+}
+
+/// A physical ITS with no hardware collections, targeting redistributors by
+/// processor number.
+fn vits_typer() -> u64 {
+    GITS_TYPER_PHYS_BIT
+        | ((VITS_ENTRY_SIZE - 1) << GITS_TYPER_ITT_ENTRY_SIZE_OFF)
+        | ((its_event_bits() as u64 - 1) << GITS_TYPER_IDBITS_OFF)
+        | ((its_device_bits() as u64 - 1) << GITS_TYPER_DEVBITS_OFF)
+}
+
+/// Runs the commands the guest queued, all of them complete on return.
+fn vits_process() {
+    let vits = &mut myvm().arch.vits;
+    if vits.ctlr & GITS_CTLR_EN_BIT == 0 || vits.cbaser & GITS_BASER_VALID_BIT == 0 {
+        return;
+    }
+    let qbase = vits.cbaser & GITS_CBASER_PA_MSK;
+    let qsize = ((vits.cbaser & GITS_BASER_SIZE_MSK) + 1) * PAGE_SIZE as u64;
+    while vits.creadr != vits.cwriter {
+        if vits.cwriter >= qsize {
+            debug!("gits: cwriter beyond the command queue");
+            break;
+        }
+        let mut bytes = [0u8; ITS_CMD_SIZE];
+        if copy_from_guest_ipa(&mut bytes, qbase + vits.creadr).is_err() {
+            debug!("gits: command queue not in guest memory");
+            vits.creadr = vits.cwriter;
+            break;
+        }
+        let mut cmd = [0u64; 4];
+        for (i, word) in cmd.iter_mut().enumerate() {
+            *word = u64::from_le_bytes(bytes[i * 8..(i + 1) * 8].try_into().unwrap());
+        }
+        if vits_cmd(&cmd).is_err() {
+            debug!("gits: command {:#x?} failed", cmd);
+        }
+        vits.creadr = (vits.creadr + ITS_CMD_SIZE as u64) % qsize;
+    }
+}
+
+fn vits_cmd(cmd: &[u64; 4]) -> BaoResult<()> {
+    let dev = bit64_extract(cmd[0], 32, 32) as u32;
+    let event = bit64_extract(cmd[1], 0, 32) as u32;
+    let icid = bit64_extract(cmd[2], 0, 16);
+    match cmd[0] & 0xff {
+        ITS_CMD_MAPD => {
+            let size = cmd[1] & VITS_DTE_SIZE_MSK;
+            let dte = (cmd[2] & (ITS_CMD_V_BIT | ITS_CMD_ITT_MSK)) | size;
+            vits_mapd(dev, dte)
+        }
+        ITS_CMD_MAPC => {
+            let vcpu_id = bit64_extract(cmd[2], ITS_CMD_RDBASE_OFF, 36);
+            if vcpu_id >= myvm().cpu_num as u64 {
+                return Err(BaoError::InvalidParam);
+            }
+            let cte = (cmd[2] & ITS_CMD_V_BIT) | vcpu_id;
+            vits_write_entry(1, icid, cte)
+        }
+        ITS_CMD_MAPTI => vits_mapti(dev, event, bit64_extract(cmd[1], 32, 32) as _, icid),
+        ITS_CMD_MAPI => vits_mapti(dev, event, event, icid),
+        ITS_CMD_INT => {
+            let (vlpi, vcpu) = vits_translate(dev, event)?;
+            vgic_inject(vcpu, vlpi)
+        }
+        ITS_CMD_CLEAR => {
+            let (vlpi, vcpu) = vits_translate(dev, event)?;
+            if let Some(interrupt) = myvm().arch.vgicd.lpi(vlpi) {
+                interrupt.inner.write().pend = false;
+            }
+            its_clear_event(dev, event, vcpu.phys_id);
+            Ok(())
+        }
+        ITS_CMD_DISCARD => {
+            vits_translate(dev, event)?;
+            vits_discard(dev, event)
+        }
+        ITS_CMD_MOVI => vits_movi(dev, event, icid),
+        ITS_CMD_INV => vits_inv(dev, event),
+        ITS_CMD_INVALL => {
+            let events: Vec<_> = myvm().arch.vits.events.keys().copied().collect();
+            for (dev, event) in events {
+                // Refreshing every event is harmless, whatever its collection
+                vits_inv(dev, event)?;
+            }
+            Ok(())
+        }
+        // Every command completes as it is issued, and vcpus do not move
+        ITS_CMD_SYNC | ITS_CMD_MOVALL => Ok(()),
+        _ => Err(BaoError::Unsupported),
+    }
+}
+
+/// The guest address of entry `index` of the table in `BASER<n>`.
+fn vits_table_entry(n: usize, index: u64) -> BaoResult<u64> {
+    let baser = myvm().arch.vits.baser[n];
+    let size = ((baser & GITS_BASER_SIZE_MSK) + 1) * PAGE_SIZE as u64;
+    if baser & GITS_BASER_VALID_BIT == 0 || (index + 1) * VITS_ENTRY_SIZE > size {
+        return Err(BaoError::InvalidParam);
+    }
+    Ok((baser & GITS_BASER_PA_MSK) + index * VITS_ENTRY_SIZE)
+}
+
+fn vits_read_at(addr: u64) -> BaoResult<u64> {
+    let mut bytes = [0u8; VITS_ENTRY_SIZE as usize];
+    copy_from_guest_ipa(&mut bytes, addr)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn vits_read_entry(n: usize, index: u64) -> BaoResult<u64> {
+    vits_read_at(vits_table_entry(n, index)?)
+}
+
+fn vits_write_entry(n: usize, index: u64, entry: u64) -> BaoResult<()> {
+    copy_to_guest_ipa(vits_table_entry(n, index)?, &entry.to_le_bytes())
+}
+
+/// The guest address of the ITE for `event` of `dev`. The DTE comes from
+/// guest memory, so `dev` must be one of the vm's devices.
+fn vits_ite_addr(dev: u32, event: u32) -> BaoResult<u64> {
+    if !myvm().arch.vits.devices.contains(&dev) {
+        return Err(BaoError::PermissionDenied);
+    }
+    let dte = vits_read_entry(0, dev as _)?;
+    if dte & VITS_ENTRY_V_BIT == 0 || event as u64 >= 1 << ((dte & VITS_DTE_SIZE_MSK) + 1) {
+        return Err(BaoError::NotFound);
+    }
+    Ok((dte & ITS_CMD_ITT_MSK) + event as u64 * VITS_ENTRY_SIZE)
+}
+
+fn vits_collection_vcpu(icid: u64) -> BaoResult<&'static mut VCpu> {
+    let cte = vits_read_entry(1, icid)?;
+    if cte & VITS_ENTRY_V_BIT == 0 {
+        return Err(BaoError::NotFound);
+    }
+    let vcpu_id = bit64_extract(cte, 0, 16) as VCpuID;
+    if vcpu_id >= myvm().cpu_num as _ {
+        return Err(BaoError::InvalidParam);
+    }
+    Ok(myvm().get_vcpu_mut(vcpu_id))
+}
+
+/// The vLPI `event` of `dev` raises and the vcpu it targets.
+fn vits_translate(dev: u32, event: u32) -> BaoResult<(IrqID, &'static mut VCpu)> {
+    let ite = vits_read_at(vits_ite_addr(dev, event)?)?;
+    if ite & VITS_ENTRY_V_BIT == 0 {
+        return Err(BaoError::NotFound);
+    }
+    let vcpu = vits_collection_vcpu(bit64_extract(ite, VITS_ITE_ICID_OFF, 16))?;
+    Ok((bit64_extract(ite, 0, 32) as _, vcpu))
+}
+
+fn vits_mapd(dev: u32, dte: u64) -> BaoResult<()> {
+    if !myvm().arch.vits.devices.contains(&dev) {
+        return Err(BaoError::PermissionDenied);
+    }
+    let event_bits = (dte & VITS_DTE_SIZE_MSK) as usize + 1;
+    if event_bits > its_event_bits() {
+        return Err(BaoError::InvalidParam);
+    }
+    let events: Vec<_> = myvm()
+        .arch
+        .vits
+        .events
+        .range((dev, 0)..=(dev, u32::MAX))
+        .map(|(&(_, event), _)| event)
+        .collect();
+    for event in events {
+        vits_discard(dev, event)?;
+    }
+    vits_write_entry(0, dev as _, dte)?;
+    its_unmap_device(dev);
+    if dte & VITS_ENTRY_V_BIT != 0 {
+        its_map_device(dev, event_bits)?;
+    }
+    Ok(())
+}
+
+fn vits_mapti(dev: u32, event: u32, vlpi: IrqID, icid: u64) -> BaoResult<()> {
+    if !gic_is_lpi(vlpi) || vlpi as usize >= 1 << GIC_LPI_ID_BITS {
+        return Err(BaoError::InvalidParam);
+    }
+    let ite_addr = vits_ite_addr(dev, event)?;
+    let vcpu = vits_collection_vcpu(icid)?;
+    if myvm().arch.vits.events.contains_key(&(dev, event)) {
+        vits_discard(dev, event)?;
+    }
+
+    let ite = VITS_ENTRY_V_BIT | (icid << VITS_ITE_ICID_OFF) | vlpi as u64;
+    copy_to_guest_ipa(ite_addr, &ite.to_le_bytes())?;
+
+    let plpi = its_map_event(dev, event, vcpu.phys_id)?;
+    let vgicd = &myvm().arch.vgicd;
+    vgicd.lpi_virt_ids.write().insert(plpi, vlpi);
+    myvm().arch.vits.events.insert((dev, event), plpi);
+    let interrupt = vgicd
+        .lpis
+        .write()
+        .entry(vlpi)
+        .or_insert_with(|| Box::new(VGicIntr::new(vlpi, 0)))
+        .as_mut() as *mut VGicIntr;
+    {
+        let mut intr = unsafe { (*interrupt).inner.write() };
+        intr.phys_id = plpi;
+        intr.owner = Some(vcpu);
+        intr.route = vcpu.arch.vmpidr & GICD_IROUTER_AFF_MSK;
+    }
+    vits_inv(dev, event)
+}
+
+fn vits_discard(dev: u32, event: u32) -> BaoResult<()> {
+    let Some(plpi) = myvm().arch.vits.events.remove(&(dev, event)) else {
+        return Err(BaoError::NotFound);
+    };
+    let vgicd = &myvm().arch.vgicd;
+    if let Some(vlpi) = vgicd.lpi_virt_ids.write().remove(&plpi) {
+        if let Some(interrupt) = vgicd.lpi(vlpi) {
+            let mut intr = interrupt.inner.write();
+            intr.enabled = false;
+            intr.pend = false;
+        }
+    }
+    its_unmap_event(dev, event, plpi, mycpu().id);
+    if let Ok(ite_addr) = vits_ite_addr(dev, event) {
+        copy_to_guest_ipa(ite_addr, &0u64.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn vits_movi(dev: u32, event: u32, icid: u64) -> BaoResult<()> {
+    let (vlpi, _) = vits_translate(dev, event)?;
+    let vcpu = vits_collection_vcpu(icid)?;
+    let ite = VITS_ENTRY_V_BIT | (icid << VITS_ITE_ICID_OFF) | vlpi as u64;
+    copy_to_guest_ipa(vits_ite_addr(dev, event)?, &ite.to_le_bytes())?;
+
+    if myvm().arch.vits.events.contains_key(&(dev, event)) {
+        its_move_event(dev, event, vcpu.phys_id);
+    }
+    if let Some(interrupt) = myvm().arch.vgicd.lpi(vlpi) {
+        let mut intr = interrupt.inner.write();
+        intr.route = vcpu.arch.vmpidr & GICD_IROUTER_AFF_MSK;
+        if !intr.in_lr {
+            intr.owner = Some(vcpu);
+        }
+    }
+    Ok(())
+}
+
+/// Loads the configuration of the vLPI `event` of `dev` raises from the
+/// guest's table, mirroring it on the physical LPI behind it.
+fn vits_inv(dev: u32, event: u32) -> BaoResult<()> {
+    let (vlpi, vcpu) = vits_translate(dev, event)?;
+    let Some(&plpi) = myvm().arch.vits.events.get(&(dev, event)) else {
+        return Err(BaoError::NotFound);
+    };
+    let interrupt = myvm().arch.vgicd.lpi(vlpi).ok_or(BaoError::NotFound)?;
+
+    let vgicr = &vcpu.arch.vgic_priv.vgicr;
+    let max_lpi = 1u64 << ((vgicr.propbaser & GICR_PROPBASER_IDBITS_MSK) + 1);
+    let cfg = if vgicr.ctlr & GICR_CTLR_ENABLE_LPIS_BIT != 0 && (vlpi as u64) < max_lpi {
+        let mut cfg = [0u8];
+        let prop = vgicr.propbaser & GICR_PROPBASER_PA_MSK;
+        copy_from_guest_ipa(&mut cfg, prop + (vlpi as usize - GIC_FIRST_LPI) as u64)?;
+        cfg[0]
+    } else {
+        0
+    };
+
+    let mut intr = interrupt.inner.write();
+    intr.enabled = cfg & GIC_LPI_CFG_EN_BIT != 0;
+    intr.prio = cfg & GIC_LPI_CFG_PRIO_MSK;
+    its_set_lpi(dev, event, plpi, vcpu.phys_id, intr.prio, intr.enabled);
+
+    // One that fired before the guest enabled it
+    if intr.enabled && intr.pend && !intr.in_lr {
+        if vcpu.phys_id == mycpu().id {
+            vgic_add_lr(vcpu, &mut intr);
+        } else {
+            drop(intr);
+            vcpu.arch.vgic_priv.pend_queue.lock().push(vlpi);
+            gicc_send_sgi(vcpu.phys_id, VGIC_IPI_ID);
+        }
+    }
+    Ok(())
+}
@@ -1,10 +1,26 @@
 use core::arch::asm;
 
-use crate::baocore::{types::IrqID, intr::{IntrHandleResult, interrupts_is_reserved}, vm::myvcpu};
+use crate::{
+    baocore::{
+        cpu::mycpu,
+        intr::{interrupts_hyp_handler, IntrHandleResult},
+        types::IrqID,
+        vm::myvcpu,
+    },
+};
 
-use super::{gic::{self, gic_defs::GIC_MAX_INTERUPTS}, armv8_a::vm::vcpu_arch_inject_hw_irq};
+use super::{
+    armv8_a::vm::vcpu_arch_inject_hw_irq,
+    gic::{
+        self,
+        gic_defs::{GIC_CPU_PRIV, GIC_HYP_PRIO, GIC_MAX_INTERUPTS},
+        gic_cpu_route, gic_is_priv, gicd_set_enable, gicd_set_prio, gicd_set_route,
+        gicr_set_enable, gicr_set_prio,
+    },
+};
 
 pub const MAX_INTERUPTS: usize = GIC_MAX_INTERUPTS;
+pub const CPU_PRIV_INTERUPTS: usize = GIC_CPU_PRIV;
 
 pub fn enable_irqs() {
     unsafe { asm!("msr daifclr, #0xf") };
@@ -14,12 +30,35 @@ pub fn disable_irqs() {
     unsafe { asm!("msr daifset, #0xf") };
 }
 
+pub fn interrupts_arch_is_priv(int_id: IrqID) -> bool {
+    gic_is_priv(int_id)
+}
+
+/// Enables or disables a hypervisor interrupt on the calling cpu. Shared
+/// interrupts are routed to it.
+pub fn interrupts_arch_enable(int_id: IrqID, en: bool) {
+    let cpu_id = mycpu().id;
+    if gic_is_priv(int_id) {
+        gicr_set_prio(int_id, GIC_HYP_PRIO, cpu_id);
+        gicr_set_enable(int_id, en, cpu_id);
+    } else {
+        gicd_set_prio(int_id, GIC_HYP_PRIO);
+        gicd_set_route(int_id, gic_cpu_route(cpu_id));
+        gicd_set_enable(int_id, en);
+    }
+}
+
 pub fn interrupts_handle(int_id: IrqID) -> IntrHandleResult {
-    if interrupts_is_reserved(int_id) {
-        todo!("handle reserved intr {}", int_id);
+    if let Some(handler) = interrupts_hyp_handler(int_id) {
+        handler(int_id);
+        return IntrHandleResult::HandledByHyp;
+    }
+    if vcpu_arch_inject_hw_irq(myvcpu(), int_id) {
+        IntrHandleResult::ForwardToVM
+    } else {
+        // Nobody here can take it, deactivate it rather than leave it stuck
+        IntrHandleResult::HandledByHyp
     }
-    vcpu_arch_inject_hw_irq(myvcpu(), int_id);
-    IntrHandleResult::ForwardToVM
 }
 
 pub fn interrupts_arch_init() {
@@ -7,6 +7,7 @@ pub mod platform;
 pub mod vm;
 pub mod vmm;
 pub mod psci;
+pub mod smmuv3;
 
 #[macro_use]
 pub mod sysregs;
@@ -21,4 +22,6 @@ global_asm!(include_str!("boot.S"),
 
 global_asm!(include_str!("exceptions.S"),
     CPU_SIZE = const crate::baocore::cpu::CPU_SIZE,
+    BAO_CPU_BASE = const defs::BAO_CPU_BASE,
+    CPU_EMERGENCY_STACK_TOP_OFF = const crate::baocore::cpu::CPU_EMERGENCY_STACK_TOP_OFF,
 );
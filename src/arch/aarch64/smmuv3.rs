@@ -0,0 +1,547 @@
+#![allow(non_snake_case)]
+
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::{Mutex, Once};
+
+use crate::{
+    baocore::{
+        cpu::mycpu,
+        intr::{interrupts_cpu_enable, interrupts_reserve},
+        mem::mem_alloc_page,
+        mmu::sections::SEC_HYP_GLOBAL,
+        types::{IrqID, MemOwner, Paddr, StreamID, Vaddr},
+    },
+    platform::PLATFORM,
+    println, read_reg,
+    util::{bit64_extract, num_pages, BaoError, BaoResult},
+};
+
+use super::{armv8_a::fences::fence_sync, defs::PAGE_SIZE};
+
+pub const SMMUV3_IDR0_S2P_BIT: u32 = 1 << 0;
+pub const SMMUV3_IDR0_COHACC_BIT: u32 = 1 << 4;
+pub const SMMUV3_IDR0_BTM_BIT: u32 = 1 << 5;
+pub const SMMUV3_IDR0_ST_LEVEL_OFF: u64 = 27;
+pub const SMMUV3_IDR0_ST_LEVEL_LEN: u64 = 2;
+pub const SMMUV3_IDR1_SIDSIZE_OFF: u64 = 0;
+pub const SMMUV3_IDR1_SIDSIZE_LEN: u64 = 6;
+pub const SMMUV3_IDR1_EVENTQS_OFF: u64 = 16;
+pub const SMMUV3_IDR1_EVENTQS_LEN: u64 = 5;
+pub const SMMUV3_IDR1_CMDQS_OFF: u64 = 21;
+pub const SMMUV3_IDR1_CMDQS_LEN: u64 = 5;
+
+pub const SMMUV3_CR0_SMMUEN_BIT: u32 = 1 << 0;
+pub const SMMUV3_CR0_EVENTQEN_BIT: u32 = 1 << 2;
+pub const SMMUV3_CR0_CMDQEN_BIT: u32 = 1 << 3;
+/// Tables and queues are inner shareable, inner/outer write-back
+pub const SMMUV3_CR1_DFLT: u32 = (3 << 10) | (1 << 8) | (1 << 6) | (3 << 4) | (1 << 2) | 1;
+pub const SMMUV3_CR2_RECINVSID_BIT: u32 = 1 << 1;
+pub const SMMUV3_CR2_PTM_BIT: u32 = 1 << 2;
+pub const SMMUV3_GBPA_UPDATE_BIT: u32 = 1 << 31;
+pub const SMMUV3_GBPA_ABORT_BIT: u32 = 1 << 20;
+pub const SMMUV3_IRQ_CTRL_GERROR_BIT: u32 = 1 << 0;
+pub const SMMUV3_IRQ_CTRL_EVENTQ_BIT: u32 = 1 << 2;
+pub const SMMUV3_GERROR_CMDQ_ERR_BIT: u32 = 1 << 0;
+
+pub const SMMUV3_BASE_RWA_BIT: u64 = 1 << 62;
+pub const SMMUV3_BASE_ADDR_MSK: u64 = ((1 << 52) - 1) & !0x1f;
+pub const SMMUV3_STRTAB_FMT_2LVL: u32 = 1 << 16;
+pub const SMMUV3_STRTAB_SPLIT_OFF: u32 = 6;
+pub const SMMUV3_Q_CONS_ERR_OFF: u64 = 24;
+pub const SMMUV3_Q_CONS_ERR_LEN: u64 = 7;
+pub const SMMUV3_Q_PROD_OVFLG_BIT: u32 = 1 << 31;
+pub const SMMUV3_Q_CONS_OVACKFLG_BIT: u32 = 1 << 31;
+
+/// Stream ids are split in 256 entry second level tables
+pub const SMMUV3_STRTAB_SPLIT: usize = 8;
+/// Largest stream id width handled, bounding the first level table to a page
+pub const SMMUV3_MAX_SID_BITS: usize = 16;
+/// Same for smmus without two level stream tables, which need a linear one
+pub const SMMUV3_MAX_LINEAR_SID_BITS: usize = 12;
+pub const SMMUV3_MAX_CMDQ_BITS: u32 = 8;
+pub const SMMUV3_MAX_EVENTQ_BITS: u32 = 7;
+
+pub const SMMUV3_STE_SIZE: usize = 64;
+pub const SMMUV3_STE_V_BIT: u64 = 1 << 0;
+pub const SMMUV3_STE_CFG_ABORT: u64 = 0b000 << 1;
+pub const SMMUV3_STE_CFG_S2: u64 = 0b110 << 1;
+pub const SMMUV3_STE_CFG_MSK: u64 = 0b111 << 1;
+pub const SMMUV3_STE_SHCFG_INCOMING: u64 = 1 << 44;
+/// The stage 2 translation fields of the STE match VTCR_EL2's layout
+pub const SMMUV3_STE_S2_VTCR_OFF: u64 = 32;
+pub const SMMUV3_STE_S2_VTCR_MSK: u64 = 0x7ffff;
+pub const SMMUV3_STE_S2AA64_BIT: u64 = 1 << 51;
+pub const SMMUV3_STE_S2R_BIT: u64 = 1 << 58;
+pub const SMMUV3_STE_S2TTB_MSK: u64 = ((1 << 52) - 1) & !0xf;
+pub const SMMUV3_L1STD_SPAN: u64 = SMMUV3_STRTAB_SPLIT as u64 + 1;
+
+pub const SMMUV3_CMD_CFGI_STE: u64 = 0x03;
+pub const SMMUV3_CMD_CFGI_ALL: u64 = 0x04;
+pub const SMMUV3_CMD_TLBI_EL2_ALL: u64 = 0x20;
+pub const SMMUV3_CMD_TLBI_S12_VMALL: u64 = 0x28;
+pub const SMMUV3_CMD_TLBI_NSNH_ALL: u64 = 0x30;
+pub const SMMUV3_CMD_SYNC: u64 = 0x46;
+pub const SMMUV3_CMD_SID_OFF: u64 = 32;
+pub const SMMUV3_CMD_VMID_OFF: u64 = 32;
+pub const SMMUV3_CMD_CFGI_LEAF_BIT: u64 = 1 << 0;
+pub const SMMUV3_CMD_CFGI_RANGE_ALL: u64 = 31;
+pub const SMMUV3_CMD_SIZE: usize = 16;
+pub const SMMUV3_EVT_SIZE: usize = 32;
+
+#[repr(C)]
+pub struct SmmuV3Hw {
+    pub IDR0: u32,            // 0x0
+    pub IDR1: u32,            // 0x4
+    pub IDR2: u32,            // 0x8
+    pub IDR3: u32,            // 0xc
+    pub IDR4: u32,            // 0x10
+    pub IDR5: u32,            // 0x14
+    pub IIDR: u32,            // 0x18
+    pub AIDR: u32,            // 0x1c
+    pub CR0: u32,             // 0x20
+    pub CR0ACK: u32,          // 0x24
+    pub CR1: u32,             // 0x28
+    pub CR2: u32,             // 0x2c
+    pub pad0: [u8; 0x40 - 0x30],
+    pub STATUSR: u32,         // 0x40
+    pub GBPA: u32,            // 0x44
+    pub AGBPA: u32,           // 0x48
+    pub pad1: [u8; 0x50 - 0x4c],
+    pub IRQ_CTRL: u32,        // 0x50
+    pub IRQ_CTRLACK: u32,     // 0x54
+    pub pad2: [u8; 0x60 - 0x58],
+    pub GERROR: u32,          // 0x60
+    pub GERRORN: u32,         // 0x64
+    pub GERROR_IRQ_CFG0: u64, // 0x68
+    pub GERROR_IRQ_CFG1: u32, // 0x70
+    pub GERROR_IRQ_CFG2: u32, // 0x74
+    pub pad3: [u8; 0x80 - 0x78],
+    pub STRTAB_BASE: u64,     // 0x80
+    pub STRTAB_BASE_CFG: u32, // 0x88
+    pub pad4: [u8; 0x90 - 0x8c],
+    pub CMDQ_BASE: u64,       // 0x90
+    pub CMDQ_PROD: u32,       // 0x98
+    pub CMDQ_CONS: u32,       // 0x9c
+    pub EVENTQ_BASE: u64,     // 0xa0
+    pub pad5: [u8; 0x100a8 - 0xa8],
+    pub EVENTQ_PROD: u32,     // 0x100a8
+    pub EVENTQ_CONS: u32,     // 0x100ac
+    pub pad6: [u8; 0x20000 - 0x100b0],
+}
+
+fn rd<T: Copy>(reg: &T) -> T {
+    unsafe { read_volatile(reg) }
+}
+
+fn wr<T>(reg: &mut T, val: T) {
+    unsafe { write_volatile(reg, val) }
+}
+
+/// Allocates physically contiguous, naturally aligned and zeroed memory for
+/// the smmu, returning its hypervisor va and pa.
+fn smmu_alloc(size: usize) -> (Vaddr, Paddr) {
+    let n = num_pages(size).next_power_of_two();
+    let va = mem_alloc_page(n, SEC_HYP_GLOBAL, true, MemOwner::HypData).unwrap();
+    unsafe { core::ptr::write_bytes(va as *mut u8, 0, n * PAGE_SIZE) };
+    let pa = mycpu().addr_space.mem_translate(va).unwrap();
+    (va, pa)
+}
+
+struct SmmuQueue {
+    base: Vaddr,
+    base_pa: Paddr,
+    log2size: u32,
+    entry_size: usize,
+}
+
+impl SmmuQueue {
+    fn new(log2size: u32, entry_size: usize) -> Self {
+        let (base, base_pa) = smmu_alloc((1 << log2size) * entry_size);
+        Self {
+            base,
+            base_pa,
+            log2size,
+            entry_size,
+        }
+    }
+
+    fn base_reg(&self) -> u64 {
+        SMMUV3_BASE_RWA_BIT | (self.base_pa & SMMUV3_BASE_ADDR_MSK) | self.log2size as u64
+    }
+
+    /// Index plus wrap bit
+    fn ptr(&self, reg: u32) -> u32 {
+        reg & ((2 << self.log2size) - 1)
+    }
+
+    fn inc(&self, reg: u32) -> u32 {
+        self.ptr(reg + 1)
+    }
+
+    fn is_full(&self, prod: u32, cons: u32) -> bool {
+        let (prod, cons) = (self.ptr(prod), self.ptr(cons));
+        prod != cons && (prod ^ cons) == (1 << self.log2size)
+    }
+
+    fn entry(&self, reg: u32) -> *mut u64 {
+        let index = reg as usize & ((1 << self.log2size) - 1);
+        (self.base as usize + index * self.entry_size) as *mut u64
+    }
+}
+
+enum StreamTable {
+    Linear {
+        base: Vaddr,
+        sid_bits: usize,
+    },
+    TwoLevel {
+        l1: Vaddr,
+        sid_bits: usize,
+        /// Hypervisor va of each second level table, if allocated
+        l2: Vec<Option<Vaddr>>,
+    },
+}
+
+pub struct SmmuV3 {
+    hw: Vaddr,
+    strtab: StreamTable,
+    cmdq: SmmuQueue,
+    eventq: SmmuQueue,
+    /// Broadcast TLB maintenance reaches the smmu, no explicit
+    /// invalidation is needed when the shared stage 2 tables change
+    btm: bool,
+}
+
+pub static SMMU: Once<Mutex<SmmuV3>> = Once::new();
+
+impl SmmuV3 {
+    fn hw(&self) -> &'static mut SmmuV3Hw {
+        unsafe { &mut *(self.hw as *mut SmmuV3Hw) }
+    }
+
+    fn new(hw_va: Vaddr) -> Option<Self> {
+        let hw = unsafe { &mut *(hw_va as *mut SmmuV3Hw) };
+        let idr0 = rd(&hw.IDR0);
+        let idr1 = rd(&hw.IDR1) as u64;
+
+        if idr0 & SMMUV3_IDR0_S2P_BIT == 0 {
+            println!("smmuv3: stage 2 translation not supported");
+            return None;
+        }
+        if idr0 & SMMUV3_IDR0_COHACC_BIT == 0 {
+            println!("smmuv3: non-coherent smmus are not supported");
+            return None;
+        }
+
+        let sidsize = bit64_extract(idr1, SMMUV3_IDR1_SIDSIZE_OFF, SMMUV3_IDR1_SIDSIZE_LEN);
+        let two_lvl = bit64_extract(idr0 as _, SMMUV3_IDR0_ST_LEVEL_OFF, SMMUV3_IDR0_ST_LEVEL_LEN);
+        let strtab = if two_lvl == 1 && sidsize as usize > SMMUV3_STRTAB_SPLIT {
+            let sid_bits = (sidsize as usize).min(SMMUV3_MAX_SID_BITS);
+            let l1_num = 1 << (sid_bits - SMMUV3_STRTAB_SPLIT);
+            StreamTable::TwoLevel {
+                l1: smmu_alloc(l1_num * 8).0,
+                sid_bits,
+                l2: alloc::vec![None; l1_num],
+            }
+        } else {
+            let sid_bits = (sidsize as usize).min(SMMUV3_MAX_LINEAR_SID_BITS);
+            let base = smmu_alloc((1 << sid_bits) * SMMUV3_STE_SIZE).0;
+            for sid in 0..1 << sid_bits {
+                ste_init_abort((base as usize + sid * SMMUV3_STE_SIZE) as *mut u64);
+            }
+            StreamTable::Linear { base, sid_bits }
+        };
+
+        let cmdq_bits = (bit64_extract(idr1, SMMUV3_IDR1_CMDQS_OFF, SMMUV3_IDR1_CMDQS_LEN) as u32)
+            .min(SMMUV3_MAX_CMDQ_BITS);
+        let eventq_bits = (bit64_extract(idr1, SMMUV3_IDR1_EVENTQS_OFF, SMMUV3_IDR1_EVENTQS_LEN)
+            as u32)
+            .min(SMMUV3_MAX_EVENTQ_BITS);
+
+        Some(Self {
+            hw: hw_va,
+            strtab,
+            cmdq: SmmuQueue::new(cmdq_bits, SMMUV3_CMD_SIZE),
+            eventq: SmmuQueue::new(eventq_bits, SMMUV3_EVT_SIZE),
+            btm: idr0 & SMMUV3_IDR0_BTM_BIT != 0,
+        })
+    }
+
+    fn write_cr0(&self, val: u32) {
+        let hw = self.hw();
+        wr(&mut hw.CR0, val);
+        while rd(&hw.CR0ACK) != val {}
+    }
+
+    fn enable(&mut self) {
+        let hw = self.hw();
+        // Abort incoming transactions while the smmu is being set up
+        wr(&mut hw.GBPA, SMMUV3_GBPA_UPDATE_BIT | SMMUV3_GBPA_ABORT_BIT);
+        while rd(&hw.GBPA) & SMMUV3_GBPA_UPDATE_BIT != 0 {}
+        self.write_cr0(0);
+
+        wr(&mut hw.CR1, SMMUV3_CR1_DFLT);
+        let ptm = if self.btm { 0 } else { SMMUV3_CR2_PTM_BIT };
+        wr(&mut hw.CR2, SMMUV3_CR2_RECINVSID_BIT | ptm);
+
+        let (strtab_pa, strtab_cfg) = match &self.strtab {
+            StreamTable::Linear { base, sid_bits } => (
+                mycpu().addr_space.mem_translate(*base).unwrap(),
+                *sid_bits as u32,
+            ),
+            StreamTable::TwoLevel { l1, sid_bits, .. } => (
+                mycpu().addr_space.mem_translate(*l1).unwrap(),
+                SMMUV3_STRTAB_FMT_2LVL
+                    | ((SMMUV3_STRTAB_SPLIT as u32) << SMMUV3_STRTAB_SPLIT_OFF)
+                    | *sid_bits as u32,
+            ),
+        };
+        wr(
+            &mut hw.STRTAB_BASE,
+            SMMUV3_BASE_RWA_BIT | (strtab_pa & SMMUV3_BASE_ADDR_MSK),
+        );
+        wr(&mut hw.STRTAB_BASE_CFG, strtab_cfg);
+
+        wr(&mut hw.CMDQ_BASE, self.cmdq.base_reg());
+        wr(&mut hw.CMDQ_PROD, 0);
+        wr(&mut hw.CMDQ_CONS, 0);
+        wr(&mut hw.EVENTQ_BASE, self.eventq.base_reg());
+        wr(&mut hw.EVENTQ_PROD, 0);
+        wr(&mut hw.EVENTQ_CONS, 0);
+        fence_sync();
+
+        self.write_cr0(SMMUV3_CR0_CMDQEN_BIT);
+        self.cmd([SMMUV3_CMD_CFGI_ALL, SMMUV3_CMD_CFGI_RANGE_ALL]);
+        self.cmd([SMMUV3_CMD_TLBI_EL2_ALL, 0]);
+        self.cmd([SMMUV3_CMD_TLBI_NSNH_ALL, 0]);
+        self.cmd_sync();
+
+        self.write_cr0(SMMUV3_CR0_CMDQEN_BIT | SMMUV3_CR0_EVENTQEN_BIT);
+        let irqs = SMMUV3_IRQ_CTRL_GERROR_BIT | SMMUV3_IRQ_CTRL_EVENTQ_BIT;
+        wr(&mut hw.IRQ_CTRL, irqs);
+        while rd(&hw.IRQ_CTRLACK) != irqs {}
+        self.write_cr0(SMMUV3_CR0_CMDQEN_BIT | SMMUV3_CR0_EVENTQEN_BIT | SMMUV3_CR0_SMMUEN_BIT);
+    }
+
+    fn cmd(&mut self, cmd: [u64; 2]) {
+        let hw = self.hw();
+        let prod = rd(&hw.CMDQ_PROD);
+        while self.cmdq.is_full(prod, rd(&hw.CMDQ_CONS)) {}
+        let entry = self.cmdq.entry(prod);
+        unsafe {
+            write_volatile(entry, cmd[0]);
+            write_volatile(entry.add(1), cmd[1]);
+        }
+        fence_sync();
+        wr(&mut hw.CMDQ_PROD, self.cmdq.inc(prod));
+    }
+
+    /// Waits for every command issued so far to complete.
+    fn cmd_sync(&mut self) {
+        self.cmd([SMMUV3_CMD_SYNC, 0]);
+        let hw = self.hw();
+        let prod = self.cmdq.ptr(rd(&hw.CMDQ_PROD));
+        loop {
+            if rd(&hw.GERROR) & SMMUV3_GERROR_CMDQ_ERR_BIT
+                != rd(&hw.GERRORN) & SMMUV3_GERROR_CMDQ_ERR_BIT
+            {
+                let cons = rd(&hw.CMDQ_CONS) as u64;
+                panic!(
+                    "smmuv3: command queue error {:#x}",
+                    bit64_extract(cons, SMMUV3_Q_CONS_ERR_OFF, SMMUV3_Q_CONS_ERR_LEN)
+                );
+            }
+            if self.cmdq.ptr(rd(&hw.CMDQ_CONS)) == prod {
+                break;
+            }
+        }
+    }
+
+    fn sid_bits(&self) -> usize {
+        match &self.strtab {
+            StreamTable::Linear { sid_bits, .. } | StreamTable::TwoLevel { sid_bits, .. } => {
+                *sid_bits
+            }
+        }
+    }
+
+    /// Returns the STE for `sid`, allocating its second level table on the
+    /// first use.
+    fn ste(&mut self, sid: StreamID) -> BaoResult<*mut u64> {
+        if sid as usize >= 1 << self.sid_bits() {
+            return Err(BaoError::InvalidParam);
+        }
+        let sid = sid as usize;
+        match &mut self.strtab {
+            StreamTable::Linear { base, .. } => {
+                Ok((*base as usize + sid * SMMUV3_STE_SIZE) as *mut u64)
+            }
+            StreamTable::TwoLevel { l1, l2, .. } => {
+                let l1_index = sid >> SMMUV3_STRTAB_SPLIT;
+                let l2_base = match l2[l1_index] {
+                    Some(base) => base,
+                    None => {
+                        let l2_num = 1 << SMMUV3_STRTAB_SPLIT;
+                        let (base, base_pa) = smmu_alloc(l2_num * SMMUV3_STE_SIZE);
+                        for i in 0..l2_num {
+                            ste_init_abort((base as usize + i * SMMUV3_STE_SIZE) as *mut u64);
+                        }
+                        fence_sync();
+                        let l1std = (base_pa & SMMUV3_BASE_ADDR_MSK) | SMMUV3_L1STD_SPAN;
+                        unsafe { write_volatile((*l1 as *mut u64).add(l1_index), l1std) };
+                        fence_sync();
+                        l2[l1_index] = Some(base);
+                        base
+                    }
+                };
+                let l2_index = sid & ((1 << SMMUV3_STRTAB_SPLIT) - 1);
+                Ok((l2_base as usize + l2_index * SMMUV3_STE_SIZE) as *mut u64)
+            }
+        }
+    }
+
+    fn add_stream(&mut self, sid: StreamID, vmid: usize, s2_root: Paddr) -> BaoResult<()> {
+        let ste = self.ste(sid)?;
+        if unsafe { read_volatile(ste) } & SMMUV3_STE_CFG_MSK != SMMUV3_STE_CFG_ABORT {
+            return Err(BaoError::AlreadyExists);
+        }
+
+        // Stage 1 bypassed, stage 2 shares the vm's translation tables
+        let vtcr = read_reg!(vtcr_el2);
+        let words = [
+            SMMUV3_STE_V_BIT | SMMUV3_STE_CFG_S2,
+            SMMUV3_STE_SHCFG_INCOMING,
+            vmid as u64
+                | ((vtcr & SMMUV3_STE_S2_VTCR_MSK) << SMMUV3_STE_S2_VTCR_OFF)
+                | SMMUV3_STE_S2AA64_BIT
+                | SMMUV3_STE_S2R_BIT,
+            s2_root & SMMUV3_STE_S2TTB_MSK,
+        ];
+        unsafe {
+            for (i, word) in words.iter().enumerate().skip(1) {
+                write_volatile(ste.add(i), *word);
+            }
+            fence_sync();
+            write_volatile(ste, words[0]);
+        }
+        fence_sync();
+
+        self.cmd([
+            SMMUV3_CMD_CFGI_STE | ((sid as u64) << SMMUV3_CMD_SID_OFF),
+            SMMUV3_CMD_CFGI_LEAF_BIT,
+        ]);
+        self.cmd_sync();
+        Ok(())
+    }
+
+    fn tlb_inv_vm(&mut self, vmid: usize) {
+        if !self.btm {
+            self.cmd([
+                SMMUV3_CMD_TLBI_S12_VMALL | ((vmid as u64) << SMMUV3_CMD_VMID_OFF),
+                0,
+            ]);
+            self.cmd_sync();
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let hw = self.hw();
+        loop {
+            let prod = rd(&hw.EVENTQ_PROD);
+            let cons = rd(&hw.EVENTQ_CONS);
+            // Acknowledging the overflow with the next consumer update
+            // lets the smmu record events again
+            let ovf = prod & SMMUV3_Q_PROD_OVFLG_BIT;
+            let overflowed = ovf != cons & SMMUV3_Q_CONS_OVACKFLG_BIT;
+            if overflowed {
+                println!("smmuv3: event queue overflow, events were lost");
+            }
+            if self.eventq.ptr(prod) == self.eventq.ptr(cons) {
+                if overflowed {
+                    wr(&mut hw.EVENTQ_CONS, self.eventq.ptr(cons) | ovf);
+                }
+                break;
+            }
+            let evt = self.eventq.entry(cons);
+            let (word0, addr) = unsafe { (read_volatile(evt), read_volatile(evt.add(2))) };
+            println!(
+                "smmuv3: event {:#x} from stream {:#x} at {:#x}",
+                word0 & 0xff,
+                word0 >> 32,
+                addr
+            );
+            wr(&mut hw.EVENTQ_CONS, self.eventq.inc(cons) | ovf);
+        }
+    }
+
+    fn handle_gerror(&mut self) {
+        let hw = self.hw();
+        let gerror = rd(&hw.GERROR);
+        let active = gerror ^ rd(&hw.GERRORN);
+        if active != 0 {
+            println!("smmuv3: global error {:#x}", active);
+            wr(&mut hw.GERRORN, gerror);
+        }
+    }
+}
+
+/// Valid entry aborting every transaction, so unassigned streams cannot
+/// reach memory.
+fn ste_init_abort(ste: *mut u64) {
+    for i in 0..SMMUV3_STE_SIZE / 8 {
+        unsafe { write_volatile(ste.add(i), 0) };
+    }
+    unsafe { write_volatile(ste, SMMUV3_STE_V_BIT | SMMUV3_STE_CFG_ABORT) };
+}
+
+pub fn smmu_init() {
+    let desc = &PLATFORM.arch.smmu;
+    let hw = mycpu()
+        .addr_space
+        .mem_alloc_map_dev(
+            SEC_HYP_GLOBAL,
+            desc.base,
+            None,
+            num_pages(core::mem::size_of::<SmmuV3Hw>()),
+        )
+        .unwrap();
+    let Some(mut smmu) = SmmuV3::new(hw) else {
+        return;
+    };
+    smmu.enable();
+    SMMU.call_once(|| Mutex::new(smmu));
+    interrupts_reserve(desc.interrupt_id, smmu_event_handler);
+    interrupts_cpu_enable(desc.interrupt_id, true);
+    interrupts_reserve(desc.gerror_id, smmu_gerror_handler);
+    interrupts_cpu_enable(desc.gerror_id, true);
+}
+
+pub fn smmu_present() -> bool {
+    SMMU.get().is_some()
+}
+
+pub fn smmu_add_stream(sid: StreamID, vmid: usize, s2_root: Paddr) -> BaoResult<()> {
+    match SMMU.get() {
+        Some(smmu) => smmu.lock().add_stream(sid, vmid, s2_root),
+        None => Err(BaoError::Unsupported),
+    }
+}
+
+pub fn smmu_tlb_inv_vm(vmid: usize) {
+    if let Some(smmu) = SMMU.get() {
+        smmu.lock().tlb_inv_vm(vmid);
+    }
+}
+
+pub fn smmu_event_handler(_id: IrqID) {
+    if let Some(smmu) = SMMU.get() {
+        smmu.lock().handle_events();
+    }
+}
+
+pub fn smmu_gerror_handler(_id: IrqID) {
+    if let Some(smmu) = SMMU.get() {
+        smmu.lock().handle_gerror();
+    }
+}
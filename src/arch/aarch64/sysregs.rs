@@ -205,7 +205,7 @@ pub const SCTLR_BR: u64 = 1 << 17;
 pub const SCTLR_WXN: u64 = 1 << 19;
 pub const SCTLR_EE: u64 = 1 << 25;
 
-pub const SCTLR_DFLT: u64 = SCTLR_RES1 | SCTLR_M | SCTLR_C | SCTLR_I;
+pub const SCTLR_DFLT: u64 = SCTLR_RES1 | SCTLR_M | SCTLR_C | SCTLR_I | SCTLR_WXN;
 
 pub const MPIDR_RES1: u64 = 0x80000000;
 pub const MPIDR_RES0_MSK: u64 = !(0x1f << 25);
@@ -215,6 +215,12 @@ pub const MPIDR_AFF_MSK: u64 = 0xffff;
 
 pub const PAR_F: u64 = 1;
 pub const PAR_PA_MSK: u64 = 0x3ffffff << 12;
+pub const PAR_FST_OFF: u64 = 1;
+pub const PAR_FST_LEN: u64 = 6;
+pub const PAR_FST_TYPE_MSK: u64 = 0x3c;
+pub const PAR_FST_PERM: u64 = 0xc;
+pub const PAR_ATTR_OFF: u64 = 56;
+pub const PAR_ATTR_LEN: u64 = 8;
 
 /* ESR_ELx, Exception Syndrome Register (ELx) */
 
@@ -313,6 +319,22 @@ pub fn arm_at_s1e2w(vaddr: Vaddr) -> u64 {
     }
 }
 
+pub fn arm_at_s1e1w(vaddr: Vaddr) -> u64 {
+    unsafe {
+        asm!("at s1e1w, {}", in(reg) vaddr);
+        isb();
+        PAR_EL1.get()
+    }
+}
+
+pub fn arm_at_s12e1r(vaddr: Vaddr) -> u64 {
+    unsafe {
+        asm!("at s12e1r, {}", in(reg) vaddr);
+        isb();
+        PAR_EL1.get()
+    }
+}
+
 pub fn arm_at_s12e1w(vaddr: Vaddr) -> u64 {
     unsafe {
         asm!("at s12e1w, {}", in(reg) vaddr);
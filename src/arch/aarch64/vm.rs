@@ -10,27 +10,35 @@ use crate::{
     write_reg,
 };
 
-use super::gic::{vgic::{VGicD, VGicPriv}, vgic_init};
+use super::gic::{
+    vgic::{vgic_reset_state, vgic_restore_state, VGicD, VGicPriv},
+    vgic_init, vits_init, GichState, VIts,
+};
 
 impl VMArchTrait for VM {
     fn arch_init(&mut self, config: &VMConfig, master: bool) {
         // TODO: Vgic init
         if master {
             vgic_init(self, &config.vm_platform.arch.gic);
+            if config.vm_platform.arch.gic.its_addr != 0 {
+                vits_init(self, &config.vm_platform.arch.gic, &config.vm_platform.devs);
+            }
         }
     }
 }
 
 pub struct VMArch {
     pub vgicr_addr: Vaddr,
-    pub vgicd: VGicD
+    pub vgicd: VGicD,
+    pub vits: VIts,
 }
 
 impl VMArch {
     pub fn new() -> Self {
         Self {
             vgicr_addr: 0,
-            vgicd: VGicD::new()
+            vgicd: VGicD::new(),
+            vits: VIts::new(),
         }
     }
 }
@@ -47,6 +55,8 @@ pub struct ArchRegs {
 pub struct VCpuArch {
     pub vmpidr: u64,
     pub vgic_priv: VGicPriv,
+    /// The virtual cpu interface while the vcpu is not loaded
+    pub vgic_state: GichState,
     pub psci_ctx: RwLock<PsciCtx>,
 }
 
@@ -87,6 +97,7 @@ impl VCpuArchTrait for VCpu {
         write_reg!(cntvoff_el2, 0u64);
         write_reg!(sctlr_el1, SCTLR_RES1);
         write_reg!(pmcr_el0, 0u64);
+        vgic_reset_state(self);
     }
 
     fn arch_run(&mut self) {
@@ -94,7 +105,11 @@ impl VCpuArchTrait for VCpu {
             fn vcpu_arch_entry();
         }
         match self.arch.psci_ctx.read().state {
-            PsciState::On => unsafe { vcpu_arch_entry() },
+            PsciState::On => {
+                // Vcpus never leave their cpu, this is where one is loaded
+                vgic_restore_state(self);
+                unsafe { vcpu_arch_entry() }
+            }
             _ => todo!("vcpu_arch_run: idle"),
         }
     }
@@ -7,7 +7,7 @@ pub fn vmm_arch_init() {
     let hcr = HCR_VM_BIT
         | HCR_RW_BIT
         | HCR_IMO_BIT
-        | HCR_FMO_BIT
+        | HCR_FMO_BIT // also lets group 0 virtual interrupts reach the guest as FIQs
         | HCR_TSC_BIT
         | HCR_APK_BIT
         | HCR_API_BIT;
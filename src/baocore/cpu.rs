@@ -6,15 +6,16 @@ use crate::{
     arch::aarch64::{
         armv8_a::cpu_arch_profile::CPU_MASTER,
         cpu::CpuArch,
-        defs::{BAO_CPU_BASE, CPU_STACK_SIZE, PAGE_SIZE},
+        defs::{BAO_CPU_BASE, CPU_EMERGENCY_STACK_SIZE, CPU_STACK_SIZE, PAGE_SIZE},
     },
     platform::PLATFORM,
     util::align_up,
 };
 
 use super::{
+    intr::CpuIrqHandlers,
     mmu::mem::AddrSpace,
-    types::{CpuID, Paddr},
+    types::{CpuID, Paddr, Vaddr},
     vm::VCpu,
 };
 
@@ -24,6 +25,17 @@ pub struct CpuStack {
     stack: [u8; CPU_STACK_SIZE],
 }
 
+/// Never mapped, so an overflowing stack faults instead of running into the
+/// rest of the cpu area.
+#[repr(C)]
+#[repr(align(0x1000))]
+struct CpuStackGuard([u8; PAGE_SIZE]);
+
+/// Hypervisor exceptions are handled here, the stack may be what overflowed.
+#[repr(C)]
+#[repr(align(0x1000))]
+struct CpuEmergencyStack([u8; CPU_EMERGENCY_STACK_SIZE]);
+
 #[repr(C)]
 pub struct Cpu {
     pub vcpu: *mut VCpu, // vcpu should be put ahead
@@ -31,7 +43,10 @@ pub struct Cpu {
     pub handling_msgs: bool,
     pub addr_space: AddrSpace,
     pub arch: CpuArch,
+    pub irq_handlers: CpuIrqHandlers,
     // interface: *mut CpuIf,
+    emergency_stack: CpuEmergencyStack,
+    stack_guard: CpuStackGuard,
     stack: CpuStack,
 }
 
@@ -46,6 +61,15 @@ pub trait CpuArchTrait {
 }
 
 pub const CPU_SIZE: usize = size_of::<Cpu>();
+/// Offsets in the cpu area of the stack guard page, which is also the top of
+/// the emergency stack.
+pub const CPU_STACK_GUARD_OFF: usize = CPU_SIZE - CPU_STACK_SIZE - PAGE_SIZE;
+pub const CPU_EMERGENCY_STACK_TOP_OFF: usize = CPU_STACK_GUARD_OFF;
+
+pub fn cpu_stack_guard_contains(va: Vaddr) -> bool {
+    let guard = (BAO_CPU_BASE + CPU_STACK_GUARD_OFF) as Vaddr;
+    (guard..guard + PAGE_SIZE as Vaddr).contains(&va)
+}
 
 pub fn mycpu() -> &'static mut Cpu {
     unsafe { &mut *(BAO_CPU_BASE as *mut Cpu) }
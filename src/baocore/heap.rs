@@ -1,21 +1,138 @@
 use crate::{
-    arch::aarch64::{armv8_a::pagetable::PTE_HYP_FLAGS, defs::PAGE_SIZE},
-    baocore::{cpu::mycpu, mmu::sections::SEC_HYP_GLOBAL},
+    arch::aarch64::defs::PAGE_SIZE,
+    baocore::{
+        cpu::mycpu, mem::mem_alloc_page, mmu::sections::SEC_HYP_GLOBAL, types::MemOwner,
+    },
+    util::num_pages,
 };
 use alloc::vec::Vec;
-use buddy_system_allocator::LockedHeap;
-use core::alloc::Layout;
+use buddy_system_allocator::Heap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{null_mut, NonNull},
+};
+use spin::Mutex;
 
 // use super::types::CpuID;
 
 pub const HV_HEAP_SIZE: usize = 0x20000;
+/// Minimum amount of memory pulled from the page pools each time the heap
+/// runs out of space.
+pub const HV_HEAP_GROW_SIZE: usize = 0x10000;
+
+struct HvHeapInner {
+    heap: Heap<32>,
+    peak: usize,
+    grows: usize,
+}
+
+pub struct HvHeap {
+    inner: Mutex<HvHeapInner>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes handed to the allocator so far
+    pub total: usize,
+    /// Bytes currently allocated, including buddy rounding
+    pub used: usize,
+    /// High-water mark of `used`
+    pub peak: usize,
+    /// Number of times the heap was grown from the page pools
+    pub grows: usize,
+}
+
+impl HvHeap {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(HvHeapInner {
+                heap: Heap::<32>::new(),
+                peak: 0,
+                grows: 0,
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let inner = self.inner.lock();
+        HeapStats {
+            total: inner.heap.stats_total_bytes(),
+            used: inner.heap.stats_alloc_actual(),
+            peak: inner.peak,
+            grows: inner.grows,
+        }
+    }
+}
+
+impl HvHeap {
+    /// Pulls pages from the page pools into the heap. The heap lock is not
+    /// held meanwhile, mapping the pages may need the heap itself.
+    fn grow(&self, layout: &Layout) -> bool {
+        // The buddy allocator needs a naturally aligned block to satisfy the
+        // request. The new pages have no particular alignment, so pull twice
+        // the block size to be sure one fits.
+        let block = layout.size().max(layout.align()).next_power_of_two();
+        let size = HV_HEAP_GROW_SIZE.max(2 * block);
+        let n = num_pages(size);
+        match mem_alloc_page(n, SEC_HYP_GLOBAL, false, MemOwner::HypHeap) {
+            Ok(va) => {
+                let mut inner = self.inner.lock();
+                unsafe {
+                    inner
+                        .heap
+                        .add_to_heap(va as usize, va as usize + n * PAGE_SIZE);
+                }
+                inner.grows += 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        let mut inner = self.inner.lock();
+        let ptr = inner.heap.alloc(layout).ok()?;
+        inner.peak = inner.peak.max(inner.heap.stats_alloc_actual());
+        Some(ptr)
+    }
+}
+
+unsafe impl GlobalAlloc for HvHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Another cpu may take the new pages first, so retry until the pools
+        // run dry.
+        loop {
+            if let Some(ptr) = self.try_alloc(layout) {
+                return ptr.as_ptr();
+            }
+            if !self.grow(&layout) {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner
+            .lock()
+            .heap
+            .dealloc(NonNull::new_unchecked(ptr), layout);
+    }
+}
 
 #[cfg_attr(not(test), global_allocator)]
-static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::new();
+static HEAP_ALLOCATOR: HvHeap = HvHeap::new();
 
 #[alloc_error_handler]
 fn handle_alloc_error(layout: Layout) -> ! {
-    panic!("Heap allocation error, layout = {:?}", layout);
+    panic!(
+        "Heap allocation error, layout = {:?}, heap = {:?}",
+        layout,
+        HEAP_ALLOCATOR.stats()
+    );
+}
+
+pub fn heap_stats() -> HeapStats {
+    HEAP_ALLOCATOR.stats()
 }
 
 fn simple_test() {
@@ -31,19 +148,18 @@ fn simple_test() {
 
 pub fn init() {
     assert!(mycpu().is_master());
-    let heap_start = mycpu()
-        .addr_space
-        .mem_alloc_map(
-            SEC_HYP_GLOBAL,
-            None,
-            None,
-            HV_HEAP_SIZE / PAGE_SIZE,
-            PTE_HYP_FLAGS,
-        )
-        .unwrap();
+    let heap_start = mem_alloc_page(
+        HV_HEAP_SIZE / PAGE_SIZE,
+        SEC_HYP_GLOBAL,
+        false,
+        MemOwner::HypHeap,
+    )
+    .unwrap();
     unsafe {
         HEAP_ALLOCATOR
+            .inner
             .lock()
+            .heap
             .init(heap_start as usize, HV_HEAP_SIZE);
     }
     simple_test();
@@ -0,0 +1,154 @@
+use super::{
+    heap::heap_stats,
+    mem::{mem_pool_stats, mem_usage},
+    mmu::guest::copy_to_guest,
+    types::MemOwner,
+    vm::{myvcpu, myvm},
+};
+use crate::config::platform::qemu_aarch64_virt::linux_freertos::CONFIG;
+
+pub const HC_INVAL: u64 = 0;
+/// Memory accounting queries. x1 selects the query:
+/// - `HC_MEM_INFO_POOL`: x2 = pool index; returns x1 = base, x2 = size and
+///   x3 = free pages
+/// - `HC_MEM_INFO_OWNER`: x2 = owner kind, x3 = vm id for per-vm owners,
+///   which must be the caller's; returns x1 = pages held
+/// - `HC_MEM_INFO_HEAP`: returns x1 = total, x2 = used, x3 = peak heap bytes
+pub const HC_MEM_INFO: u64 = 2;
+/// Dumps and checks the calling vm's stage-2 mappings on the console.
+/// Fails if the check found problems.
+#[cfg(feature = "pt_dump")]
+pub const HC_PT_DUMP: u64 = 3;
+/// Dirty page tracking of the calling vm's memory. x1 selects the operation:
+/// - `HC_DIRTY_LOG_START`: x2 = base ipa, x3 = number of pages
+/// - `HC_DIRTY_LOG_STOP`
+/// - `HC_DIRTY_LOG_FETCH`: x2 = va of a buffer, x3 = its size in bytes;
+///   copies the bitmap there, one bit per page from the base, and returns
+///   x1 = pages tracked
+/// - `HC_DIRTY_LOG_CLEAR`: clears the bitmap and protects the pages again
+///
+/// Vms whose devices dma through the iommu can not use it, the iommu shares
+/// the stage 2 tables.
+pub const HC_DIRTY_LOG: u64 = 4;
+
+pub const HC_MEM_INFO_POOL: u64 = 0;
+pub const HC_MEM_INFO_OWNER: u64 = 1;
+pub const HC_MEM_INFO_HEAP: u64 = 2;
+
+pub const HC_DIRTY_LOG_START: u64 = 0;
+pub const HC_DIRTY_LOG_STOP: u64 = 1;
+pub const HC_DIRTY_LOG_FETCH: u64 = 2;
+pub const HC_DIRTY_LOG_CLEAR: u64 = 3;
+
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HcError {
+    Success = 0,
+    Failure,
+    InvalidId,
+    InvalidArgs,
+}
+
+/// Vms may only query their own per-vm owners.
+fn mem_owner_from_raw(kind: u64, vm_id: u64) -> Option<MemOwner> {
+    let vm_id = vm_id as usize;
+    let owner = match kind {
+        0 => MemOwner::HypImage,
+        1 => MemOwner::CpuArea,
+        2 => MemOwner::HypPageTable,
+        3 => MemOwner::HypHeap,
+        4 => MemOwner::HypData,
+        5 => MemOwner::SharedMem,
+        6 => MemOwner::VMPageTable(vm_id),
+        7 => MemOwner::VMRam(vm_id),
+        8 => MemOwner::Reserved,
+        _ => return None,
+    };
+    match owner {
+        MemOwner::VMPageTable(id) | MemOwner::VMRam(id) if id != myvm().id => None,
+        owner => Some(owner),
+    }
+}
+
+fn hypercall_mem_info(query: u64, arg1: u64, arg2: u64) -> Result<[u64; 3], HcError> {
+    match query {
+        HC_MEM_INFO_POOL => {
+            let pool = mem_pool_stats(arg1 as _).ok_or(HcError::InvalidArgs)?;
+            Ok([pool.base, pool.size as _, pool.free as _])
+        }
+        HC_MEM_INFO_OWNER => {
+            let owner = mem_owner_from_raw(arg1, arg2).ok_or(HcError::InvalidArgs)?;
+            Ok([mem_usage(owner) as _, 0, 0])
+        }
+        HC_MEM_INFO_HEAP => {
+            let heap = heap_stats();
+            Ok([heap.total as _, heap.used as _, heap.peak as _])
+        }
+        _ => Err(HcError::InvalidArgs),
+    }
+}
+
+fn hypercall_dirty_log(op: u64, arg1: u64, arg2: u64) -> Result<[u64; 3], HcError> {
+    let vm = myvm();
+    match op {
+        HC_DIRTY_LOG_START => {
+            let config = &CONFIG.read().vmlist[vm.id];
+            if config.vm_platform.devs.iter().any(|dev| !dev.stream_ids.is_empty()) {
+                return Err(HcError::Failure);
+            }
+            vm.addr_space
+                .mem_dirty_log_start(arg1, arg2 as _)
+                .map_err(|_| HcError::InvalidArgs)?;
+        }
+        HC_DIRTY_LOG_STOP => vm.addr_space.mem_dirty_log_stop(),
+        HC_DIRTY_LOG_FETCH => {
+            // Copied out first, writing the buffer may dirty one of its pages
+            let (bitmap, num_pages) = vm
+                .addr_space
+                .mem_dirty_log()
+                .map(|log| (log.as_bytes().to_vec(), log.num_pages))
+                .ok_or(HcError::Failure)?;
+            let n = bitmap.len().min(arg2 as _);
+            copy_to_guest(arg1, &bitmap[..n]).map_err(|_| HcError::InvalidArgs)?;
+            return Ok([num_pages as _, 0, 0]);
+        }
+        HC_DIRTY_LOG_CLEAR => vm.addr_space.mem_dirty_log_clear(),
+        _ => return Err(HcError::InvalidArgs),
+    }
+    Ok([0; 3])
+}
+
+#[cfg(feature = "pt_dump")]
+fn hypercall_pt_dump() -> Result<[u64; 3], HcError> {
+    let vm = myvm();
+    vm.addr_space.mem_dump();
+    match vm.check_mappings(&CONFIG.read().vmlist[vm.id]) {
+        0 => Ok([0; 3]),
+        _ => Err(HcError::Failure),
+    }
+}
+
+/// Handles the hypercall `id` issued by the current vcpu. Arguments are taken
+/// from, and results written back to, the vcpu's x1-x3.
+pub fn hypercall(id: u64) -> HcError {
+    let vcpu = myvcpu();
+    let (arg0, arg1, arg2) = (vcpu.read_reg(1), vcpu.read_reg(2), vcpu.read_reg(3));
+
+    let res = match id {
+        HC_MEM_INFO => hypercall_mem_info(arg0, arg1, arg2),
+        HC_DIRTY_LOG => hypercall_dirty_log(arg0, arg1, arg2),
+        #[cfg(feature = "pt_dump")]
+        HC_PT_DUMP => hypercall_pt_dump(),
+        _ => Err(HcError::InvalidId),
+    };
+
+    match res {
+        Ok(vals) => {
+            for (i, val) in vals.iter().enumerate() {
+                vcpu.write_reg(i as u64 + 1, *val);
+            }
+            HcError::Success
+        }
+        Err(e) => e,
+    }
+}
@@ -1,40 +1,73 @@
 use spin::{Lazy, RwLock};
 
 use crate::{
-    arch::aarch64::{
-        defs::PAGE_SIZE,
-        intr::{interrupts_arch_init, MAX_INTERUPTS},
+    arch::aarch64::intr::{
+        interrupts_arch_enable, interrupts_arch_init, interrupts_arch_is_priv,
+        CPU_PRIV_INTERUPTS, MAX_INTERUPTS,
     },
     util::bitmap::{BMSpace, Bitmap},
 };
 
-use super::types::{IrqID, Vaddr};
+use super::{cpu::mycpu, types::IrqID};
 
-static HYP_BM_SPACE: BMSpace = BMSpace([0; PAGE_SIZE]);
-static GLOBAL_BM_SPACE: BMSpace = BMSpace([0; PAGE_SIZE]);
+/// Hypervisor side of a reserved interrupt, called with the interrupt id.
+pub type IrqHandler = fn(IrqID);
+
+/// Per-cpu handlers of the private interrupts (SGIs and PPIs).
+pub type CpuIrqHandlers = [Option<IrqHandler>; CPU_PRIV_INTERUPTS];
+
+static HYP_BM_SPACE: BMSpace = BMSpace::new();
+static GLOBAL_BM_SPACE: BMSpace = BMSpace::new();
 
 static HYP_INTR_BITMAP: Lazy<RwLock<Bitmap>> =
     Lazy::new(|| RwLock::new(Bitmap::new(HYP_BM_SPACE.base(), MAX_INTERUPTS / 8)));
 static GLOBAL_INTR_BITMAP: Lazy<RwLock<Bitmap>> =
     Lazy::new(|| RwLock::new(Bitmap::new(GLOBAL_BM_SPACE.base(), MAX_INTERUPTS / 8)));
 
+/// Handlers of the shared interrupts, private ones live in each cpu.
+static INTR_HANDLERS: RwLock<[Option<IrqHandler>; MAX_INTERUPTS]> =
+    RwLock::new([None; MAX_INTERUPTS]);
+
 pub enum IntrHandleResult {
     ForwardToVM,
     HandledByHyp
 }
 
-pub fn interrupts_reserve(int_id: IrqID, _handler: Vaddr) {
+/// Takes `int_id` away from the vms and has `handler` service it. Private
+/// interrupts are registered for the calling cpu only, so each cpu taking
+/// one must reserve it itself.
+pub fn interrupts_reserve(int_id: IrqID, handler: IrqHandler) {
     if int_id as usize >= MAX_INTERUPTS {
         return;
     }
     HYP_INTR_BITMAP.write().set(int_id as _);
     GLOBAL_INTR_BITMAP.write().set(int_id as _);
+    if interrupts_arch_is_priv(int_id) {
+        mycpu().irq_handlers[int_id as usize] = Some(handler);
+    } else {
+        INTR_HANDLERS.write()[int_id as usize] = Some(handler);
+    }
 }
 
 pub fn interrupts_is_reserved(int_id: IrqID) -> bool {
     HYP_INTR_BITMAP.read().get(int_id as _)
 }
 
+/// The handler this cpu runs for `int_id`, if the hypervisor owns it.
+pub fn interrupts_hyp_handler(int_id: IrqID) -> Option<IrqHandler> {
+    if int_id as usize >= MAX_INTERUPTS || !interrupts_is_reserved(int_id) {
+        None
+    } else if interrupts_arch_is_priv(int_id) {
+        mycpu().irq_handlers[int_id as usize]
+    } else {
+        INTR_HANDLERS.read()[int_id as usize]
+    }
+}
+
+pub fn interrupts_cpu_enable(int_id: IrqID, en: bool) {
+    interrupts_arch_enable(int_id, en);
+}
+
 pub fn init() {
     interrupts_arch_init();
 }
@@ -0,0 +1,44 @@
+use crate::{
+    arch::aarch64::smmuv3::{smmu_add_stream, smmu_init, smmu_present, smmu_tlb_inv_vm},
+    config::VMConfig,
+    platform::PLATFORM,
+};
+
+use super::{cpu::mycpu, vm::VM};
+
+pub fn init() {
+    if PLATFORM.arch.smmu.base != 0 {
+        smmu_init();
+    }
+}
+
+/// Invalidates whatever the iommu cached from the stage 2 tables of vm `vmid`.
+pub fn iommu_vm_tlb_inv(vmid: usize) {
+    smmu_tlb_inv_vm(vmid);
+}
+
+impl VM {
+    /// Restricts the dma of the vm's devices to the vm's own memory, the
+    /// devices' streams are translated by the vm's stage 2 tables.
+    pub fn iommu_init(&mut self, config: &VMConfig) {
+        let mut streams = config
+            .vm_platform
+            .devs
+            .iter()
+            .flat_map(|dev| dev.stream_ids.iter())
+            .peekable();
+        if streams.peek().is_none() {
+            return;
+        }
+        if !smmu_present() {
+            panic!("vm {}: devices with stream ids but no iommu", self.id);
+        }
+
+        let s2_root = mycpu().addr_space.mem_translate(self.addr_space.pt.root).unwrap();
+        for sid in streams {
+            if let Err(e) = smmu_add_stream(*sid, self.id, s2_root) {
+                panic!("vm {}: failed to add stream {:#x} ({:?})", self.id, sid, e);
+            }
+        }
+    }
+}
@@ -5,8 +5,8 @@ use crate::{config::platform::qemu_aarch64_virt::linux_freertos::CONFIG, util::n
 
 use super::{
     cpu::mycpu,
-    mem::mem_alloc_ppages,
-    types::{IrqID, Paddr, Vaddr},
+    mem::{mem_alloc_ppages, mem_scrub_ppages},
+    types::{IrqID, MemOwner, MemScrub, Paddr, Vaddr},
 };
 
 #[derive(Clone)]
@@ -52,8 +52,9 @@ fn alloc_shmem() {
     for shmem in shmem_list.iter_mut() {
         if shmem.phys.is_none() {
             let n = num_pages(shmem.size as _);
-            let ppages = mem_alloc_ppages(n, false).unwrap();
+            let ppages = mem_alloc_ppages(n, false, MemOwner::SharedMem).unwrap();
             assert!(ppages.num_pages == n);
+            mem_scrub_ppages(&ppages, MemScrub::Zero);
             shmem.phys = Some(ppages.base);
         }
     }
@@ -1,74 +1,121 @@
-use core::mem::MaybeUninit;
-
-use spin::{Lazy, Mutex};
+use alloc::vec::Vec;
+use spin::Mutex;
 
 use super::{
     cpu::{mem_cpu_boot_alloc_size, mycpu, CPU_SYNC_TOKEN},
     heap,
-    mmu::{mem::mem_prot_init, sections::SEC_HYP_GLOBAL},
-    types::{AsSecID, ColorMap, Paddr},
+    mmu::{
+        mem::mem_prot_init,
+        sections::{SEC_HYP_GLOBAL, SEC_HYP_PRIVATE},
+    },
+    types::{AsSecID, ColorMap, MemOwner, MemScrub, Paddr},
 };
 use crate::{
     arch::aarch64::{armv8_a::pagetable::PTE_HYP_FLAGS, defs::PAGE_SIZE},
-    config,
+    config::{self, platform::qemu_aarch64_virt::linux_freertos::CONFIG},
     platform::PLATFORM,
+    println,
     util::{
-        align_up, bitmap::Bitmap, image_load_size, image_noload_size, image_size, is_aligned,
-        num_pages, range_in_range, vm_image_size, BaoError, BaoResult,
+        align_up, bitmap::Bitmap, clear_memory, fill_memory, image_load_size, image_noload_size,
+        image_size, is_aligned, num_pages, range_in_range, vm_image_size, BaoError, BaoResult,
     },
 };
 
-pub const MAX_PAGE_POOLS: usize = 4;
-
 pub struct PagePools {
-    pools: [Option<&'static mut MemPagePool>; MAX_PAGE_POOLS],
+    /// Pool of the region the hypervisor was loaded to, set up before the heap
+    root: Option<MemPagePool>,
+    /// Pools of the other platform regions
+    pools: Vec<MemPagePool>,
 }
 
-#[allow(invalid_value)]
-pub static PAGE_POOLS: Lazy<Mutex<PagePools>> = Lazy::new(|| {
-    Mutex::new(PagePools {
-        pools: {
-            let mut pools: [Option<&'static mut MemPagePool>; MAX_PAGE_POOLS] =
-                unsafe { MaybeUninit::uninit().assume_init() };
-            for i in 0..MAX_PAGE_POOLS {
-                pools[i] = None;
-            }
-            pools
-        },
-    })
-});
+pub static PAGE_POOLS: Mutex<PagePools> = Mutex::new(PagePools::new());
 
 impl PagePools {
-    pub fn insert(&mut self, pool: &'static mut MemPagePool) {
-        for p in self.pools.iter_mut() {
-            if p.is_none() {
-                *p = Some(pool);
-                return;
-            }
+    const fn new() -> Self {
+        Self {
+            root: None,
+            pools: Vec::new(),
         }
-        panic!("can't find free slot in PagePools");
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &MemPagePool> {
+        self.root.iter().chain(self.pools.iter())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut MemPagePool> {
+        self.root.iter_mut().chain(self.pools.iter_mut())
     }
 }
 
-pub fn add_page_pool(pool: &'static mut MemPagePool) {
-    PAGE_POOLS.lock().insert(pool);
+pub fn mem_alloc_ppages(num_pages: usize, aligned: bool, owner: MemOwner) -> Option<PPages> {
+    let ppages = PAGE_POOLS
+        .lock()
+        .iter_mut()
+        .find_map(|pp| pp.alloc(num_pages, aligned));
+    if let Some(ppages) = &ppages {
+        mem_account(owner, ppages.num_pages);
+    }
+    ppages
 }
 
-pub fn mem_alloc_ppages(num_pages: usize, aligned: bool) -> Option<PPages> {
+/// Takes `ppages` out of the page pool holding them. Fails unless a single
+/// pool holds the whole range and none of it is in use yet.
+pub fn mem_reserve_ppages(ppages: &PPages, owner: MemOwner) -> bool {
     let mut r = PAGE_POOLS.lock();
-    for pp in r.pools.iter_mut() {
-        if let Some(pp) = pp {
-            let ppages = pp.alloc(num_pages, aligned);
-            if ppages.is_some() {
-                return ppages;
-            }
-        };
+    let reserved = r
+        .iter_mut()
+        .find(|pp| pp.contains(ppages))
+        .map_or(false, |pp| pp.reserve_ppages(ppages));
+    if reserved {
+        mem_account(owner, ppages.num_pages);
     }
-    None
+    reserved
 }
 
-pub fn mem_alloc_page(num_pages: usize, sec: AsSecID, phys_aligned: bool) -> Result<u64, BaoError> {
-    if let Some(ppages) = mem_alloc_ppages(num_pages, phys_aligned) {
+/// Gives `ppages` back to the page pool holding them.
+pub fn mem_free_ppages(ppages: &PPages, owner: MemOwner) {
+    let mut r = PAGE_POOLS.lock();
+    for pp in r.iter_mut() {
+        if pp.contains(ppages) {
+            pp.free_ppages(ppages);
+            mem_unaccount(owner, ppages.num_pages);
+            return;
+        }
+    }
+}
+
+/// Cleans `ppages` according to `scrub` through a temporary hypervisor
+/// mapping.
+pub fn mem_scrub_ppages(ppages: &PPages, scrub: MemScrub) {
+    if scrub == MemScrub::Skip || ppages.num_pages == 0 {
+        return;
+    }
+    let addr_space = &mut mycpu().addr_space;
+    let va = addr_space
+        .mem_alloc_map(
+            SEC_HYP_PRIVATE,
+            Some(ppages),
+            None,
+            ppages.num_pages,
+            PTE_HYP_FLAGS,
+        )
+        .unwrap();
+    let size = ppages.num_pages * PAGE_SIZE;
+    match scrub {
+        MemScrub::Zero => clear_memory(va, size),
+        MemScrub::Pattern(pattern) => fill_memory(va, size, pattern),
+        MemScrub::Skip => unreachable!(),
+    }
+    addr_space.mem_unmap(va, ppages.num_pages, false);
+}
+
+pub fn mem_alloc_page(
+    num_pages: usize,
+    sec: AsSecID,
+    phys_aligned: bool,
+    owner: MemOwner,
+) -> Result<u64, BaoError> {
+    if let Some(ppages) = mem_alloc_ppages(num_pages, phys_aligned, owner) {
         if ppages.num_pages == num_pages {
             return mycpu().addr_space.mem_alloc_map(
                 sec,
@@ -82,6 +129,111 @@ pub fn mem_alloc_page(num_pages: usize, sec: AsSecID, phys_aligned: bool) -> Res
     Err(BaoError::OutOfMemory)
 }
 
+/// Vm ids past this are not accounted for individually.
+pub const MEM_ACCT_MAX_VMS: usize = 256;
+const MEM_ACCT_HYP_OWNERS: usize = 7;
+
+struct MemUsage {
+    hyp: [usize; MEM_ACCT_HYP_OWNERS],
+    vm_pt: [usize; MEM_ACCT_MAX_VMS],
+    vm_ram: [usize; MEM_ACCT_MAX_VMS],
+}
+
+impl MemUsage {
+    const fn new() -> Self {
+        Self {
+            hyp: [0; MEM_ACCT_HYP_OWNERS],
+            vm_pt: [0; MEM_ACCT_MAX_VMS],
+            vm_ram: [0; MEM_ACCT_MAX_VMS],
+        }
+    }
+
+    fn counter(&mut self, owner: MemOwner) -> Option<&mut usize> {
+        match owner {
+            MemOwner::HypImage => Some(&mut self.hyp[0]),
+            MemOwner::CpuArea => Some(&mut self.hyp[1]),
+            MemOwner::HypPageTable => Some(&mut self.hyp[2]),
+            MemOwner::HypHeap => Some(&mut self.hyp[3]),
+            MemOwner::HypData => Some(&mut self.hyp[4]),
+            MemOwner::SharedMem => Some(&mut self.hyp[5]),
+            MemOwner::Reserved => Some(&mut self.hyp[6]),
+            MemOwner::VMPageTable(id) => self.vm_pt.get_mut(id),
+            MemOwner::VMRam(id) => self.vm_ram.get_mut(id),
+        }
+    }
+}
+
+static MEM_USAGE: Mutex<MemUsage> = Mutex::new(MemUsage::new());
+
+pub fn mem_account(owner: MemOwner, num_pages: usize) {
+    if let Some(cnt) = MEM_USAGE.lock().counter(owner) {
+        *cnt += num_pages;
+    }
+}
+
+fn mem_unaccount(owner: MemOwner, num_pages: usize) {
+    if let Some(cnt) = MEM_USAGE.lock().counter(owner) {
+        *cnt = cnt.saturating_sub(num_pages);
+    }
+}
+
+/// Number of pages currently held by `owner`.
+pub fn mem_usage(owner: MemOwner) -> usize {
+    MEM_USAGE.lock().counter(owner).map_or(0, |cnt| *cnt)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PagePoolStats {
+    pub base: Paddr,
+    /// Pool size in pages
+    pub size: usize,
+    /// Pages not yet allocated or reserved
+    pub free: usize,
+}
+
+pub fn mem_pool_stats(pool: usize) -> Option<PagePoolStats> {
+    PAGE_POOLS.lock().iter().nth(pool).map(|pp| PagePoolStats {
+        base: pp.base,
+        size: pp.size,
+        free: pp.free,
+    })
+}
+
+pub fn mem_report() {
+    println!("Memory usage (in {:#x} byte pages):", PAGE_SIZE);
+    let mut i = 0;
+    while let Some(pool) = mem_pool_stats(i) {
+        println!(
+            "  pool {}: base {:#x}, {} pages, {} free",
+            i, pool.base, pool.size, pool.free
+        );
+        i += 1;
+    }
+    println!("  hyp image:       {}", mem_usage(MemOwner::HypImage));
+    println!("  cpu areas:       {}", mem_usage(MemOwner::CpuArea));
+    println!("  hyp page tables: {}", mem_usage(MemOwner::HypPageTable));
+    println!("  hyp data:        {}", mem_usage(MemOwner::HypData));
+    println!("  shared memory:   {}", mem_usage(MemOwner::SharedMem));
+    println!("  reserved:        {}", mem_usage(MemOwner::Reserved));
+    let heap = heap::heap_stats();
+    println!(
+        "  heap:            {} ({:#x} of {:#x} bytes used, peak {:#x}, grown {} times)",
+        mem_usage(MemOwner::HypHeap),
+        heap.used,
+        heap.total,
+        heap.peak,
+        heap.grows
+    );
+    for id in 0..CONFIG.read().vmlist.len() {
+        println!(
+            "  vm {}: ram {}, page tables {}",
+            id,
+            mem_usage(MemOwner::VMRam(id)),
+            mem_usage(MemOwner::VMPageTable(id))
+        );
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct PPages {
@@ -111,6 +263,52 @@ pub struct MemPagePool {
 }
 
 impl MemPagePool {
+    fn new(base: Paddr, size: usize) -> Self {
+        MemPagePool {
+            base,
+            size,
+            free: size,
+            last: 0,
+            bitmap: None,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn init_bitmap(&mut self) -> BaoResult<()> {
+        let bitmap_num_pages = self.size.div_ceil(8 * PAGE_SIZE);
+        let bitmap_va =
+            mem_alloc_page(bitmap_num_pages, SEC_HYP_GLOBAL, false, MemOwner::HypData)?;
+        let mut bitmap = Bitmap::new(bitmap_va, bitmap_num_pages * PAGE_SIZE);
+        bitmap.clear_all();
+        self.bitmap = Some(bitmap);
+        Ok(())
+    }
+
+    /// Takes the frames of the platform's reserved regions out of the pool.
+    /// Fails if any of them is already in use.
+    fn reserve_holes(&mut self) -> BaoResult<()> {
+        let pool_end = self.base + (self.size * PAGE_SIZE) as Paddr;
+        for hole in PLATFORM.reserved.iter() {
+            let beg = hole.base.max(self.base);
+            let end = (hole.base + hole.size as Paddr).min(pool_end);
+            if beg >= end {
+                continue;
+            }
+            let first = (beg - self.base) as usize / PAGE_SIZE;
+            let last = ((end - self.base) as usize).div_ceil(PAGE_SIZE);
+            let ppages = PPages::new(self.base + (first * PAGE_SIZE) as Paddr, last - first);
+            if !self.reserve_ppages(&ppages) {
+                println!(
+                    "reserved region {:#x} (size {:#x}) is already in use",
+                    hole.base, hole.size
+                );
+                return Err(BaoError::AlreadyExists);
+            }
+            mem_account(MemOwner::Reserved, ppages.num_pages);
+        }
+        Ok(())
+    }
+
     pub fn set_up_bitmap(&mut self, load_addr: Paddr) -> BaoResult<()> {
         let cpu_size = PLATFORM.cpu_num * mem_cpu_boot_alloc_size();
         let bitmap_num_pages = self.size.div_ceil(8 * PAGE_SIZE);
@@ -129,19 +327,26 @@ impl MemPagePool {
         if !self.reserve_ppages(&bitmap_pp) {
             return Err(BaoError::AlreadyExists);
         }
+        mem_account(MemOwner::HypData, bitmap_num_pages);
         Ok(())
     }
 
-    pub fn reserve_ppages(&mut self, ppages: &PPages) -> bool {
-        let is_in_rgn = range_in_range(
+    pub fn contains(&self, ppages: &PPages) -> bool {
+        range_in_range(
             ppages.base as usize,
             ppages.num_pages * PAGE_SIZE,
             self.base as usize,
             self.size * PAGE_SIZE,
-        );
-        if !is_in_rgn {
+        )
+    }
+
+    pub fn reserve_ppages(&mut self, ppages: &PPages) -> bool {
+        if ppages.num_pages == 0 {
             return true;
         }
+        if !self.contains(ppages) {
+            return false;
+        }
 
         let pageoff = num_pages((ppages.base - self.base) as _);
         let was_free = !self.are_ppages_reserved(ppages);
@@ -155,6 +360,18 @@ impl MemPagePool {
         was_free
     }
 
+    pub fn free_ppages(&mut self, ppages: &PPages) {
+        let _lock = self.lock.lock();
+        let pageoff = num_pages((ppages.base - self.base) as _);
+        let bitmap = self.bitmap.as_mut().unwrap();
+        for bit in pageoff..pageoff + ppages.num_pages {
+            if bitmap.get(bit) {
+                bitmap.clear(bit);
+                self.free += 1;
+            }
+        }
+    }
+
     pub fn are_ppages_reserved(&self, ppages: &PPages) -> bool {
         let rgn_found = range_in_range(
             ppages.base as _,
@@ -192,14 +409,26 @@ impl MemPagePool {
         let cpu_base_addr = image_noload_addr + image_noload_size as u64;
 
         let images_load_ppages = PPages::new(load_addr, num_pages(image_load_size));
+        // The embedded vm images, in-place ones are mapped straight to their
+        // vm, so the pools must not hand them out before it is created.
+        let vm_images_ppages =
+            PPages::new(load_addr + image_load_size as u64, num_pages(vm_image_size));
         let images_noload_ppages = PPages::new(image_noload_addr, num_pages(image_noload_size));
         let cpu_ppages = PPages::new(cpu_base_addr, num_pages(cpu_size));
 
         let image_load_reserved = self.reserve_ppages(&images_load_ppages);
+        let vm_images_reserved = self.reserve_ppages(&vm_images_ppages);
         let image_noload_reserved = self.reserve_ppages(&images_noload_ppages);
         let cpu_reserved = self.reserve_ppages(&cpu_ppages);
 
-        if image_load_reserved && image_noload_reserved && cpu_reserved {
+        if image_load_reserved && vm_images_reserved && image_noload_reserved && cpu_reserved {
+            mem_account(
+                MemOwner::HypImage,
+                images_load_ppages.num_pages
+                    + vm_images_ppages.num_pages
+                    + images_noload_ppages.num_pages,
+            );
+            mem_account(MemOwner::CpuArea, cpu_ppages.num_pages);
             Ok(())
         } else {
             Err(BaoError::AlreadyExists)
@@ -248,67 +477,64 @@ impl MemPagePool {
     }
 }
 
+/// A range of physical memory as described by the platform.
 #[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct MemRegion {
-    base: Paddr,
-    size: usize,
-    page_pool: MemPagePool,
+    pub base: Paddr,
+    pub size: usize,
 }
 
 impl MemRegion {
     pub const fn new(base: Paddr, size: usize) -> Self {
-        MemRegion {
-            base,
-            size,
-            page_pool: MemPagePool {
-                base: 0,
-                size: 0,
-                free: 0,
-                last: 0,
-                bitmap: None,
-                lock: Mutex::new(()),
-            },
-        }
+        MemRegion { base, size }
     }
 
-    pub fn page_pool_root_init(&mut self, load_addr: Paddr) -> BaoResult<()> {
-        let pool_sz = self.size / PAGE_SIZE;
-        let mut page_pool = MemPagePool {
-            base: self.base,
-            size: pool_sz,
-            free: pool_sz,
-            last: 0,
-            bitmap: None,
-            lock: Mutex::new(()),
-        };
-        page_pool.set_up_bitmap(load_addr)?;
-        page_pool.reserve_hyp_mem(load_addr)?;
-        self.page_pool = page_pool;
-        Ok(())
+    fn page_pool(&self) -> MemPagePool {
+        MemPagePool::new(self.base, self.size / PAGE_SIZE)
     }
 }
 
-fn mem_find_root_region(load_addr: Paddr) -> BaoResult<&'static mut MemRegion> {
+fn mem_find_root_region(load_addr: Paddr) -> BaoResult<&'static MemRegion> {
     let image_size = image_size();
 
     /* Find the root memory region in which the hypervisor was loaded. */
-    for i in 0..PLATFORM.region_num {
-        let region = unsafe { &mut *(&PLATFORM.regions[i] as *const _ as *mut MemRegion) };
-        let is_in_rgn = range_in_range(load_addr as _, image_size, region.base as _, region.size);
-        if is_in_rgn {
-            return Ok(region);
-        }
-    }
-
-    Err(BaoError::NotFound)
+    PLATFORM
+        .regions
+        .iter()
+        .find(|region| range_in_range(load_addr as _, image_size, region.base as _, region.size))
+        .ok_or(BaoError::NotFound)
 }
 
-fn mem_setup_root_pool(load_addr: Paddr) -> BaoResult<&'static mut MemRegion> {
+fn mem_setup_root_pool(load_addr: Paddr) -> BaoResult<&'static MemRegion> {
     let root_mem_region = mem_find_root_region(load_addr)?;
-    root_mem_region.page_pool_root_init(load_addr)?;
+    let mut page_pool = root_mem_region.page_pool();
+    page_pool.set_up_bitmap(load_addr)?;
+    page_pool.reserve_hyp_mem(load_addr)?;
+    page_pool.reserve_holes()?;
+    PAGE_POOLS.lock().root = Some(page_pool);
     Ok(root_mem_region)
 }
 
+/// Sets up the pools of every region other than the root one. Their bitmaps
+/// come from the pools already in place, so this needs the heap.
+fn mem_create_ppools(root_mem_region: &MemRegion) -> BaoResult<()> {
+    let mut pools = Vec::with_capacity(PLATFORM.regions.len());
+    for region in PLATFORM.regions.iter() {
+        if core::ptr::eq(region, root_mem_region) {
+            continue;
+        }
+        let mut page_pool = region.page_pool();
+        page_pool.init_bitmap()?;
+        page_pool.reserve_holes()?;
+        pools.push(page_pool);
+    }
+    // Moved in whole, growing the vector under the lock could need the
+    // heap to grow, and so the pools.
+    PAGE_POOLS.lock().pools = pools;
+    Ok(())
+}
+
 pub fn init(load_addr: Paddr) {
     mem_prot_init();
     if mycpu().is_master() {
@@ -317,9 +543,12 @@ pub fn init(load_addr: Paddr) {
             Ok(m) => m,
             Err(e) => panic!("{:#x?}", e),
         };
-        add_page_pool(&mut mem_region.page_pool);
         heap::init();
+        if let Err(e) = mem_create_ppools(mem_region) {
+            panic!("failed to create page pools ({:?})", e);
+        }
         config::init(load_addr);
     }
     CPU_SYNC_TOKEN.sync_and_clear_msg();
+    mycpu().addr_space.mem_scratch_init();
 }
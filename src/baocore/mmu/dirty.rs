@@ -0,0 +1,187 @@
+use crate::{
+    arch::aarch64::{
+        armv8_a::{
+            pagetable::{pte_vm_access, PTE_RSW_WRPROT},
+            tlb::{tlb_vm_inv_all, tlb_vm_inv_ipa},
+        },
+        defs::PAGE_SIZE,
+    },
+    baocore::{
+        cpu::mycpu,
+        iommu::iommu_vm_tlb_inv,
+        mem::mem_alloc_page,
+        types::{AsType, MemAccess, MemOwner, Vaddr},
+    },
+    util::{bitmap::Bitmap, is_aligned, BaoError, BaoResult},
+};
+
+use super::{mem::AddrSpace, sections::SEC_HYP_GLOBAL};
+
+/// Pages written by a vm since dirty logging started or was last cleared.
+pub struct DirtyLog {
+    pub base: Vaddr,
+    pub num_pages: usize,
+    /// One bit per page, starting at `base`
+    pub bitmap: Bitmap,
+}
+
+impl DirtyLog {
+    pub fn is_dirty(&self, ipa: Vaddr) -> bool {
+        self.page_index(ipa).map_or(false, |i| self.bitmap.get(i))
+    }
+
+    /// The bitmap as bytes, the page at `base + i * PAGE_SIZE` is bit `i % 8`
+    /// of byte `i / 8`.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            core::slice::from_raw_parts(self.bitmap.base() as *const u8, self.num_pages.div_ceil(8))
+        }
+    }
+
+    fn page_index(&self, ipa: Vaddr) -> Option<usize> {
+        let i = (ipa.checked_sub(self.base)? as usize) / PAGE_SIZE;
+        (i < self.num_pages).then_some(i)
+    }
+
+    fn bitmap_pages(num_pages: usize) -> usize {
+        num_pages.div_ceil(8 * PAGE_SIZE)
+    }
+}
+
+impl AddrSpace {
+    /// Starts tracking guest writes to the `num_pages` pages at `base`. The
+    /// read-write mappings in the range become read-only at stage 2 until the
+    /// first write to each page. The iommu must not share the tables, device
+    /// writes would fault.
+    pub fn mem_dirty_log_start(&mut self, base: Vaddr, num_pages: usize) -> BaoResult<()> {
+        if self.as_type != AsType::AsVM {
+            return Err(BaoError::Unsupported);
+        }
+        if !is_aligned(base as _, PAGE_SIZE) || num_pages == 0 {
+            return Err(BaoError::InvalidParam);
+        }
+
+        let _lock = self.lock.lock();
+        if self.dirty_log.is_some() {
+            return Err(BaoError::AlreadyExists);
+        }
+
+        let bitmap_pages = DirtyLog::bitmap_pages(num_pages);
+        let bitmap_va = mem_alloc_page(bitmap_pages, SEC_HYP_GLOBAL, false, MemOwner::HypData)?;
+        let mut bitmap = Bitmap::new(bitmap_va, bitmap_pages * PAGE_SIZE);
+        bitmap.clear_all();
+
+        self.dirty_log = Some(DirtyLog {
+            base,
+            num_pages,
+            bitmap,
+        });
+        self.mem_set_write_protect(base, num_pages, true);
+        Ok(())
+    }
+
+    /// Stops dirty logging, giving write permission back to every page it
+    /// took it from.
+    pub fn mem_dirty_log_stop(&mut self) {
+        let _lock = self.lock.lock();
+        if let Some(log) = self.dirty_log.take() {
+            self.mem_set_write_protect(log.base, log.num_pages, false);
+            mycpu()
+                .addr_space
+                .mem_unmap(log.bitmap.base(), DirtyLog::bitmap_pages(log.num_pages), true);
+        }
+    }
+
+    pub fn mem_dirty_log(&self) -> Option<&DirtyLog> {
+        self.dirty_log.as_ref()
+    }
+
+    /// Clears the dirty bitmap and write-protects the dirtied pages again.
+    pub fn mem_dirty_log_clear(&mut self) {
+        let _lock = self.lock.lock();
+        if let Some(log) = self.dirty_log.as_mut() {
+            log.bitmap.clear_all();
+            let (base, num_pages) = (log.base, log.num_pages);
+            self.mem_set_write_protect(base, num_pages, true);
+        }
+    }
+
+    /// Handles a stage 2 write permission fault at `ipa`. Returns false if
+    /// the page was not write-protected by dirty logging.
+    pub fn mem_dirty_log_fault(&mut self, ipa: Vaddr) -> bool {
+        let _lock = self.lock.lock();
+        if self.dirty_log.is_none() {
+            return false;
+        }
+
+        let mut lvl = 0;
+        let pte_ptr = loop {
+            let pte_ptr = self.pt.pt_get_pte(lvl, ipa);
+            let pte = unsafe { *pte_ptr };
+            if !pte.is_valid() {
+                return false;
+            } else if pte.is_table(&self.pt, lvl) {
+                lvl += 1;
+            } else if !pte.check_rsw(PTE_RSW_WRPROT) {
+                return false;
+            } else if lvl < self.pt.dscr.lvls - 1 {
+                // Only track the page written, not the whole block
+                let lvlsz = self.pt.pt_lvlsize(lvl) as u64;
+                self.mem_split_block(lvl, pte_ptr, ipa & !(lvlsz - 1));
+                lvl += 1;
+            } else {
+                break pte_ptr;
+            }
+        };
+
+        unsafe { (*pte_ptr).set_s2_writable(true) };
+        let page = ipa & !(PAGE_SIZE as u64 - 1);
+        tlb_vm_inv_ipa(page);
+        iommu_vm_tlb_inv(self.id as _);
+
+        let log = self.dirty_log.as_mut().unwrap();
+        if let Some(i) = log.page_index(page) {
+            log.bitmap.set(i);
+        }
+        true
+    }
+
+    /// Takes write permission from (`protect`) or gives it back to the
+    /// mappings in the range that dirty logging tracks.
+    fn mem_set_write_protect(&self, base: Vaddr, num_pages: usize, protect: bool) {
+        let top = base + (num_pages * PAGE_SIZE) as u64;
+        let mut va = base;
+        let mut lvl = 0;
+        while va < top {
+            let pte_ptr = self.pt.pt_get_pte(lvl, va);
+            let pte = unsafe { &mut *pte_ptr };
+            if pte.is_valid() && pte.is_table(&self.pt, lvl) {
+                lvl += 1;
+                continue;
+            }
+            let lvlsz = self.pt.pt_lvlsize(lvl) as u64;
+            let blk_va = va & !(lvlsz - 1);
+            if pte.is_valid() {
+                // Write-only mappings would become inaccessible
+                let writable = pte_vm_access(pte.0) == Some(MemAccess::ReadWrite);
+                if protect && writable && (blk_va < base || blk_va + lvlsz > top) {
+                    // Only protect the part of the block in the range, the
+                    // rest would stay read-only once logging stops
+                    self.mem_split_block(lvl, pte_ptr, blk_va);
+                    lvl += 1;
+                    continue;
+                } else if protect && writable {
+                    pte.set_s2_writable(false);
+                    pte.set_rsw(PTE_RSW_WRPROT);
+                } else if !protect && pte.check_rsw(PTE_RSW_WRPROT) {
+                    pte.set_s2_writable(true);
+                    pte.set_rsw(0);
+                }
+            }
+            va = blk_va + lvlsz;
+            lvl = 0;
+        }
+        tlb_vm_inv_all();
+        iommu_vm_tlb_inv(self.id as _);
+    }
+}
@@ -0,0 +1,157 @@
+use aarch64::regs::PAR_EL1;
+use alloc::vec::Vec;
+use tock_registers::interfaces::{Readable, Writeable};
+
+use crate::{
+    arch::aarch64::{
+        armv8_a::pagetable::{pte_vm_access, pte_vm_mem_type, PTE_HYP_FLAGS},
+        defs::PAGE_SIZE,
+        sysregs::{
+            arm_at_s12e1r, arm_at_s12e1w, arm_at_s1e1w, PAR_ATTR_LEN, PAR_ATTR_OFF, PAR_F,
+            PAR_FST_LEN, PAR_FST_OFF, PAR_FST_PERM, PAR_FST_TYPE_MSK, PAR_PA_MSK,
+        },
+    },
+    baocore::{
+        cpu::mycpu,
+        mem::PPages,
+        types::{MemAccess, MemType, Paddr, Vaddr},
+        vm::myvm,
+    },
+    util::{align_down, bit64_extract, BaoError, BaoResult},
+};
+
+use super::sections::SEC_HYP_PRIVATE;
+
+fn guest_at(va: Vaddr, write: bool) -> u64 {
+    let par_saved = PAR_EL1.get();
+    let par = if write {
+        arm_at_s12e1w(va)
+    } else {
+        arm_at_s12e1r(va)
+    };
+    PAR_EL1.set(par_saved);
+    par
+}
+
+fn par_is_perm_fault(par: u64) -> bool {
+    par & PAR_F != 0
+        && bit64_extract(par, PAR_FST_OFF, PAR_FST_LEN) & PAR_FST_TYPE_MSK == PAR_FST_PERM
+}
+
+/// The intermediate physical address `va` translates to at stage 1.
+fn guest_translate_s1(va: Vaddr) -> Option<Vaddr> {
+    let par_saved = PAR_EL1.get();
+    let par = arm_at_s1e1w(va);
+    PAR_EL1.set(par_saved);
+    (par & PAR_F == 0).then_some((par & PAR_PA_MSK) | (va & (PAGE_SIZE as u64 - 1)))
+}
+
+/// Translates `va` through the stage 1 and 2 tables of the vcpu running on
+/// this cpu, checking the guest may read it, or write it if `write` is set.
+/// Writing a page dirty logging protects marks it dirty, as a guest store
+/// would.
+fn guest_translate(va: Vaddr, write: bool) -> BaoResult<Paddr> {
+    let mut par = guest_at(va, write);
+    if write && par_is_perm_fault(par) {
+        let dirtied =
+            guest_translate_s1(va).map_or(false, |ipa| myvm().addr_space.mem_dirty_log_fault(ipa));
+        if dirtied {
+            par = guest_at(va, write);
+        }
+    }
+
+    if par & PAR_F != 0 {
+        return match par_is_perm_fault(par) {
+            true => Err(BaoError::PermissionDenied),
+            false => Err(BaoError::NotFound),
+        };
+    }
+    // Only normal memory can be accessed through a cacheable hypervisor
+    // mapping, device attributes have the upper nibble cleared.
+    if bit64_extract(par, PAR_ATTR_OFF, PAR_ATTR_LEN) & 0xf0 == 0 {
+        return Err(BaoError::InvalidParam);
+    }
+    Ok((par & PAR_PA_MSK) | (va & (PAGE_SIZE as u64 - 1)))
+}
+
+/// Translates the intermediate physical address `ipa` through the stage 2
+/// tables of the vm running on this cpu, with the same checks as
+/// `guest_translate`. Any of the vm's cpus can walk them, `vm_init` maps the
+/// tables on each.
+fn guest_translate_ipa(ipa: Vaddr, write: bool) -> BaoResult<Paddr> {
+    let mut res = Err(BaoError::NotFound);
+    myvm().addr_space.mem_walk_range(ipa, ipa, |m| {
+        res = match (pte_vm_mem_type(m.flags), pte_vm_access(m.flags)) {
+            (Some(MemType::Normal), Some(MemAccess::ReadWrite)) => Ok(m.pa),
+            (Some(MemType::Normal), Some(MemAccess::ReadOnly)) if !write => Ok(m.pa),
+            (Some(MemType::Normal), _) => Err(BaoError::PermissionDenied),
+            _ => Err(BaoError::InvalidParam),
+        };
+    });
+    match res {
+        Err(BaoError::PermissionDenied) if write && myvm().addr_space.mem_dirty_log_fault(ipa) => {
+            guest_translate_ipa(ipa, write)
+        }
+        res => res,
+    }
+}
+
+/// Calls `f` with a hypervisor pointer for each page sized chunk of the guest
+/// buffer `[va, va + len)` and its offset in the buffer. Every page is
+/// translated before the first call, so a fault leaves the guest untouched.
+fn guest_access(
+    va: Vaddr,
+    len: usize,
+    write: bool,
+    translate: fn(Vaddr, bool) -> BaoResult<Paddr>,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) -> BaoResult<()> {
+    let mut chunks = Vec::new();
+    let mut off = 0;
+    while off < len {
+        let cur = va.checked_add(off as u64).ok_or(BaoError::InvalidParam)?;
+        let size = (PAGE_SIZE - cur as usize % PAGE_SIZE).min(len - off);
+        chunks.push((translate(cur, write)?, off, size));
+        off += size;
+    }
+
+    let addr_space = &mut mycpu().addr_space;
+    for (pa, off, size) in chunks {
+        let frame = PPages::new(align_down(pa as _, PAGE_SIZE) as _, 1);
+        let hyp_va =
+            addr_space.mem_alloc_map(SEC_HYP_PRIVATE, Some(&frame), None, 1, PTE_HYP_FLAGS)?;
+        f((hyp_va + pa % PAGE_SIZE as u64) as *mut u8, off, size);
+        addr_space.mem_unmap(hyp_va, 1, false);
+    }
+    Ok(())
+}
+
+/// Copies the guest buffer at `src` into `dst`.
+pub fn copy_from_guest(dst: &mut [u8], src: Vaddr) -> BaoResult<()> {
+    guest_access(src, dst.len(), false, guest_translate, |ptr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(ptr, dst[off..].as_mut_ptr(), size);
+    })
+}
+
+/// Copies `src` into the guest buffer at `dst`.
+pub fn copy_to_guest(dst: Vaddr, src: &[u8]) -> BaoResult<()> {
+    guest_access(dst, src.len(), true, guest_translate, |ptr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(src[off..].as_ptr(), ptr, size);
+    })
+}
+
+/// Copies the guest buffer at the intermediate physical address `src`, for
+/// tables guests hand to emulated devices by address, into `dst`.
+pub fn copy_from_guest_ipa(dst: &mut [u8], src: Vaddr) -> BaoResult<()> {
+    guest_access(src, dst.len(), false, guest_translate_ipa, |ptr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(ptr, dst[off..].as_mut_ptr(), size);
+    })
+}
+
+/// Copies `src` into the guest buffer at the intermediate physical address
+/// `dst`.
+pub fn copy_to_guest_ipa(dst: Vaddr, src: &[u8]) -> BaoResult<()> {
+    guest_access(dst, src.len(), true, guest_translate_ipa, |ptr, off, size| unsafe {
+        core::ptr::copy_nonoverlapping(src[off..].as_ptr(), ptr, size);
+    })
+}
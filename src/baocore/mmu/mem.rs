@@ -5,25 +5,33 @@ use tock_registers::interfaces::{Readable, Writeable};
 use crate::{
     arch::aarch64::{
         armv8_a::{
-            fences::{fence_sync, fence_sync_write},
+            fences::{fence_sync, fence_sync_write, isb},
             pagetable::{
-                PageTableArch, HYP_PT_DSCR, PTE, PTE_HYP_FLAGS, PTE_INVALID, PTE_RSW_MSK,
-                PTE_RSW_RSRV, PTE_TABLE, VM_PT_DSCR, PTE_VM_DEV_FLAGS,
+                PageTableArch, HYP_PT_DSCR, PTE, PTE_FLAGS_MSK, PTE_HYP_FLAGS, PTE_INVALID,
+                PTE_PAGE, PTE_RSW_MSK, PTE_RSW_RSRV, PTE_TABLE, PTE_TYPE_MSK, VM_PT_DSCR,
+                PTE_VM_DEV_FLAGS,
             },
+            tlb::{tlb_hyp_inv_va, tlb_vm_inv_ipa},
         },
         defs::PAGE_SIZE,
         sysregs::{arm_at_s12e1w, arm_at_s1e2w, PAR_F, PAR_PA_MSK},
     },
     baocore::{
         cpu::mycpu,
-        mem::{mem_alloc_page, mem_alloc_ppages, PPages},
+        iommu::iommu_vm_tlb_inv,
+        mem::{mem_alloc_page, mem_alloc_ppages, mem_free_ppages, mem_scrub_ppages, PPages},
         pagetable::{root_pt_addr, Pagetable},
-        types::{AsSecID, AsType, Asid, ColorMap, MemFlags, Paddr, Vaddr, MAX_VA},
+        types::{
+            AsSecID, AsType, Asid, ColorMap, MemFlags, MemOwner, MemScrub, Paddr, Vaddr, MAX_VA,
+        },
     },
     util::{clear_memory, is_aligned, num_pages, BaoError, BaoResult},
 };
 
-use super::sections::{mem_get_sections, SEC_HYP_PRIVATE, SEC_HYP_VM, SEC_HYP_GLOBAL};
+use super::{
+    dirty::DirtyLog,
+    sections::{mem_get_sections, SEC_HYP_GLOBAL, SEC_HYP_PRIVATE, SEC_HYP_VM},
+};
 
 pub const HYP_ASID: u64 = 0;
 
@@ -34,6 +42,9 @@ pub struct AddrSpace {
     pub colors: ColorMap,
     pub id: Asid,
     pub lock: Mutex<()>,
+    pub dirty_log: Option<DirtyLog>,
+    /// Page of a cpu's own address space where `mem_scratch_map` maps frames
+    pub scratch: Option<Vaddr>,
 }
 
 pub trait AsArchTrait {
@@ -52,6 +63,8 @@ impl AddrSpace {
         self.colors = colors;
         self.id = id;
         self.lock = Mutex::new(());
+        self.dirty_log = None;
+        self.scratch = None;
 
         if root_pt.is_none() {
             self.pt.dscr = match as_type {
@@ -66,6 +79,7 @@ impl AddrSpace {
                     _ => SEC_HYP_PRIVATE,
                 },
                 true,
+                self.pt_owner(),
             )
             .unwrap();
             unsafe {
@@ -90,6 +104,22 @@ impl AddrSpace {
         self.arch_init();
     }
 
+    /// Owner charged for the page tables of this address space.
+    fn pt_owner(&self) -> MemOwner {
+        match self.as_type {
+            AsType::AsVM => MemOwner::VMPageTable(self.id as _),
+            _ => MemOwner::HypPageTable,
+        }
+    }
+
+    /// Owner charged for frames allocated on demand when mapping.
+    fn data_owner(&self) -> MemOwner {
+        match self.as_type {
+            AsType::AsVM => MemOwner::VMRam(self.id as _),
+            _ => MemOwner::HypData,
+        }
+    }
+
     pub fn mem_find_sec(&self, va: Vaddr) -> Option<AsSecID> {
         let sections = mem_get_sections(self.as_type);
         sections
@@ -198,18 +228,17 @@ impl AddrSpace {
         if vpage.is_some() && !failed {
             let mut count = 0;
             let mut addr = vpage.unwrap();
-            let lvl = 0;
             while count < n {
-                let mut pte_ptr = None;
+                let mut found = None;
                 for lvl in 0..self.pt.dscr.lvls {
                     let p = self.pt.pt_get_pte(lvl, addr);
                     if !unsafe { *p }.is_valid() {
-                        pte_ptr = Some(p);
+                        found = Some((lvl, p));
                         break;
                     }
                 }
-                assert!(pte_ptr.is_some());
-                unsafe { *pte_ptr.unwrap() }.set_rsw(PTE_RSW_RSRV);
+                let (lvl, pte_ptr) = found.unwrap();
+                unsafe { (*pte_ptr).set_rsw(PTE_RSW_RSRV) };
                 let lvlsize = self.pt.pt_lvlsize(lvl);
                 addr += lvlsize as u64;
                 count += lvlsize / PAGE_SIZE;
@@ -228,7 +257,6 @@ impl AddrSpace {
     ) -> BaoResult<Vaddr> {
         assert!(is_aligned(va as usize, PAGE_SIZE));
         let mut count = 0;
-        let mut pte_ptr = None;
         let mut vaddr = va;
 
         let sec = self.mem_find_sec(vaddr);
@@ -252,43 +280,40 @@ impl AddrSpace {
 
         let mut paddr = ppages.as_ref().map_or(0, |ppages| ppages.base);
         while count < num_pages {
-            let mut lvl = 0;
-            for _ in 0..self.pt.dscr.lvls {
-                pte_ptr = Some(self.pt.pt_get_pte(lvl, vaddr));
-                if self.pt.dscr.lvl_term[lvl] {
-                    let pte = unsafe { *pte_ptr.unwrap() };
-                    if pte.is_mappable(&self.pt, lvl, num_pages - count, vaddr, paddr) {
-                        break;
-                    } else if !pte.is_valid() {
-                        self.alloc_pt_and_set(lvl, pte_ptr.unwrap(), vaddr);
-                    } else if !pte.is_table(&self.pt, lvl) {
-                        panic!("trying to override previous mapping");
-                    }
-                }
-                lvl += 1;
-            }
+            // Walk down to the highest level at which a block (or page) can be
+            // installed for the current va/pa pair, splitting on the way.
+            // When frames are allocated on demand only the va alignment
+            // matters, as the pools hand out naturally aligned blocks.
+            let pa_align = if ppages.is_some() { paddr } else { 0 };
+            let (lvl, mut pte_ptr) = self.mem_walk_mappable(vaddr, pa_align, num_pages - count);
 
-            let mut entry = self.pt.pt_getpteindex(pte_ptr.unwrap(), lvl);
+            let mut entry = self.pt.pt_getpteindex(pte_ptr, lvl);
             let nentries = self.pt.pt_nentries(lvl);
             let lvlsz = self.pt.pt_lvlsize(lvl);
 
             while entry < nentries && count < num_pages && num_pages - count >= lvlsz / PAGE_SIZE {
                 if ppages.is_none() {
-                    match mem_alloc_ppages(lvlsz / PAGE_SIZE, true) {
+                    match mem_alloc_ppages(lvlsz / PAGE_SIZE, true, self.data_owner()) {
                         Some(ppages) => {
                             paddr = ppages.base;
                         }
+                        None if lvl < self.pt.dscr.lvls - 1 => {
+                            // No contiguous block left in the pools, retry
+                            // with the next smaller granule.
+                            self.alloc_pt_and_set(lvl, pte_ptr, vaddr);
+                            break;
+                        }
                         None => return Err(BaoError::OutOfMemory),
                     }
                 }
                 unsafe {
-                    *(pte_ptr.unwrap()) = PTE::new(paddr, self.pt.page_type(lvl), flags);
+                    *pte_ptr = PTE::new(paddr, self.pt.page_type(lvl), flags);
                 }
                 vaddr += lvlsz as u64;
                 paddr += lvlsz as u64;
                 count += lvlsz / PAGE_SIZE;
                 entry += 1;
-                pte_ptr = Some(unsafe { pte_ptr.unwrap().add(1) });
+                pte_ptr = unsafe { pte_ptr.add(1) };
             }
         }
 
@@ -296,6 +321,30 @@ impl AddrSpace {
         Ok(va)
     }
 
+    /// Descends the page table for `vaddr` until reaching a level whose
+    /// descriptor can map `left` pages at `paddr` as a single block (or
+    /// page), allocating intermediate tables as needed.
+    fn mem_walk_mappable(&self, vaddr: Vaddr, paddr: Paddr, left: usize) -> (usize, *mut PTE) {
+        let last = self.pt.dscr.lvls - 1;
+        for lvl in 0..last {
+            let pte_ptr = self.pt.pt_get_pte(lvl, vaddr);
+            let pte = unsafe { *pte_ptr };
+            if self.pt.dscr.lvl_term[lvl] && pte.is_mappable(&self.pt, lvl, left, vaddr, paddr) {
+                return (lvl, pte_ptr);
+            } else if !pte.is_valid() {
+                self.alloc_pt_and_set(lvl, pte_ptr, vaddr);
+            } else if !pte.is_table(&self.pt, lvl) {
+                panic!("trying to override previous mapping");
+            }
+        }
+
+        let pte_ptr = self.pt.pt_get_pte(last, vaddr);
+        if unsafe { *pte_ptr }.is_valid() {
+            panic!("trying to override previous mapping");
+        }
+        (last, pte_ptr)
+    }
+
     pub fn mem_alloc_map(
         &mut self,
         section: AsSecID,
@@ -316,15 +365,25 @@ impl AddrSpace {
         pa: Paddr,
         at: Option<Vaddr>,
         num_pages: usize,
+    ) -> BaoResult<Vaddr> {
+        let flags = match self.as_type {
+            AsType::AsHyp | AsType::AsHypCry => PTE_HYP_FLAGS,
+            AsType::AsVM => PTE_VM_DEV_FLAGS,
+        };
+        self.mem_alloc_map_dev_flags(section, pa, at, num_pages, flags)
+    }
+
+    pub fn mem_alloc_map_dev_flags(
+        &mut self,
+        section: AsSecID,
+        pa: Paddr,
+        at: Option<Vaddr>,
+        num_pages: usize,
+        flags: MemFlags,
     ) -> BaoResult<Vaddr> {
         assert!(is_aligned(pa as _, PAGE_SIZE));
         match self.mem_alloc_vpage(section, at, num_pages) {
             Some(va) => {
-                let flags = match self.as_type {
-                    AsType::AsHyp => PTE_HYP_FLAGS,
-                    AsType::AsVM => PTE_VM_DEV_FLAGS,
-                    AsType::AsHypCry => todo!()
-                };
                 let ppages = PPages::new(pa, num_pages);
                 self.mem_map(va, Some(&ppages), num_pages, flags)
             }
@@ -346,8 +405,9 @@ impl AddrSpace {
                 pte = src_as.pt.pt_get_pte(lvl, src_va);
             }
             let lvl_size = src_as.pt.pt_lvlsize(lvl);
-            let pa = unsafe {*pte}.pa() + src_va % lvl_size as u64;
-            let size = lvl_size.min(size_left);
+            let lvl_off = src_va as usize % lvl_size;
+            let pa = unsafe {*pte}.pa() + lvl_off as u64;
+            let size = (lvl_size - lvl_off).min(size_left);
             let n = size / PAGE_SIZE;
             self.mem_map(dst_va, Some(&PPages::new(pa, n)), n, PTE_HYP_FLAGS).unwrap();
             dst_va += size as u64;
@@ -359,6 +419,123 @@ impl AddrSpace {
         base
     }
 
+    /// Removes the mappings for `num_pages` starting at `at`, splitting
+    /// blocks only partially covered. With `free_ppages` the frames are
+    /// zeroed and given back to the page pools.
+    pub fn mem_unmap(&mut self, at: Vaddr, num_pages: usize, free_ppages: bool) {
+        let top = at + (num_pages * PAGE_SIZE) as u64;
+        let sec = self
+            .mem_find_sec(at)
+            .and_then(|sec| mem_get_sections(self.as_type).sec.get(sec as usize))
+            .expect("mem_unmap: address outside every section");
+
+        let _sec_lock;
+        let _as_lock = self.lock.lock();
+        if sec.shared {
+            _sec_lock = sec.lock();
+        }
+
+        let mut vaddr = at;
+        let mut lvl = 0;
+        while vaddr < top {
+            let pte_ptr = self.pt.pt_get_pte(lvl, vaddr);
+            let pte = unsafe { *pte_ptr };
+            let lvlsz = self.pt.pt_lvlsize(lvl) as u64;
+            let blk_va = vaddr & !(lvlsz - 1);
+
+            if !pte.is_valid() {
+                vaddr = blk_va + lvlsz;
+                lvl = 0;
+            } else if pte.is_table(&self.pt, lvl) {
+                lvl += 1;
+            } else if blk_va < vaddr || blk_va + lvlsz > top {
+                self.mem_split_block(lvl, pte_ptr, blk_va);
+                lvl += 1;
+            } else {
+                if free_ppages {
+                    let ppages = PPages::new(pte.pa(), lvlsz as usize / PAGE_SIZE);
+                    match self.as_type {
+                        AsType::AsVM => mem_scrub_ppages(&ppages, MemScrub::Zero),
+                        _ => clear_memory(vaddr, lvlsz as _),
+                    }
+                    mem_free_ppages(&ppages, self.data_owner());
+                }
+                unsafe { *pte_ptr = PTE(PTE_INVALID) };
+                self.tlb_inv(vaddr);
+                vaddr += lvlsz;
+                lvl = 0;
+            }
+        }
+    }
+
+    /// Replaces the block at `lvl` by a table of next level entries mapping
+    /// the same frames with the same attributes. The table is filled before
+    /// it is installed, break-before-make, so no walker ever sees it partly
+    /// built or both translations at once.
+    pub fn mem_split_block(&self, lvl: usize, pte_ptr: *mut PTE, blk_va: Vaddr) {
+        let pte = unsafe { *pte_ptr };
+        let flags = pte.0 & PTE_FLAGS_MSK & !PTE_TYPE_MSK;
+        let n = num_pages(self.pt.pt_size(lvl + 1));
+        let pt_ppages =
+            mem_alloc_ppages(n, false, self.pt_owner()).expect("mem_split_block: no free mem");
+
+        let cpu_as = &mycpu().addr_space;
+        let pt = cpu_as.mem_scratch_map(pt_ppages.base) as *mut PTE;
+        let lvlsz = self.pt.pt_lvlsize(lvl + 1) as u64;
+        for i in 0..self.pt.pt_nentries(lvl + 1) {
+            unsafe {
+                *pt.add(i) = PTE::new(
+                    pte.pa() + i as u64 * lvlsz,
+                    self.pt.page_type(lvl + 1),
+                    flags,
+                );
+            }
+        }
+        cpu_as.mem_scratch_unmap();
+
+        unsafe { *pte_ptr = PTE(PTE_INVALID) };
+        fence_sync_write();
+        self.tlb_inv(blk_va);
+        unsafe { *pte_ptr = PTE::new(pt_ppages.base, PTE_TABLE, PTE_HYP_FLAGS) };
+        fence_sync_write();
+    }
+
+    /// Reserves the page `mem_scratch_map` uses, on the cpu's own address
+    /// space once the page pools are up.
+    pub fn mem_scratch_init(&mut self) {
+        self.scratch = self.mem_alloc_vpage(SEC_HYP_PRIVATE, None, 1);
+        assert!(self.scratch.is_some(), "mem_scratch_init: no free va");
+    }
+
+    /// Maps the frame at `pa` at the cpu's scratch page. No lock is taken,
+    /// so it works while any address space, this one included, is locked.
+    /// Only one frame can be mapped at a time.
+    pub fn mem_scratch_map(&self, pa: Paddr) -> Vaddr {
+        let va = self.scratch.unwrap();
+        let pte_ptr = self.pt.pt_get_pte(self.pt.dscr.lvls - 1, va);
+        unsafe { *pte_ptr = PTE::new(pa, PTE_PAGE, PTE_HYP_FLAGS) };
+        fence_sync();
+        isb();
+        va
+    }
+
+    pub fn mem_scratch_unmap(&self) {
+        let va = self.scratch.unwrap();
+        let pte_ptr = self.pt.pt_get_pte(self.pt.dscr.lvls - 1, va);
+        unsafe { *pte_ptr = PTE(PTE_RSW_RSRV) };
+        tlb_hyp_inv_va(va);
+    }
+
+    fn tlb_inv(&self, va: Vaddr) {
+        match self.as_type {
+            AsType::AsVM => {
+                tlb_vm_inv_ipa(va);
+                iommu_vm_tlb_inv(self.id as _);
+            }
+            _ => tlb_hyp_inv_va(va),
+        }
+    }
+
     pub fn mem_translate(&self, va: Vaddr) -> Option<Paddr> {
         let par_saved = PAR_EL1.get();
         let par = match self.as_type {
@@ -378,7 +555,7 @@ impl AddrSpace {
         if pt_size > 1 {
             unimplemented!("alloc_pt_and_set: pt is too big")
         }
-        match mem_alloc_ppages(pt_size, false) {
+        match mem_alloc_ppages(pt_size, false, self.pt_owner()) {
             Some(ppages) => {
                 let pte_dflt_val = PTE_INVALID | (unsafe { *pte_ptr }.0 & PTE_RSW_MSK);
                 unsafe { *pte_ptr = PTE::new(ppages.base, PTE_TABLE, PTE_HYP_FLAGS) }
@@ -1,3 +1,6 @@
+pub mod dirty;
+pub mod guest;
 pub mod mem;
 pub mod sections;
 pub mod vmm;
+pub mod walk;
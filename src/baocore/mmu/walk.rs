@@ -0,0 +1,150 @@
+use crate::{
+    arch::aarch64::armv8_a::pagetable::{
+        pte_vm_access, pte_vm_mem_type, PTE_FLAGS_MSK, PTE_RSW_MSK, PTE_TYPE_MSK, PTE_XN,
+    },
+    baocore::{
+        cpu::mycpu,
+        types::{AsType, MemFlags, Paddr, Vaddr},
+    },
+    println, pt_cpu_rec_index, pt_vm_rec_index,
+};
+
+use super::mem::AddrSpace;
+
+/// A run of contiguous mappings sharing the same level and attributes.
+#[derive(Debug, Clone, Copy)]
+pub struct MemMapping {
+    pub va: Vaddr,
+    pub pa: Paddr,
+    pub size: usize,
+    pub lvl: usize,
+    pub flags: MemFlags,
+}
+
+impl MemMapping {
+    pub fn va_end(&self) -> Vaddr {
+        self.va + self.size as u64
+    }
+
+    pub fn pa_end(&self) -> Paddr {
+        self.pa + self.size as u64
+    }
+
+    fn extend(&mut self, next: &MemMapping) -> bool {
+        let contiguous = self.va_end() == next.va && self.pa_end() == next.pa;
+        if contiguous && self.lvl == next.lvl && self.flags == next.flags {
+            self.size += next.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl AddrSpace {
+    /// Calls `f` for every run of valid leaf entries, in increasing va order.
+    /// The page tables are reached through the recursive mapping, so a vm's
+    /// tables can only be walked from one of its own cpus, each of which
+    /// maps them in `vm_init`.
+    pub fn mem_walk(&self, f: impl FnMut(&MemMapping)) {
+        self.mem_walk_range(0, Vaddr::MAX, f);
+    }
+
+    /// Same as `mem_walk`, with runs clipped to `[beg, last]`.
+    pub fn mem_walk_range(&self, beg: Vaddr, last: Vaddr, mut f: impl FnMut(&MemMapping)) {
+        assert!(
+            self.pt.pt_is_reachable(),
+            "mem_walk: address space {} is not mapped on cpu {}",
+            self.id,
+            mycpu().id
+        );
+        let mut run: Option<MemMapping> = None;
+        self.mem_walk_lvl(0, 0, beg, last, &mut |m| {
+            if !run.as_mut().map_or(false, |r| r.extend(&m)) {
+                if let Some(r) = run.replace(m) {
+                    f(&r);
+                }
+            }
+        });
+        if let Some(r) = run {
+            f(&r);
+        }
+    }
+
+    pub fn mem_is_mapped(&self, va: Vaddr) -> bool {
+        let mut mapped = false;
+        self.mem_walk_range(va, va, |_| mapped = true);
+        mapped
+    }
+
+    fn mem_walk_lvl(
+        &self,
+        lvl: usize,
+        base: Vaddr,
+        beg: Vaddr,
+        last: Vaddr,
+        f: &mut dyn FnMut(MemMapping),
+    ) {
+        let pt = self.pt.pt_get(lvl, base);
+        let lvlsz = self.pt.pt_lvlsize(lvl);
+        let nentries = self.pt.pt_nentries(lvl);
+
+        for i in 0..nentries {
+            // Skip the recursive slots, they would walk the tables themselves.
+            if lvl == 0
+                && self.as_type != AsType::AsVM
+                && (i == pt_cpu_rec_index!() || i == pt_vm_rec_index!())
+            {
+                continue;
+            }
+            let va = base + (i * lvlsz) as u64;
+            let va_last = va + (lvlsz - 1) as u64;
+            if va_last < beg || va > last {
+                continue;
+            }
+            let pte = unsafe { *pt.add(i) };
+            if !pte.is_valid() {
+                continue;
+            }
+            if pte.is_table(&self.pt, lvl) {
+                self.mem_walk_lvl(lvl + 1, va, beg, last, f);
+            } else if pte.is_page(&self.pt, lvl) {
+                let start = va.max(beg);
+                f(MemMapping {
+                    va: start,
+                    pa: pte.pa() + (start - va),
+                    size: (va_last.min(last) - start) as usize + 1,
+                    lvl,
+                    flags: pte.0 & PTE_FLAGS_MSK & !(PTE_TYPE_MSK | PTE_RSW_MSK),
+                });
+            }
+        }
+    }
+
+    pub fn mem_dump(&self) {
+        println!("address space {} ({:?}) mappings:", self.id, self.as_type);
+        self.mem_walk(|m| {
+            if self.as_type == AsType::AsVM {
+                println!(
+                    "  {:#014x}-{:#014x} -> {:#014x} L{} {:?} {:?} {}",
+                    m.va,
+                    m.va_end(),
+                    m.pa,
+                    m.lvl,
+                    pte_vm_mem_type(m.flags),
+                    pte_vm_access(m.flags),
+                    if m.flags & PTE_XN != 0 { "XN" } else { "X" },
+                );
+            } else {
+                println!(
+                    "  {:#014x}-{:#014x} -> {:#014x} L{} flags {:#x}",
+                    m.va,
+                    m.va_end(),
+                    m.pa,
+                    m.lvl,
+                    m.flags,
+                );
+            }
+        });
+    }
+}
@@ -10,6 +10,8 @@ pub mod vm;
 pub mod vmm;
 pub mod emul;
 pub mod ipc;
+pub mod hypercall;
+pub mod iommu;
 
 #[macro_use]
 pub mod console;
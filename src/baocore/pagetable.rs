@@ -2,9 +2,12 @@ use core::mem::size_of;
 
 use crate::{
     arch::aarch64::{
-        armv8_a::pagetable::{
-            pte_mask, PageTableArch, PageTableDescriptor, PTE, PTE_HYP_FLAGS, PTE_PAGE, PTE_SIZE,
-            PTE_SUPERPAGE, PTE_TABLE,
+        armv8_a::{
+            fences::{fence_sync, isb},
+            pagetable::{
+                pte_mask, PageTableArch, PageTableDescriptor, PTE, PTE_HYP_FLAGS, PTE_PAGE,
+                PTE_SIZE, PTE_SUPERPAGE, PTE_TABLE,
+            },
         },
         defs::{BAO_CPU_BASE, PAGE_SIZE},
     },
@@ -29,9 +32,6 @@ impl Pagetable {
     }
 
     pub fn pt_set_recursive(&mut self, index: usize) {
-        let root_pt_pa = mycpu().addr_space.mem_translate(self.root).unwrap();
-        let hyp_pte = (mycpu().addr_space.pt.root + (index * PTE_SIZE) as u64) as *mut PTE;
-        unsafe { *hyp_pte = PTE::new(root_pt_pa, PTE_TABLE, PTE_HYP_FLAGS) };
         self.arch.rec_index = index;
         self.arch.rec_mask = 0;
         let cpu_rec_index = mycpu().addr_space.pt.arch.rec_index;
@@ -39,6 +39,26 @@ impl Pagetable {
             let lvl_off = self.dscr.lvl_off[i];
             self.arch.rec_mask |= (cpu_rec_index as u64) << lvl_off;
         }
+        self.pt_map_recursive();
+    }
+
+    /// Points the current cpu's recursive slot at this table. Every cpu that
+    /// walks the table needs it, not only the one that created it.
+    pub fn pt_map_recursive(&self) {
+        let root_pt_pa = mycpu().addr_space.mem_translate(self.root).unwrap();
+        unsafe { *self.pt_rec_slot() = PTE::new(root_pt_pa, PTE_TABLE, PTE_HYP_FLAGS) };
+        fence_sync();
+        isb();
+    }
+
+    /// Whether the current cpu reaches this table through its recursive slot.
+    pub fn pt_is_reachable(&self) -> bool {
+        let pte = unsafe { *self.pt_rec_slot() };
+        pte.is_valid() && mycpu().addr_space.mem_translate(self.root) == Some(pte.pa())
+    }
+
+    fn pt_rec_slot(&self) -> *mut PTE {
+        (mycpu().addr_space.pt.root + (self.arch.rec_index * PTE_SIZE) as u64) as *mut PTE
     }
 
     pub fn pt_get_pte(&self, lvl: usize, va: Vaddr) -> *mut PTE {
@@ -9,6 +9,7 @@ pub type Asid = u64;
 pub type MemFlags = u64;
 pub type AsSecID = u64;
 pub type IrqID = u32;
+pub type StreamID = u32;
 
 pub const MAX_VA: Vaddr = Vaddr::MAX - 1;
 
@@ -19,3 +20,50 @@ pub enum AsType {
     AsVM,
     AsHypCry,
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemAccess {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemType {
+    /// Normal memory, inner/outer write-back cacheable
+    Normal,
+    /// Normal memory, inner/outer non-cacheable
+    NormalNonCacheable,
+    /// Device memory, strictly ordered: no gathering, reordering or early
+    /// write acknowledgement
+    DevicenGnRnE,
+    DevicenGnRE,
+    DeviceGRE,
+}
+
+/// Who a set of physical frames was handed to, used for memory accounting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemOwner {
+    HypImage,
+    CpuArea,
+    HypPageTable,
+    HypHeap,
+    /// Any other hypervisor data, e.g. pool bitmaps or vm structures
+    HypData,
+    SharedMem,
+    /// Carve-outs the platform marks as never to be handed out
+    Reserved,
+    VMPageTable(usize),
+    VMRam(usize),
+}
+
+/// How memory is cleaned before being handed to a vm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemScrub {
+    /// Leave the contents as they are, to save boot time
+    Skip,
+    Zero,
+    /// Fill with a recognizable pattern, to catch guests relying on
+    /// uninitialized memory
+    Pattern(u64),
+}
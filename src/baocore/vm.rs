@@ -4,27 +4,35 @@ use spin::{Mutex, RwLock};
 use crate::{
     arch::aarch64::{
         armv8_a::{
-            pagetable::{PTE, PTE_HYP_FLAGS, PTE_VM_FLAGS},
-            vm::ArchVMPlatform,
+            pagetable::{pte_vm_flags, PTE, PTE_HYP_FLAGS, PTE_HYP_RO_FLAGS},
+            vm::{vcpu_arch_inject_irq, vm_arch_inject_irq, ArchVMPlatform},
+        },
+        defs::PAGE_SIZE,
+        gic::{
+            vgic::{vgic_set_hw, VGicPriv},
+            GichState,
         },
-        gic::vgic::{vgic_set_hw, VGicPriv},
         vm::{ArchRegs, PsciCtx, PsciState, VCpuArch, VMArch},
     },
     config::VMConfig,
     println,
-    util::{num_pages, range_in_range},
+    util::{align_down, align_up, is_aligned, num_pages, range_in_range, BaoResult},
 };
 
 use super::{
     cpu::{mycpu, SyncToken},
     emul::{EmulHandler, EmulMem, EmulReg},
     ipc::{IPC, SHMEM_LIST},
-    mem::PPages,
+    mem::{mem_scrub_ppages, PPages},
     mmu::{
         mem::AddrSpace,
-        sections::{SEC_HYP_GLOBAL, SEC_HYP_PRIVATE, SEC_VM_ANY},
+        walk::MemMapping,
+        sections::{SEC_HYP_PRIVATE, SEC_VM_ANY},
+    },
+    types::{
+        AsType, CpuID, CpuMap, IrqID, MemAccess, MemFlags, MemScrub, MemType, Paddr, StreamID,
+        VCpuID, Vaddr,
     },
-    types::{AsType, CpuID, CpuMap, IrqID, Paddr, VCpuID, Vaddr},
 };
 
 pub struct VMMemRegion {
@@ -32,13 +40,42 @@ pub struct VMMemRegion {
     pub size: usize,
     pub place_phys: bool,
     pub phys: Paddr,
+    pub access: MemAccess,
+    pub mem_type: MemType,
+    /// Forbid the vm from executing out of this region
+    pub xn: bool,
+    /// Cleaning applied to the region's frames, except for the vm image,
+    /// before the vm starts
+    pub scrub: MemScrub,
+}
+
+impl VMMemRegion {
+    pub fn flags(&self) -> MemFlags {
+        pte_vm_flags(self.mem_type, self.access, self.xn)
+    }
 }
 
 pub struct VMDeviceRegion {
     pub va: Option<Vaddr>,
     pub pa: Paddr,
     pub size: usize,
+    pub mem_type: MemType,
+    pub access: MemAccess,
+    /// Allow devices that do not cover whole pages. Whatever shares those
+    /// pages with the device becomes accessible to the vm as well.
+    pub partial_page: bool,
     pub interrupts: Vec<IrqID>,
+    /// Ids the vm sees `interrupts` under, in the same order. `None` exposes
+    /// them with their hardware ids.
+    pub virt_interrupts: Option<Vec<IrqID>>,
+    /// DMA from these streams is translated by the vm's stage 2 tables
+    pub stream_ids: Vec<StreamID>,
+}
+
+impl VMDeviceRegion {
+    pub fn flags(&self) -> MemFlags {
+        pte_vm_flags(self.mem_type, self.access, true)
+    }
 }
 
 pub struct VMPlatform {
@@ -131,6 +168,18 @@ pub fn myvm() -> &'static mut VM {
     unsafe { &mut *(*(mycpu().vcpu)).vm }
 }
 
+/// Injects the virtual interrupt `id`, which has no hardware interrupt
+/// behind it, into `vcpu`. It may run on another cpu.
+pub fn vcpu_inject_irq(vcpu: &'static mut VCpu, id: IrqID) -> BaoResult<()> {
+    vcpu_arch_inject_irq(vcpu, id)
+}
+
+/// Same as `vcpu_inject_irq` for a shared interrupt, delivered to the vcpu
+/// the guest routed it to.
+pub fn vm_inject_irq(vm: &'static mut VM, id: IrqID) -> BaoResult<()> {
+    vm_arch_inject_irq(vm, id)
+}
+
 pub trait VMArchTrait {
     fn arch_init(&mut self, config: &VMConfig, master: bool);
 }
@@ -186,6 +235,7 @@ impl VM {
                     state: PsciState::Off,
                 }),
                 vgic_priv: VGicPriv::new(mycpu().id),
+                vgic_state: GichState::new(),
             },
             regs: ArchRegs {
                 x: [0; 31],
@@ -214,17 +264,60 @@ impl VM {
 
     fn map_img_rgn(&mut self, config: &VMConfig, reg: &VMMemRegion) {
         if reg.place_phys {
-            self.copy_img_to_rgn(config, reg);
             self.map_mem_region(reg);
+            self.copy_img_to_rgn(config, reg);
         } else if config.inplace {
-            todo!("config.inplace");
-            // self.map_img_rgn_inplace(config, reg);
+            self.map_img_rgn_inplace(config, reg);
         } else {
             self.map_mem_region(reg);
             self.install_image(config);
         }
     }
 
+    fn map_img_rgn_inplace(&mut self, config: &VMConfig, reg: &VMMemRegion) {
+        let img_base = config.base_addr;
+        let n_img = num_pages(config.size);
+        let img_end = img_base + (n_img * PAGE_SIZE) as u64;
+        // mem region pages before and after the img
+        let n_before = num_pages((img_base - reg.base) as _);
+        let n_aft = num_pages((reg.base + reg.size as u64).saturating_sub(img_end) as _);
+
+        assert!(is_aligned(config.load_addr as _, PAGE_SIZE));
+        assert!(is_aligned(img_base as _, PAGE_SIZE));
+
+        // The image frames were reserved at boot, with the hypervisor image
+        // or, for separately loaded images, when the config was set up.
+        let img_ppages = PPages::new(config.load_addr, n_img);
+
+        let flags = reg.flags();
+        self.map_mem_range(reg.base, None, n_before, flags);
+        self.map_mem_range(img_base, Some(&img_ppages), n_img, flags);
+        self.map_mem_range(img_end, None, n_aft, flags);
+        self.scrub_mem_range(reg.base, n_before, reg.scrub);
+        self.scrub_mem_range(img_end, n_aft, reg.scrub);
+    }
+
+    fn map_mem_range(&mut self, base: Vaddr, ppages: Option<&PPages>, n: usize, flags: MemFlags) {
+        if n == 0 {
+            return;
+        }
+        let va = self
+            .addr_space
+            .mem_alloc_map(SEC_VM_ANY, ppages, Some(base), n, flags)
+            .unwrap();
+        assert_eq!(va, base);
+    }
+
+    fn scrub_mem_range(&self, base: Vaddr, n: usize, scrub: MemScrub) {
+        if n == 0 || scrub == MemScrub::Skip {
+            return;
+        }
+        let last = base + (n * PAGE_SIZE) as u64 - 1;
+        self.addr_space.mem_walk_range(base, last, |m| {
+            mem_scrub_ppages(&PPages::new(m.pa, m.size / PAGE_SIZE), scrub);
+        });
+    }
+
     fn copy_img_to_rgn(&mut self, config: &VMConfig, reg: &VMMemRegion) {
         // Map original image address
         let n_img = num_pages(config.size);
@@ -236,7 +329,7 @@ impl VM {
                 Some(&src_pa_img),
                 None,
                 n_img,
-                PTE_HYP_FLAGS,
+                PTE_HYP_RO_FLAGS,
             )
             .unwrap();
         // Map new address
@@ -251,8 +344,8 @@ impl VM {
             core::ptr::copy_nonoverlapping(src_va as *const u8, dst_va as *mut u8, config.size);
         }
         // todo: cache_flush_range(dst_va, n_img * PAGE_SIZE);
-        // mem_unmap(&cpu().as_, src_va, n_img, false);
-        // mem_unmap(&cpu().as_, dst_va, n_img, false);
+        mycpu().addr_space.mem_unmap(src_va, n_img, false);
+        mycpu().addr_space.mem_unmap(dst_va, n_img, false);
     }
 
     fn map_mem_region(&mut self, reg: &VMMemRegion) {
@@ -265,12 +358,8 @@ impl VM {
             None
         };
 
-        let va = self
-            .addr_space
-            .mem_alloc_map(SEC_VM_ANY, ppages.as_ref(), Some(reg.base), n, PTE_VM_FLAGS)
-            .unwrap();
-
-        assert_eq!(va, reg.base);
+        self.map_mem_range(reg.base, ppages.as_ref(), n, reg.flags());
+        self.scrub_mem_range(reg.base, n, reg.scrub);
     }
 
     fn install_image(&self, config: &VMConfig) {
@@ -279,11 +368,11 @@ impl VM {
         let src_va = mycpu()
             .addr_space
             .mem_alloc_map(
-                SEC_HYP_GLOBAL,
+                SEC_HYP_PRIVATE,
                 Some(&img_ppages),
                 None,
                 img_num_pages,
-                PTE_HYP_FLAGS,
+                PTE_HYP_RO_FLAGS,
             )
             .unwrap();
         let dst_va =
@@ -293,19 +382,53 @@ impl VM {
         unsafe {
             core::ptr::copy_nonoverlapping(src_va as *const u8, dst_va as *mut u8, config.size);
         }
+        // The guest image is no business of the hypervisor once installed
+        mycpu().addr_space.mem_unmap(src_va, img_num_pages, false);
+        mycpu().addr_space.mem_unmap(dst_va, img_num_pages, false);
     }
 
     fn init_dev(&mut self, config: &VMConfig) {
         for dev in config.vm_platform.devs.iter() {
-            if dev.va.is_some() {
+            if let Some(dev_va) = dev.va {
+                let pa_off = dev.pa as usize % PAGE_SIZE;
+                let whole_pages = pa_off == 0 && is_aligned(dev.size, PAGE_SIZE);
+                if !whole_pages && !dev.partial_page {
+                    panic!(
+                        "vm {}: device {:#x} (size {:#x}) does not cover whole pages",
+                        self.id, dev.pa, dev.size
+                    );
+                }
+                if dev_va as usize % PAGE_SIZE != pa_off {
+                    panic!(
+                        "vm {}: device {:#x} mapped at {:#x} with a different page offset",
+                        self.id, dev.pa, dev_va
+                    );
+                }
+
                 let va = self
                     .addr_space
-                    .mem_alloc_map_dev(SEC_VM_ANY, dev.pa, dev.va, num_pages(dev.size))
+                    .mem_alloc_map_dev_flags(
+                        SEC_VM_ANY,
+                        align_down(dev.pa as _, PAGE_SIZE) as _,
+                        Some(align_down(dev_va as _, PAGE_SIZE) as _),
+                        num_pages(pa_off + dev.size),
+                        dev.flags(),
+                    )
                     .unwrap();
-                assert_eq!(va, dev.va.unwrap());
+                assert_eq!(va, align_down(dev_va as _, PAGE_SIZE) as Vaddr);
             };
-            for intr in dev.interrupts.iter() {
-                self.interrupt_assign(*intr);
+            let virt_interrupts = dev.virt_interrupts.as_ref().unwrap_or(&dev.interrupts);
+            if virt_interrupts.len() != dev.interrupts.len() {
+                panic!(
+                    "vm {}: device {:#x} has {} interrupts but {} virtual ids",
+                    self.id,
+                    dev.pa,
+                    dev.interrupts.len(),
+                    virt_interrupts.len()
+                );
+            }
+            for (intr, virt_intr) in dev.interrupts.iter().zip(virt_interrupts.iter()) {
+                self.interrupt_assign(*intr, *virt_intr);
             }
         }
     }
@@ -330,12 +453,75 @@ impl VM {
                 size: size as _,
                 place_phys: true,
                 phys: shmem.phys.unwrap(),
+                access: MemAccess::ReadWrite,
+                mem_type: MemType::Normal,
+                xn: false,
+                // Shared with other vms, it was cleaned once when allocated
+                scrub: MemScrub::Skip,
             };
 
             self.map_mem_region(&reg);
         }
     }
 
+    /// Walks the vm's stage-2 tables and reports mappings outside every
+    /// configured region and frames mapped at more than one ipa outside of
+    /// shared memory. Returns the number of problems found.
+    pub fn check_mappings(&self, config: &VMConfig) -> usize {
+        let platform = &config.vm_platform;
+        let in_ipc = |m: &MemMapping| {
+            platform
+                .ipcs
+                .iter()
+                .any(|ipc| range_in_range(m.va as _, m.size, ipc.base as _, ipc.size as _))
+        };
+        let configured = |m: &MemMapping| {
+            platform
+                .vm_regions
+                .iter()
+                .any(|reg| range_in_range(m.va as _, m.size, reg.base as _, reg.size))
+                || platform.devs.iter().any(|dev| {
+                    dev.va.map_or(false, |va| {
+                        let base = align_down(va as _, PAGE_SIZE);
+                        let size = align_up(va as usize + dev.size, PAGE_SIZE) - base;
+                        range_in_range(m.va as _, m.size, base, size)
+                    })
+                })
+                || in_ipc(m)
+        };
+
+        let mut errors = 0;
+        let mut mappings = Vec::new();
+        self.addr_space.mem_walk(|m| mappings.push(*m));
+
+        for m in mappings.iter().filter(|m| !configured(m)) {
+            println!(
+                "vm {}: ipa {:#x}-{:#x} is mapped outside the configured regions",
+                self.id,
+                m.va,
+                m.va_end()
+            );
+            errors += 1;
+        }
+
+        mappings.sort_unstable_by_key(|m| m.pa);
+        for pair in mappings.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if b.pa < a.pa_end() && !(in_ipc(a) && in_ipc(b)) {
+                println!(
+                    "vm {}: ipa {:#x} and {:#x} both map pa {:#x}",
+                    self.id,
+                    a.va + (b.pa - a.pa),
+                    b.va,
+                    b.pa
+                );
+                errors += 1;
+            }
+        }
+
+        errors
+    }
+
     pub fn emul_get_mem(&self, addr: Vaddr) -> Option<EmulHandler> {
         for emu in self.emul_mem_list.iter() {
             println!("addr{:#x}, emu.va_base{:#x?} emu.size{:#x?}", addr, emu.va_base, emu.size);
@@ -354,9 +540,9 @@ impl VM {
         self.emul_reg_list.push(emu);
     }
 
-    fn interrupt_assign(&mut self, id: IrqID) {
+    fn interrupt_assign(&mut self, id: IrqID, virt_id: IrqID) {
         // todo: check int_id conflict?
-        vgic_set_hw(self, id);
+        vgic_set_hw(self, virt_id, id);
     }
 }
 
@@ -374,6 +560,10 @@ pub fn vm_init(vm_alloc: &VMAllocation, config: &VMConfig, master: bool, vm_id:
     }
     vm.cpu_init();
     vm.sync_token.sync_barrier();
+    if !master {
+        // Walking the vm's stage 2 tables goes through the recursive slot
+        vm.addr_space.pt.pt_map_recursive();
+    }
 
     vm.vcpu_init(config);
     vm.sync_token.sync_barrier();
@@ -385,6 +575,12 @@ pub fn vm_init(vm_alloc: &VMAllocation, config: &VMConfig, master: bool, vm_id:
         vm.init_mem_regions(config);
         vm.init_dev(config);
         vm.init_ipc(config);
+        vm.iommu_init(config);
+        #[cfg(feature = "pt_dump")]
+        {
+            vm.addr_space.mem_dump();
+            vm.check_mappings(config);
+        }
     }
 
     vm.sync_token.sync_and_clear_msg();
@@ -11,13 +11,13 @@ use crate::{
 
 use super::{
     cpu::{mycpu, SyncToken, CPU_SYNC_TOKEN},
-    ipc,
-    mem::mem_alloc_page,
+    iommu, ipc,
+    mem::{mem_alloc_page, mem_report},
     mmu::{
         sections::SEC_HYP_VM,
         vmm::{vmm_get_vm_install_info, vmm_vm_install},
     },
-    types::CpuMap,
+    types::{CpuMap, IrqID, MemOwner},
     vm::{vm_init, VCpu, VMAllocation, VMInstallInfo, VM},
 };
 
@@ -69,7 +69,13 @@ fn vmm_alloc_vm(config: &VMConfig) -> VMAllocation {
     let vcpu_size = config.vm_platform.cpu_num * core::mem::size_of::<VCpu>();
     let total_size = align_up(vcpus_offset + vcpu_size, PAGE_SIZE);
 
-    let allocation = mem_alloc_page(num_pages(total_size), SEC_HYP_VM, false).unwrap();
+    let allocation = mem_alloc_page(
+        num_pages(total_size),
+        SEC_HYP_VM,
+        false,
+        MemOwner::HypData,
+    )
+    .unwrap();
     unsafe {
         *(allocation as *mut VM) = VM {
             vcpus: null_mut(),
@@ -112,21 +118,40 @@ fn vmm_alloc_install_vm(vm_id: usize, master: bool) -> VMAllocation {
     }
 }
 
+/// The vm the hardware interrupt `int_id` is passed through to. Every vm is
+/// set up before any of them runs.
+pub fn vmm_intr_owner(int_id: IrqID) -> Option<&'static mut VM> {
+    VM_ASSIGN.iter().find_map(|vm_assign| {
+        let vm = unsafe { &mut *(vm_assign.read().vm_alloc?.base as *mut VM) };
+        vm.arch.vgicd.hw_virt_ids.contains_key(&int_id).then_some(vm)
+    })
+}
+
 pub fn init() {
     vmm_arch_init();
+    if mycpu().is_master() {
+        iommu::init();
+    }
     ipc::init();
     CPU_SYNC_TOKEN.sync_barrier();
 
     let (master, vm_id) = vmm_assign_vcpu();
+    if let Some(vm_id) = vm_id {
+        let vm_alloc = vmm_alloc_install_vm(vm_id, master);
+        let cfg = CONFIG.read();
+        vm_init(&vm_alloc, &cfg.vmlist[vm_id], master, vm_id);
+    }
+
+    // Cpus without a vm wait here too, the vms are all set up past it
+    CPU_SYNC_TOKEN.sync_barrier();
+    if mycpu().is_master() {
+        mem_report();
+    }
+
     match vm_id {
-        Some(vm_id) => {
-            let vm_alloc = vmm_alloc_install_vm(vm_id, master);
-            let cfg = CONFIG.read();
-            vm_init(&vm_alloc, &cfg.vmlist[vm_id], master, vm_id);
-            unsafe {
-                (*mycpu().vcpu).run();
-            }
-        }
+        Some(_) => unsafe {
+            (*mycpu().vcpu).run();
+        },
         _ => todo!("cpu_idle"),
     }
 }
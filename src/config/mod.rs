@@ -1,11 +1,20 @@
 use alloc::vec::Vec;
 
 use crate::{
-    arch::aarch64::defs::BAO_VAS_BASE,
+    arch::aarch64::{
+        armv8_a::pagetable::PTE_HYP_RO_FLAGS,
+        defs::{BAO_VAS_BASE, PAGE_SIZE},
+    },
     baocore::{
-        types::{Paddr, Vaddr},
-        vm::VMPlatform, ipc::SharedMemConfig,
+        cpu::mycpu,
+        ipc::SharedMemConfig,
+        mem::{mem_reserve_ppages, PPages},
+        mmu::sections::SEC_HYP_PRIVATE,
+        types::{MemOwner, Paddr, Vaddr},
+        vm::VMPlatform,
     },
+    println,
+    util::{is_aligned, num_pages, range_in_range, BaoError, BaoResult},
 };
 
 use self::platform::qemu_aarch64_virt::linux_freertos::CONFIG;
@@ -32,11 +41,25 @@ pub struct VMConfig {
     pub load_addr: Paddr,
     pub size: usize,
     pub separately_loaded: bool,
+    /// A separately loaded image is preceded by a `VMImageHeader` page at
+    /// `load_addr`. Its size is taken from the header when `size` is 0.
+    pub image_header: bool,
     pub inplace: bool,
     pub entry: Vaddr,
     pub vm_platform: VMPlatform,
 }
 
+pub const VM_IMAGE_MAGIC: u64 = u64::from_le_bytes(*b"BAOVMIMG");
+
+/// Header placed by the image packer in the page right before a separately
+/// loaded vm image.
+#[repr(C)]
+pub struct VMImageHeader {
+    pub magic: u64,
+    /// Size of the image in bytes, not counting the header page.
+    pub size: u64,
+}
+
 pub struct Config {
     pub shared_mem: Vec<SharedMemConfig>,
     pub vmlist: Vec<VMConfig>,
@@ -51,6 +74,88 @@ fn adjust_vm_image_addr(load_addr: Paddr) {
     }
 }
 
+fn read_vm_image_header(hdr_addr: Paddr) -> VMImageHeader {
+    let hdr_va = mycpu()
+        .addr_space
+        .mem_alloc_map(
+            SEC_HYP_PRIVATE,
+            Some(&PPages::new(hdr_addr, 1)),
+            None,
+            1,
+            PTE_HYP_RO_FLAGS,
+        )
+        .unwrap();
+    let hdr = unsafe { core::ptr::read_volatile(hdr_va as *const VMImageHeader) };
+    mycpu().addr_space.mem_unmap(hdr_va, 1, false);
+    hdr
+}
+
+fn setup_separate_vm_image(vm_id: usize, vm_config: &mut VMConfig) -> BaoResult<()> {
+    if !is_aligned(vm_config.load_addr as _, PAGE_SIZE) {
+        return Err(BaoError::InvalidParam);
+    }
+
+    if vm_config.image_header {
+        let hdr = read_vm_image_header(vm_config.load_addr);
+        if hdr.magic != VM_IMAGE_MAGIC {
+            println!(
+                "vm image at {:#x}: bad magic {:#x}",
+                vm_config.load_addr, hdr.magic
+            );
+            return Err(BaoError::NotFound);
+        }
+        if vm_config.size != 0 && vm_config.size != hdr.size as usize {
+            println!(
+                "vm image at {:#x}: size {:#x} does not match config ({:#x})",
+                vm_config.load_addr, hdr.size, vm_config.size
+            );
+            return Err(BaoError::InvalidParam);
+        }
+        vm_config.load_addr += PAGE_SIZE as u64;
+        vm_config.size = hdr.size as _;
+    }
+
+    if vm_config.size == 0 {
+        return Err(BaoError::InvalidParam);
+    }
+
+    let fits_rgn = vm_config.vm_platform.vm_regions.iter().any(|reg| {
+        range_in_range(
+            vm_config.base_addr as _,
+            vm_config.size,
+            reg.base as _,
+            reg.size,
+        )
+    });
+    if !fits_rgn {
+        return Err(BaoError::InvalidParam);
+    }
+
+    // The header page stays reserved along with the image.
+    let hdr_pages = vm_config.image_header as usize;
+    let img_ppages = PPages::new(
+        vm_config.load_addr - (hdr_pages * PAGE_SIZE) as u64,
+        hdr_pages + num_pages(vm_config.size),
+    );
+    if !mem_reserve_ppages(&img_ppages, MemOwner::VMRam(vm_id)) {
+        return Err(BaoError::AlreadyExists);
+    }
+
+    Ok(())
+}
+
+fn setup_separate_vm_images() {
+    let mut config = CONFIG.write();
+    for (i, vm_config) in config.vmlist.iter_mut().enumerate() {
+        if vm_config.separately_loaded {
+            if let Err(e) = setup_separate_vm_image(i, vm_config) {
+                panic!("vm {}: invalid separately loaded image ({:?})", i, e);
+            }
+        }
+    }
+}
+
 pub fn init(load_addr: Paddr) {
     adjust_vm_image_addr(load_addr);
+    setup_separate_vm_images();
 }
@@ -5,6 +5,7 @@ use crate::{
     arch::aarch64::armv8_a::vm::{ArchVMPlatform, VGicDscr},
     baocore::{
         ipc::{SharedMemConfig, IPC},
+        types::{MemAccess, MemScrub, MemType},
         vm::{VMDeviceRegion, VMMemRegion, VMPlatform},
     },
     config::{Config, VMConfig},
@@ -32,6 +33,7 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
         load_addr: _freertos_vm_beg as u64,
         size: (_freertos_vm_end as usize - _freertos_vm_beg as usize),
         separately_loaded: false,
+        image_header: false,
         inplace: false,
         entry: 0x0,
         vm_platform: VMPlatform {
@@ -41,6 +43,10 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
                 size: 0x8000000,
                 place_phys: false,
                 phys: 0,
+                access: MemAccess::ReadWrite,
+                mem_type: MemType::Normal,
+                xn: false,
+                scrub: MemScrub::Zero,
             }],
             devs: vec![
                 VMDeviceRegion {
@@ -48,28 +54,45 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
                     pa: 0x9000000,
                     va: Some(0xff000000),
                     size: 0x10000,
+                    mem_type: MemType::DeviceGRE,
+                    access: MemAccess::ReadWrite,
+                    partial_page: false,
                     interrupts: vec![33],
+                    virt_interrupts: None,
+                    stream_ids: vec![],
                 },
                 VMDeviceRegion {
                     /* Arch timer interrupt */
                     pa: 0,
                     va: None,
                     size: 0,
+                    mem_type: MemType::DeviceGRE,
+                    access: MemAccess::ReadWrite,
+                    partial_page: false,
                     interrupts: vec![27],
+                    virt_interrupts: None,
+                    stream_ids: vec![],
                 },
                 // VMDeviceRegion {
                 //     /* virtio devices */
                 //     pa: 0xa003000,
                 //     va: 0xa003000,
                 //     size: 0x1000,
+                //     mem_type: MemType::DeviceGRE,
+                //     access: MemAccess::ReadWrite,
+                //     partial_page: false,
                 //     interrupts: vec![72, 73, 74, 75, 76, 77, 78, 79],
+                //     virt_interrupts: None,
+                //     stream_ids: vec![],
                 // },
             ],
             arch: ArchVMPlatform {
                 gic: VGicDscr {
                     gicd_addr: 0xf9010000,
-                    gicc_addr: 0,
+                    // Only one of these is used, depending on the gic version
+                    gicc_addr: 0xf9020000,
                     gicr_addr: 0xf9020000,
+                    its_addr: 0,
                     interrupt_num: 0,
                 },
             },
@@ -87,6 +110,7 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
         load_addr: _linux_vm_beg as u64,
         size: (_linux_vm_end as usize - _linux_vm_beg as usize),
         separately_loaded: false,
+        image_header: false,
         inplace: false,
         entry: 0x60000000,
         vm_platform: VMPlatform {
@@ -96,6 +120,10 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
                 size: 0x40000000,
                 place_phys: true,
                 phys: 0x60000000,
+                access: MemAccess::ReadWrite,
+                mem_type: MemType::Normal,
+                xn: false,
+                scrub: MemScrub::Zero,
             }],
             devs: vec![
                 VMDeviceRegion {
@@ -103,28 +131,45 @@ pub static CONFIG: Lazy<RwLock<Config>> = Lazy::new(|| {
                     pa: 0,
                     va: None,
                     size: 0,
+                    mem_type: MemType::DeviceGRE,
+                    access: MemAccess::ReadWrite,
+                    partial_page: false,
                     interrupts: vec![27],
+                    virt_interrupts: None,
+                    stream_ids: vec![],
                 },
                 VMDeviceRegion {
                     /* virtio devices */
                     pa: 0xa003000,
                     va: Some(0xa003000),
                     size: 0x1000,
+                    mem_type: MemType::DeviceGRE,
+                    access: MemAccess::ReadWrite,
+                    partial_page: false,
                     interrupts: vec![72, 73, 74, 75, 76, 77, 78, 79],
+                    virt_interrupts: None,
+                    stream_ids: vec![],
                 },
                 VMDeviceRegion {
                     /* Pl011 */
                     pa: 0x9000000,
                     va: Some(0x9000000),
                     size: 0x10000,
+                    mem_type: MemType::DeviceGRE,
+                    access: MemAccess::ReadWrite,
+                    partial_page: false,
                     interrupts: vec![33],
+                    virt_interrupts: None,
+                    stream_ids: vec![],
                 },
             ],
             arch: ArchVMPlatform {
                 gic: VGicDscr {
                     gicd_addr: 0x8000000,
-                    gicc_addr: 0,
+                    gicc_addr: 0x8010000,
                     gicr_addr: 0x80a0000,
+                    // 0x8080000 to give passthrough pci devices their msis
+                    its_addr: 0,
                     interrupt_num: 0,
                 },
             },
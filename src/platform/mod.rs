@@ -3,10 +3,13 @@
 pub mod drivers;
 pub mod qemu_aarch64_virt;
 
-use crate::baocore::{
-    cache::Cache,
-    mem::MemRegion,
-    types::{CpuID, IrqID, Paddr},
+use crate::{
+    arch::aarch64::gic::GicVersion,
+    baocore::{
+        cache::Cache,
+        mem::MemRegion,
+        types::{CpuID, IrqID, Paddr},
+    },
 };
 use core::mem::size_of;
 
@@ -20,18 +23,27 @@ pub struct ArchPlatform {
 
 #[repr(C)]
 pub struct GICDescriptor {
+    pub version: GicVersion,
+    /// Cpu interface, its virtualization control and the virtual cpu
+    /// interface handed to guests, GICv2 only
     pub gicc_addr: Paddr,
     pub gich_addr: Paddr,
     pub gicv_addr: Paddr,
     pub gicd_addr: Paddr,
+    /// GICv3 only
     pub gicr_addr: Paddr,
+    /// Interrupt translation service, GICv3 only and 0 when absent
+    pub its_addr: Paddr,
     pub maintenance_id: IrqID,
 }
 
 #[repr(C)]
 pub struct SMMUDescriptor {
     pub base: u64,
+    /// Event queue interrupt
     pub interrupt_id: u32,
+    /// Global error interrupt
+    pub gerror_id: u32,
     pub global_mask: usize,
 }
 
@@ -39,6 +51,7 @@ const fn default_smmu_desc() -> SMMUDescriptor {
     SMMUDescriptor {
         base: 0,
         interrupt_id: 0,
+        gerror_id: 0,
         global_mask: 0,
     }
 }
@@ -68,8 +81,10 @@ const fn default_clusters_desc() -> ClustersDescriptor {
 #[repr(C)]
 pub struct Platform {
     pub cpu_num: usize,
-    pub region_num: usize,
-    pub regions: [MemRegion; 2],
+    pub regions: &'static [MemRegion],
+    /// Memory inside `regions` that must never be handed out, e.g. firmware
+    /// or secure world carve-outs
+    pub reserved: &'static [MemRegion],
     pub console_base: Paddr,
     pub cache: Cache,
     pub arch: ArchPlatform,
@@ -4,22 +4,33 @@ use super::*;
 
 pub static PLATFORM: Platform = Platform {
     cpu_num: 4,
-    region_num: 1,
-    regions: [
-        MemRegion::new(0x40000000, 0x100000000),
-        MemRegion::new(0, 0),
-    ],
+    regions: &[MemRegion::new(0x40000000, 0x100000000)],
+    reserved: &[],
     console_base: 0x9000000,
     cache: Cache {},
     arch: ArchPlatform {
+        // `-machine virt,gic-version=2` or `gic-version=3`
         gic: GICDescriptor {
+            version: if cfg!(feature = "gicv2") {
+                GicVersion::GicVersion2
+            } else {
+                GicVersion::GicVersion3
+            },
             gicd_addr: 0x08000000,
             gicc_addr: 0x08010000,
             gich_addr: 0x08030000,
             gicv_addr: 0x08040000,
             gicr_addr: 0x080A0000,
+            its_addr: 0x08080000,
             maintenance_id: 25,
         },
+        // With `-machine virt,iommu=smmuv3`:
+        // smmu: SMMUDescriptor {
+        //     base: 0x09050000,
+        //     interrupt_id: 106,
+        //     gerror_id: 109,
+        //     global_mask: 0,
+        // },
         smmu: default_smmu_desc(),
         generic_timer: default_generic_timer_desc(),
         clusters: ClustersDescriptor {
@@ -1,3 +1,5 @@
+use core::cell::UnsafeCell;
+
 use crate::{arch::aarch64::defs::PAGE_SIZE, baocore::types::Vaddr, util::is_aligned};
 
 #[repr(C)]
@@ -8,11 +10,19 @@ pub struct Bitmap {
 
 #[repr(C)]
 #[repr(align(0x1000))]
-pub struct BMSpace(pub [u8; PAGE_SIZE]);
+/// Backing store for a static bitmap. The cell keeps it out of the read-only
+/// image sections.
+pub struct BMSpace(UnsafeCell<[u8; PAGE_SIZE]>);
+
+unsafe impl Sync for BMSpace {}
 
 impl BMSpace {
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new([0; PAGE_SIZE]))
+    }
+
     pub fn base(&self) -> Vaddr {
-        &self.0[0] as *const _ as _
+        self.0.get() as _
     }
 }
 
@@ -22,6 +32,10 @@ impl Bitmap {
         Self { base, size_bytes }
     }
 
+    pub fn base(&self) -> Vaddr {
+        self.base
+    }
+
     pub fn clear_all(&mut self) {
         let ptr = self.base as *mut u8;
         unsafe {
@@ -1,6 +1,9 @@
 pub mod bitmap;
 
-use crate::{arch::aarch64::defs::PAGE_SIZE, baocore::types::Vaddr};
+use crate::{
+    arch::aarch64::{armv8_a::mem::mem_zero, defs::PAGE_SIZE},
+    baocore::types::Vaddr,
+};
 
 #[inline]
 pub fn range_in_range(base1: usize, size1: usize, base2: usize, size2: usize) -> bool {
@@ -67,10 +70,26 @@ pub fn num_pages(sz: usize) -> usize {
 }
 
 pub fn clear_memory(va: Vaddr, sz: usize) {
-    for addr in va..va + sz as u64 {
-        unsafe {
-            (addr as *mut u8).write_volatile(0);
-        }
+    mem_zero(va, sz);
+}
+
+/// Fills `[va, va + sz)` with `pattern`, laid out as if the pattern was
+/// stored at every 8 byte aligned address.
+pub fn fill_memory(va: Vaddr, sz: usize, pattern: u64) {
+    let bytes = pattern.to_le_bytes();
+    let end = va + sz as u64;
+    let mut addr = va;
+    while addr < end && addr % 8 != 0 {
+        unsafe { (addr as *mut u8).write_volatile(bytes[addr as usize % 8]) };
+        addr += 1;
+    }
+    while addr + 8 <= end {
+        unsafe { (addr as *mut u64).write_volatile(pattern) };
+        addr += 8;
+    }
+    while addr < end {
+        unsafe { (addr as *mut u8).write_volatile(bytes[addr as usize % 8]) };
+        addr += 1;
     }
 }
 
@@ -97,6 +116,7 @@ pub enum BaoError {
     InvalidParam,
     NotFound,
     OutOfMemory,
+    PermissionDenied,
     ResourceBusy,
     Unsupported,
 }